@@ -1,9 +1,11 @@
 __kernel void rgba_to_uint8_rgba_buffer(read_only image2d_t src_image,
+                                        __private uint const src_x,
+                                        __private uint const src_y,
                                         __global uchar* const buf) {
 	int2 coords = (int2)(get_global_id(0), get_global_id(1));
-	int w = get_image_width(src_image);
+	int w = get_global_size(0);
 
-	float4 pixel = read_imagef(src_image, coords) * 255.0f;
+	float4 pixel = read_imagef(src_image, coords + (int2)(src_x, src_y)) * 255.0f;
 
 	int base = (coords.y * w + coords.x) * 4;
 	buf[base] = round(pixel.x);
@@ -12,62 +14,86 @@ __kernel void rgba_to_uint8_rgba_buffer(read_only image2d_t src_image,
 	buf[base + 3] = round(pixel.w);
 }
 
-__kernel void rgb_to_yuv444_601_limited(read_only image2d_t src_image,
-                                        __private uint const Y_stride,
-                                        __private uint const U_stride,
-                                        __private uint const V_stride,
-                                        __global uchar* const Y_buf,
-                                        __global uchar* const U_buf,
-                                        __global uchar* const V_buf) {
+// Converts RGB into YUV with the BT.601 or the BT.709 matrix, in the limited or the full range.
+float3 rgb_to_yuv(float4 pixel, uint bt709, uint full_range) {
+	float kr = bt709 ? 0.2126f : 0.299f;
+	float kb = bt709 ? 0.0722f : 0.114f;
+
+	float y = kr * pixel.x + (1.0f - kr - kb) * pixel.y + kb * pixel.z;
+	float pb = (pixel.z - y) / (2.0f * (1.0f - kb));
+	float pr = (pixel.x - y) / (2.0f * (1.0f - kr));
+
+	float3 yuv;
+	if (full_range)
+		yuv = (float3)(255.0f * y, 128.0f + 255.0f * pb, 128.0f + 255.0f * pr);
+	else
+		yuv = (float3)(16.0f + 219.0f * y, 128.0f + 224.0f * pb, 128.0f + 224.0f * pr);
+
+	return clamp(round(yuv), 0.0f, 255.0f);
+}
+
+__kernel void rgb_to_yuv444(read_only image2d_t src_image,
+                            __private uint const src_x,
+                            __private uint const src_y,
+                            __private uint const Y_stride,
+                            __private uint const U_stride,
+                            __private uint const V_stride,
+                            __global uchar* const Y_buf,
+                            __global uchar* const U_buf,
+                            __global uchar* const V_buf,
+                            __private uint const bt709,
+                            __private uint const full_range) {
 	int2 coords = (int2)(get_global_id(0), get_global_id(1));
 
-	float4 pixel = read_imagef(src_image, coords);
-	float Y = 16 + pixel.x * 65.481 + pixel.y * 128.553 + pixel.z * 24.966;
-	float U = 128 - pixel.x * 37.797 - pixel.y * 74.203 + pixel.z * 112.0;
-	float V = 128 + pixel.x * 112.0 - pixel.y * 93.786 - pixel.z * 18.214;
+	float4 pixel = read_imagef(src_image, coords + (int2)(src_x, src_y));
+	float3 yuv = rgb_to_yuv(pixel, bt709, full_range);
 
 	// FFMpeg frames are flipped.
-	coords.y = get_image_height(src_image) - coords.y - 1;
+	coords.y = get_global_size(1) - coords.y - 1;
 
-	Y_buf[coords.y * Y_stride + coords.x] = round(Y);
-	U_buf[coords.y * U_stride + coords.x] = round(U);
-	V_buf[coords.y * V_stride + coords.x] = round(V);
+	Y_buf[coords.y * Y_stride + coords.x] = yuv.x;
+	U_buf[coords.y * U_stride + coords.x] = yuv.y;
+	V_buf[coords.y * V_stride + coords.x] = yuv.z;
 }
 
-__kernel void rgb_to_yuv420_601_limited(read_only image2d_t src_image,
-                                        __private uint const Y_stride,
-                                        __private uint const U_stride,
-                                        __private uint const V_stride,
-                                        __global uchar* const Y_buf,
-                                        __global uchar* const U_buf,
-                                        __global uchar* const V_buf) {
+__kernel void rgb_to_yuv420(read_only image2d_t src_image,
+                            __private uint const src_x,
+                            __private uint const src_y,
+                            __private uint const Y_stride,
+                            __private uint const U_stride,
+                            __private uint const V_stride,
+                            __global uchar* const Y_buf,
+                            __global uchar* const U_buf,
+                            __global uchar* const V_buf,
+                            __private uint const bt709,
+                            __private uint const full_range) {
 	int2 coords = (int2)(get_global_id(0), get_global_id(1));
-	int h = get_image_height(src_image);
+	int2 src_coords = coords + (int2)(src_x, src_y);
+	int h = get_global_size(1);
 
-	float4 pixel = read_imagef(src_image, coords);
+	float4 pixel = read_imagef(src_image, src_coords);
 
-	float Y = 16 + pixel.x * 65.481 + pixel.y * 128.553 + pixel.z * 24.966;
-	Y_buf[(h - coords.y - 1) * Y_stride + coords.x] = round(Y);
+	Y_buf[(h - coords.y - 1) * Y_stride + coords.x] = rgb_to_yuv(pixel, bt709, full_range).x;
 
 	if ((coords.x & 1) == 0 && (coords.y & 1) == 0) {
 		// Average the 4 pixel values for better results.
-		int w = get_image_width(src_image);
+		int w = get_global_size(0);
 		float4 top_right, bottom_left, bottom_right;
 
 		if (coords.x + 1 < w) {
-			top_right = read_imagef(src_image, coords + (int2)(1, 0));
+			top_right = read_imagef(src_image, src_coords + (int2)(1, 0));
 		} else {
 			top_right = pixel;
 		}
 
 		if (coords.y + 1 < h) {
-			bottom_left = read_imagef(src_image, coords + (int2)(0, 1));
+			bottom_left = read_imagef(src_image, src_coords + (int2)(0, 1));
 		} else {
 			bottom_left = pixel;
 		}
 
 		if (coords.x + 1 < w && coords.y + 1 < h) {
-			bottom_right = read_imagef(src_image, coords + (int2)(1, 1));
+			bottom_right = read_imagef(src_image, src_coords + (int2)(1, 1));
 		} else {
 			if (coords.x + 1 < w) {
 				bottom_right = top_right;
@@ -86,10 +112,9 @@ __kernel void rgb_to_yuv420_601_limited(read_only image2d_t src_image,
 
 		coords.y = (h >> 1) - coords.y - 1;
 
-		float U = 128 - pixel.x * 37.797 - pixel.y * 74.203 + pixel.z * 112.0;
-		float V = 128 + pixel.x * 112.0 - pixel.y * 93.786 - pixel.z * 18.214;
+		float3 yuv = rgb_to_yuv(pixel, bt709, full_range);
 
-		U_buf[coords.y * U_stride + coords.x] = round(U);
-		V_buf[coords.y * V_stride + coords.x] = round(V);
+		U_buf[coords.y * U_stride + coords.x] = yuv.y;
+		V_buf[coords.y * V_stride + coords.x] = yuv.z;
 	}
 }
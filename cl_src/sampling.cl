@@ -1,17 +1,40 @@
+float4 srgb_to_linear(float4 c) {
+	return select(pow((c + 0.055f) / 1.055f, 2.4f), c / 12.92f, c <= 0.04045f);
+}
+
+float4 linear_to_srgb(float4 c) {
+	return select(1.055f * pow(c, 1.0f / 2.4f) - 0.055f, c * 12.92f, c <= 0.0031308f);
+}
+
 __kernel void fill_with_black(write_only image2d_t dst_image) {
 	int2 coords = (int2)(get_global_id(0), get_global_id(1));
 	write_imagef(dst_image, coords, (float4)(0, 0, 0, 0));
 }
 
 __kernel void weighted_image_add(read_only image2d_t src_image,
+                                 __private uint const src_x,
+                                 __private uint const src_y,
                                  read_only image2d_t buf_image,
                                  write_only image2d_t dst_image,
-                                 __private float const weight) {
+                                 __private float const weight,
+                                 __private uint const linear) {
 	int2 coords = (int2)(get_global_id(0), get_global_id(1));
 
-	float4 src_pixel = read_imagef(src_image, coords);
+	float4 src_pixel = read_imagef(src_image, coords + (int2)(src_x, src_y));
 	float4 buf_pixel = read_imagef(buf_image, coords);
+
+	if (linear)
+		src_pixel = srgb_to_linear(src_pixel);
+
 	float4 pixel = buf_pixel + weight * src_pixel;
 
 	write_imagef(dst_image, coords, pixel);
 }
+
+__kernel void linear_to_srgb_image(read_only image2d_t src_image,
+                                   write_only image2d_t dst_image) {
+	int2 coords = (int2)(get_global_id(0), get_global_id(1));
+
+	float4 pixel = read_imagef(src_image, coords);
+	write_imagef(dst_image, coords, linear_to_srgb(clamp(pixel, 0.0f, 1.0f)));
+}
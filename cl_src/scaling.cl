@@ -0,0 +1,141 @@
+// Keep in sync with ocl_scaling_filter() in hw.rs.
+#define FILTER_BILINEAR 0
+#define FILTER_BICUBIC 1
+#define FILTER_LANCZOS 2
+#define FILTER_AREA 3
+
+const sampler_t scaling_sampler = CLK_NORMALIZED_COORDS_FALSE
+                                | CLK_ADDRESS_CLAMP_TO_EDGE
+                                | CLK_FILTER_NEAREST;
+
+float filter_radius(uint filter) {
+	switch (filter) {
+		case FILTER_BICUBIC: return 2.0f;
+		case FILTER_LANCZOS: return 3.0f;
+		default: return 1.0f;
+	}
+}
+
+float filter_weight(uint filter, float x) {
+	x = fabs(x);
+
+	switch (filter) {
+		case FILTER_BICUBIC: {
+			// Keys cubic with a = -0.5.
+			const float a = -0.5f;
+
+			if (x < 1.0f)
+				return ((a + 2.0f) * x - (a + 3.0f)) * x * x + 1.0f;
+			if (x < 2.0f)
+				return ((a * x - 5.0f * a) * x + 8.0f * a) * x - 4.0f * a;
+			return 0.0f;
+		}
+
+		case FILTER_LANCZOS: {
+			const float a = 3.0f;
+
+			if (x < 1e-5f)
+				return 1.0f;
+			if (x >= a)
+				return 0.0f;
+
+			float px = M_PI_F * x;
+			return a * sin(px) * sin(px / a) / (px * px);
+		}
+
+		default:
+			return x < 1.0f ? 1.0f - x : 0.0f;
+	}
+}
+
+float4 sample_filtered(read_only image2d_t src_image,
+                       int2 src_origin,
+                       int2 src_size,
+                       int2 coords,
+                       float2 ratio,
+                       uint filter) {
+	// When downscaling, the filter is stretched to cover all source pixels.
+	float2 scale = fmax(ratio, (float2)(1.0f, 1.0f));
+	float2 support = filter_radius(filter) * scale;
+	float2 center = ((float2)(coords.x, coords.y) + 0.5f) * ratio - 0.5f;
+
+	int x0 = max((int)ceil(center.x - support.x), 0);
+	int x1 = min((int)floor(center.x + support.x), src_size.x - 1);
+	int y0 = max((int)ceil(center.y - support.y), 0);
+	int y1 = min((int)floor(center.y + support.y), src_size.y - 1);
+
+	float4 sum = (float4)(0.0f, 0.0f, 0.0f, 0.0f);
+	float weight_sum = 0.0f;
+
+	for (int y = y0; y <= y1; y++) {
+		float weight_y = filter_weight(filter, (y - center.y) / scale.y);
+
+		for (int x = x0; x <= x1; x++) {
+			float weight = weight_y * filter_weight(filter, (x - center.x) / scale.x);
+
+			sum += weight * read_imagef(src_image, scaling_sampler, src_origin + (int2)(x, y));
+			weight_sum += weight;
+		}
+	}
+
+	if (weight_sum == 0.0f) {
+		int2 nearest = clamp(convert_int2(round(center)), (int2)(0, 0), src_size - 1);
+		return read_imagef(src_image, scaling_sampler, src_origin + nearest);
+	}
+
+	return sum / weight_sum;
+}
+
+float4 sample_area(read_only image2d_t src_image,
+                   int2 src_origin,
+                   int2 src_size,
+                   int2 coords,
+                   float2 ratio) {
+	// Average the source pixels weighted by how much of them the output pixel covers.
+	float2 start = (float2)(coords.x, coords.y) * ratio;
+	float2 end = start + ratio;
+
+	int x0 = (int)floor(start.x);
+	int x1 = min((int)ceil(end.x), src_size.x) - 1;
+	int y0 = (int)floor(start.y);
+	int y1 = min((int)ceil(end.y), src_size.y) - 1;
+
+	float4 sum = (float4)(0.0f, 0.0f, 0.0f, 0.0f);
+	float weight_sum = 0.0f;
+
+	for (int y = y0; y <= y1; y++) {
+		float weight_y = fmin(end.y, y + 1.0f) - fmax(start.y, (float)y);
+
+		for (int x = x0; x <= x1; x++) {
+			float weight = weight_y * (fmin(end.x, x + 1.0f) - fmax(start.x, (float)x));
+
+			sum += weight * read_imagef(src_image, scaling_sampler, src_origin + (int2)(x, y));
+			weight_sum += weight;
+		}
+	}
+
+	return sum / weight_sum;
+}
+
+__kernel void scale_image(read_only image2d_t src_image,
+                          __private uint const src_x,
+                          __private uint const src_y,
+                          __private uint const src_w,
+                          __private uint const src_h,
+                          __private uint const filter,
+                          write_only image2d_t dst_image) {
+	int2 coords = (int2)(get_global_id(0), get_global_id(1));
+	int2 src_origin = (int2)(src_x, src_y);
+	int2 src_size = (int2)(src_w, src_h);
+	float2 ratio = (float2)((float)src_w / get_global_size(0),
+	                        (float)src_h / get_global_size(1));
+
+	float4 pixel;
+	if (filter == FILTER_AREA)
+		pixel = sample_area(src_image, src_origin, src_size, coords, ratio);
+	else
+		pixel = sample_filtered(src_image, src_origin, src_size, coords, ratio, filter);
+
+	// Bicubic and Lanczos can overshoot.
+	write_imagef(dst_image, coords, clamp(pixel, 0.0f, 1.0f));
+}
@@ -0,0 +1,23 @@
+__kernel void blend_watermark(read_only image2d_t src_image,
+                              __private uint const src_x,
+                              __private uint const src_y,
+                              read_only image2d_t watermark,
+                              __private int const watermark_x,
+                              __private int const watermark_y,
+                              write_only image2d_t dst_image) {
+	int2 coords = (int2)(get_global_id(0), get_global_id(1));
+
+	float4 pixel = read_imagef(src_image, coords + (int2)(src_x, src_y));
+
+	// The source image has the origin in the bottom left corner, the watermark in the top left.
+	int2 watermark_coords = (int2)(coords.x - watermark_x,
+	                               get_global_size(1) - coords.y - 1 - watermark_y);
+
+	if (all(watermark_coords >= (int2)(0, 0))
+	    && all(watermark_coords < get_image_dim(watermark))) {
+		float4 watermark_pixel = read_imagef(watermark, watermark_coords);
+		pixel.xyz = mix(pixel.xyz, watermark_pixel.xyz, watermark_pixel.w);
+	}
+
+	write_imagef(dst_image, coords, pixel);
+}
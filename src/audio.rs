@@ -0,0 +1,313 @@
+use failure::{bail, ensure, format_err, Error};
+use std::result;
+
+use crate::loudness::LoudnessMeter;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Level above which the soft limiter starts compressing, in linear units.
+const SOFT_LIMIT_THRESHOLD: f32 = 0.8;
+
+/// What to do with the audio peaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limiter {
+    /// Leave the audio as is; peaks above full scale clip in integer output formats.
+    None,
+
+    /// Smoothly compress the peaks above the threshold so they never reach full scale.
+    Soft,
+}
+
+/// Highest gain the loudness normalization applies, in dB.
+const MAX_LOUDNESS_GAIN: f64 = 20f64;
+
+/// Time constant of the realtime loudness normalization gain changes, in seconds.
+const REALTIME_GAIN_TIME_CONSTANT: f64 = 3f64;
+
+/// Loudness normalization toward a target integrated loudness.
+///
+/// The gain is continuously adjusted toward the target based on the loudness so far, with the
+/// soft limiter catching the peaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessNormalization {
+    /// Target integrated loudness, in LUFS.
+    pub target: f64,
+}
+
+/// Applies the loudness normalization and the limiter to the float audio and keeps the
+/// statistics.
+pub struct AudioProcessor {
+    limiter: Limiter,
+    loudness_normalization: Option<LoudnessNormalization>,
+
+    /// Measures the input loudness for the loudness normalization.
+    input_meter: LoudnessMeter,
+
+    /// Measures the output loudness for the statistics.
+    output_meter: LoudnessMeter,
+
+    /// Current loudness normalization gain, in dB.
+    gain: f64,
+
+    /// Loudness normalization gain the current gain is moving toward, in dB.
+    target_gain: f64,
+
+    /// Per-sample smoothing factor for the gain changes.
+    gain_smoothing: f64,
+
+    /// Number of clipped sample values, counting each channel separately.
+    clipped: u64,
+
+    /// Highest absolute sample value in the output.
+    peak: f32,
+
+    /// Whether any samples were processed.
+    got_samples: bool,
+}
+
+impl AudioProcessor {
+    pub fn new(limiter: Limiter,
+               loudness_normalization: Option<LoudnessNormalization>,
+               rate: u32)
+               -> Self {
+        Self { limiter,
+               loudness_normalization,
+               input_meter: LoudnessMeter::new(rate),
+               output_meter: LoudnessMeter::new(rate),
+               gain: 0f64,
+               target_gain: 0f64,
+               gain_smoothing: 1f64
+                               - (-1f64 / (REALTIME_GAIN_TIME_CONSTANT * f64::from(rate))).exp(),
+               clipped: 0,
+               peak: 0f32,
+               got_samples: false }
+    }
+
+    /// Adds clipped sample values counted elsewhere, such as in the 16-bit capture path.
+    #[inline]
+    pub fn add_clipped(&mut self, count: u64) {
+        self.clipped += count;
+    }
+
+    /// Processes the samples in place.
+    pub fn process(&mut self, samples: &mut [(f32, f32)]) {
+        if !samples.is_empty() {
+            self.got_samples = true;
+        }
+
+        if let Some(LoudnessNormalization { target }) = self.loudness_normalization {
+            if self.input_meter.add(samples) {
+                if let Some(loudness) = self.input_meter.integrated() {
+                    self.target_gain = loudness_gain(loudness, target);
+                }
+            }
+
+            for sample in samples.iter_mut() {
+                self.gain += (self.target_gain - self.gain) * self.gain_smoothing;
+
+                let gain = db_to_linear(self.gain);
+                *sample = (sample.0 * gain, sample.1 * gain);
+            }
+        }
+
+        self.limit(samples);
+        self.update_stats(samples);
+    }
+
+    /// Applies the soft limiter if needed.
+    ///
+    /// The loudness normalization always needs the limiter to catch the peaks.
+    fn limit(&self, samples: &mut [(f32, f32)]) {
+        if self.limiter == Limiter::Soft || self.loudness_normalization.is_some() {
+            for sample in samples.iter_mut() {
+                *sample = (soft_limit(sample.0), soft_limit(sample.1));
+            }
+        }
+    }
+
+    /// Returns a message with the audio statistics, or `None` if there was no audio.
+    pub fn stats(&self) -> Option<String> {
+        if !self.got_samples {
+            return None;
+        }
+
+        let loudness = self.output_meter
+                           .integrated()
+                           .map(|loudness| format!("{:.1}", loudness))
+                           .unwrap_or_else(|| "-inf".to_string());
+
+        Some(format!("Audio: loudness {} LUFS, peak {} dBFS, {} clipped samples.\n",
+                     loudness,
+                     format_db(self.peak),
+                     self.clipped))
+    }
+
+    fn update_stats(&mut self, samples: &[(f32, f32)]) {
+        for &(left, right) in samples {
+            for &value in &[left, right] {
+                if value.abs() > 1f32 {
+                    self.clipped += 1;
+                }
+            }
+        }
+
+        self.peak = self.peak.max(peak(samples));
+        self.output_meter.add(samples);
+    }
+}
+
+/// Parses the given string into a `Limiter`.
+pub fn parse_limiter(string: &str) -> Result<Limiter> {
+    match string {
+        "none" => Ok(Limiter::None),
+        "soft" => Ok(Limiter::Soft),
+        _ => bail!("expected none or soft"),
+    }
+}
+
+/// Parses the given strings into a `LoudnessNormalization`.
+pub fn parse_loudness_normalization(mode: &str,
+                                    target: &str)
+                                    -> Result<Option<LoudnessNormalization>> {
+    let target = || -> Result<f64> {
+        let target = target.parse::<f64>()
+                           .map_err(|_| format_err!("invalid target loudness"))?;
+        ensure!(target < 0f64, "the target loudness must be negative");
+        Ok(target)
+    };
+
+    match mode {
+        "none" => Ok(None),
+        "realtime" => Ok(Some(LoudnessNormalization { target: target()? })),
+        _ => bail!("expected none or realtime"),
+    }
+}
+
+/// Returns the gain which brings the loudness to the target, in dB.
+#[inline]
+fn loudness_gain(loudness: f64, target: f64) -> f64 {
+    (target - loudness).max(-MAX_LOUDNESS_GAIN)
+                       .min(MAX_LOUDNESS_GAIN)
+}
+
+#[inline]
+fn db_to_linear(db: f64) -> f32 {
+    10f64.powf(db / 20f64) as f32
+}
+
+/// Returns the highest absolute sample value.
+fn peak(samples: &[(f32, f32)]) -> f32 {
+    samples.iter()
+           .fold(0f32, |peak, &(l, r)| peak.max(l.abs()).max(r.abs()))
+}
+
+/// Compresses the value above the threshold so that it approaches but never reaches 1.
+#[inline]
+fn soft_limit(value: f32) -> f32 {
+    let magnitude = value.abs();
+    if magnitude <= SOFT_LIMIT_THRESHOLD {
+        return value;
+    }
+
+    let range = 1f32 - SOFT_LIMIT_THRESHOLD;
+    let limited = SOFT_LIMIT_THRESHOLD
+                  + range * ((magnitude - SOFT_LIMIT_THRESHOLD) / range).tanh();
+
+    limited.copysign(value)
+}
+
+/// Formats the linear level in decibels relative to full scale.
+fn format_db(level: f32) -> String {
+    if level > 0f32 {
+        format!("{:.1}", 20f32 * level.log10())
+    } else {
+        "-inf".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn soft_limit_test() {
+        assert_eq!(soft_limit(0.5), 0.5);
+        assert_eq!(soft_limit(-0.5), -0.5);
+
+        assert!(soft_limit(1f32) < 1f32);
+        assert!(soft_limit(10f32) <= 1f32);
+        assert!(soft_limit(-10f32) >= -1f32);
+        assert!(soft_limit(0.9) > soft_limit(0.85));
+    }
+
+    #[test]
+    fn soft_limiter_test() {
+        let mut processor = AudioProcessor::new(Limiter::Soft, None, 48000);
+
+        let mut samples = vec![(0.5, -2f32), (1f32, 0f32)];
+        processor.process(&mut samples);
+        assert_eq!(samples[0].0, 0.5);
+        assert!(samples[0].1 > -1f32);
+        assert!(samples[1].0 < 1f32);
+        assert_eq!(processor.clipped, 0);
+    }
+
+    #[test]
+    fn clip_counter_test() {
+        let mut processor = AudioProcessor::new(Limiter::None, None, 48000);
+
+        let mut samples = vec![(1.5, -1.5), (0.5, 1f32)];
+        processor.process(&mut samples);
+        processor.add_clipped(3);
+
+        assert_eq!(processor.clipped, 5);
+        assert_eq!(processor.peak, 1.5);
+    }
+
+    #[test]
+    fn loudness_normalization_test() {
+        let normalization = LoudnessNormalization { target: -14f64 };
+        let mut processor = AudioProcessor::new(Limiter::None, Some(normalization), 48000);
+
+        // A stereo 1 kHz sine at -20 dBFS measures -20 LUFS.
+        let mut samples = Vec::new();
+        for i in 0..48000 * 30 {
+            let phase = 2f64 * ::std::f64::consts::PI * 1000f64 * f64::from(i) / 48000f64;
+            let value = (0.1 * phase.sin()) as f32;
+            samples.push((value, value));
+        }
+
+        for chunk in samples.chunks_mut(4800) {
+            processor.process(chunk);
+        }
+
+        // After ten time constants the gain has settled.
+        assert!((processor.gain - 6f64).abs() < 0.1, "{}", processor.gain);
+        let end_peak = peak(&samples[samples.len() - 4800..]);
+        assert!((end_peak - 0.2).abs() < 0.01, "{}", end_peak);
+        assert_eq!(processor.clipped, 0);
+    }
+
+    #[test]
+    fn parse_loudness_normalization_test() {
+        assert_eq!(parse_loudness_normalization("none", "").unwrap(), None);
+        assert_eq!(parse_loudness_normalization("realtime", "-16").unwrap(),
+                   Some(LoudnessNormalization { target: -16f64 }));
+        assert!(parse_loudness_normalization("gain", "16").is_err());
+        assert!(parse_loudness_normalization("peak", "-16").is_err());
+    }
+
+    #[test]
+    fn parse_limiter_test() {
+        assert_eq!(parse_limiter("soft").unwrap(), Limiter::Soft);
+        assert!(parse_limiter("hard").is_err());
+        assert!(parse_limiter("normalize").is_err());
+    }
+
+    #[test]
+    fn format_db_test() {
+        assert_eq!(format_db(1f32), "0.0");
+        assert_eq!(format_db(0.5), "-6.0");
+        assert_eq!(format_db(0f32), "-inf");
+    }
+}
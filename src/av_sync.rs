@@ -0,0 +1,164 @@
+/// Shifts the audio relative to the video by inserting silence or dropping samples at the start.
+pub struct AudioOffset {
+    /// Number of silent samples still to be inserted.
+    silence: usize,
+
+    /// Number of samples still to be dropped.
+    drop: usize,
+}
+
+/// Tracks the video and the audio durations received by the capture thread and warns when they
+/// drift apart.
+pub struct DriftMonitor {
+    /// Duration of one video frame, in seconds.
+    frame_duration: f64,
+
+    /// Sample rate of the audio.
+    rate: f64,
+
+    /// Drift above which a warning is given, in seconds.
+    threshold: f64,
+
+    /// Number of video frames received so far.
+    video_frames: u64,
+
+    /// Number of audio samples received so far.
+    audio_samples: u64,
+
+    /// Whether the drift is currently above the threshold and the warning was already given.
+    warned: bool,
+}
+
+impl AudioOffset {
+    /// Creates the offset of `offset_ms` milliseconds at the given sample rate. Positive offsets
+    /// delay the audio, negative ones make it play earlier.
+    pub fn new(offset_ms: i64, rate: u32) -> Self {
+        let samples = (offset_ms.abs() as f64 * f64::from(rate) / 1000f64).round() as usize;
+
+        if offset_ms >= 0 {
+            Self { silence: samples,
+                   drop: 0 }
+        } else {
+            Self { silence: 0,
+                   drop: samples }
+        }
+    }
+
+    /// Applies the offset to the next samples.
+    pub fn apply(&mut self, samples: &mut Vec<(f32, f32)>) {
+        if self.drop > 0 {
+            let count = self.drop.min(samples.len());
+            samples.drain(..count);
+            self.drop -= count;
+        }
+
+        if self.silence > 0 && !samples.is_empty() {
+            samples.splice(..0, (0..self.silence).map(|_| (0f32, 0f32)));
+            self.silence = 0;
+        }
+    }
+}
+
+impl DriftMonitor {
+    pub fn new(frame_duration: f64, rate: u32, threshold: f64) -> Self {
+        Self { frame_duration,
+               rate: f64::from(rate),
+               threshold,
+               video_frames: 0,
+               audio_samples: 0,
+               warned: false }
+    }
+
+    #[inline]
+    pub fn add_video(&mut self, frames: usize) {
+        self.video_frames += frames as u64;
+    }
+
+    #[inline]
+    pub fn add_audio(&mut self, samples: usize) {
+        self.audio_samples += samples as u64;
+    }
+
+    /// Returns the audio duration minus the video duration, in seconds.
+    #[inline]
+    pub fn drift(&self) -> f64 {
+        self.audio_samples as f64 / self.rate - self.video_frames as f64 * self.frame_duration
+    }
+
+    /// Returns a warning message if the drift went above the threshold.
+    ///
+    /// The warning is given again only after the drift goes back under the threshold.
+    pub fn check(&mut self) -> Option<String> {
+        let drift = self.drift();
+
+        if drift.abs() <= self.threshold {
+            self.warned = false;
+            return None;
+        }
+
+        if self.warned {
+            return None;
+        }
+
+        self.warned = true;
+
+        Some(format!("Warning: the audio is {:.0} ms {} the video (see \
+                      cap_av_drift_threshold_ms).\n",
+                     drift.abs() * 1000f64,
+                     if drift > 0f64 { "ahead of" } else { "behind" }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn audio_offset_silence_test() {
+        let mut offset = AudioOffset::new(2, 1000);
+
+        let mut samples = Vec::new();
+        offset.apply(&mut samples);
+        assert!(samples.is_empty());
+
+        let mut samples = vec![(1f32, 1f32)];
+        offset.apply(&mut samples);
+        assert_eq!(samples, vec![(0f32, 0f32), (0f32, 0f32), (1f32, 1f32)]);
+
+        let mut samples = vec![(1f32, 1f32)];
+        offset.apply(&mut samples);
+        assert_eq!(samples, vec![(1f32, 1f32)]);
+    }
+
+    #[test]
+    fn audio_offset_drop_test() {
+        let mut offset = AudioOffset::new(-3, 1000);
+
+        let mut samples = vec![(1f32, 1f32), (2f32, 2f32)];
+        offset.apply(&mut samples);
+        assert!(samples.is_empty());
+
+        let mut samples = vec![(3f32, 3f32), (4f32, 4f32)];
+        offset.apply(&mut samples);
+        assert_eq!(samples, vec![(4f32, 4f32)]);
+    }
+
+    #[test]
+    fn drift_monitor_test() {
+        let mut monitor = DriftMonitor::new(0.01, 1000, 0.05);
+
+        monitor.add_video(10);
+        monitor.add_audio(100);
+        assert_eq!(monitor.check(), None);
+
+        monitor.add_audio(60);
+        assert!(monitor.check().unwrap().contains("60 ms ahead of"));
+        assert_eq!(monitor.check(), None);
+
+        monitor.add_video(10);
+        assert_eq!(monitor.check(), None);
+
+        monitor.add_video(10);
+        assert!(monitor.check().unwrap().contains("140 ms behind"));
+    }
+}
@@ -2,21 +2,48 @@ use failure::{bail, ensure, err_msg, Error, ResultExt};
 use ffmpeg::frame::Video as VideoFrame;
 use ffmpeg::{format, Rational};
 use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::BufWriter;
 use std::ops::Deref;
 use std::result;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::sync::{Mutex, Once, RwLock, ONCE_INIT};
+use std::sync::{Arc, Mutex, Once, RwLock, ONCE_INIT};
 use std::thread;
 
-use crate::encode::{Encoder, EncoderParameters};
+use crate::audio::{self, AudioProcessor, Limiter, LoudnessNormalization};
+use crate::av_sync::{AudioOffset, DriftMonitor};
+use crate::color::{self, ColorConversion};
+use crate::disk_space::{self, DiskSpace, DiskSpaceMonitor};
+use crate::encode::{Encoder, EncoderParameters, FrameDuplication, ScalingAlgorithm,
+                    VFR_TIME_BASE};
 use crate::engine::{Engine, MainThreadMarker};
+use crate::fade::{AudioFader, Fade, VideoFader, MAX_FADE_OUT_MEMORY};
+use crate::fps_converter::sampling::{cap_sampling_curve, cap_sampling_exposure, cap_sampling_linear,
+                                     cap_sampling_phase, cap_sampling_sps};
+use crate::fps_converter::timelapse::{cap_timelapse, cap_timelapse_audio, cap_timelapse_blend};
+use crate::fps_converter::timescale::{cap_timescale, cap_timescale_audio, cap_timescale_ramp};
+use crate::fps_converter::vfr::{cap_vfr, cap_vfr_timecodes};
 use crate::fps_converter::*;
+use crate::hash_log::HashLog;
 use crate::hooks::hw;
+use crate::input::InputLog;
+use crate::overlay::{self, FrameInfo, InputDisplay, InputDisplaySettings, Overlay,
+                     OverlaySettings};
 // use profiler::*;
+use crate::timecodes::TimecodeWriter;
+use crate::timescale::{self, AudioStretchMode, AudioStretcher, TimelapseAudio, Timescale};
 use crate::utils::format_error;
+use crate::watermark::{self, Watermark};
 
 type Result<T> = result::Result<T, Error>;
 
+/// Sound output rate used when the audio is disabled and the sound is not initialized.
+const DEFAULT_SOUND_RATE: u32 = 22050;
+
+/// Highest sampling exposure, in video frames. Every video frame of the exposure needs its own
+/// accumulation buffer.
+const MAX_SAMPLING_EXPOSURE: f64 = 8f64;
+
 lazy_static! {
     static ref CAPTURING: RwLock<bool> = RwLock::new(false);
 
@@ -40,15 +67,101 @@ thread_local! {
 }
 
 pub struct CaptureParameters {
-    pub sampling_exposure: f64,
+    pub video: bool,
+    pub audio: bool,
+    pub capture_region: Region,
+    pub output_resolution: (u32, u32),
+    pub scaling_algorithm: ScalingAlgorithm,
+    pub sampling_shutter: Shutter,
+
+    /// Whether the sampling converter blends the frames in linear light.
+    pub sampling_linear: bool,
     pub sampling_time_base: Option<Rational>,
     pub sound_extra: f64,
     pub time_base: Rational,
     pub volume: f32,
+
+    /// Whether the audio is captured as unclamped float.
+    pub audio_float: bool,
+
+    /// Whether every game frame is captured with its own timestamp.
+    pub vfr: bool,
+
+    /// The FPS converter selected with `cap_fps_converter`.
+    pub fps_converter: &'static FPSConverterInfo,
+
+    /// How the RGB frames are converted into YUV on the GPU.
+    pub color_conversion: ColorConversion,
+
+    /// Speed of the game time relative to the output time, `None` if the time isn't scaled.
+    pub timescale: Option<Timescale>,
+
+    /// Seconds of game time per second of output, `None` if the timelapse mode is disabled.
+    pub timelapse: Option<f64>,
+
+    /// Whether the timelapse mode blends the skipped frames, with equal weights regardless of
+    /// the sampling shutter settings.
+    pub timelapse_blend: bool,
+
+    /// Whether the video buffers need the frame info for the overlay or the input display.
+    pub frame_info: bool,
+}
+
+/// Parameters used by the capture thread itself rather than by the encoder.
+pub struct CaptureThreadParameters {
+    /// Minimum free space on the output filesystem, in bytes. Zero disables the check.
+    pub min_free_space: u64,
+
+    /// File to write the output hashes to, empty if disabled. See `HashLog` for what the hashes
+    /// cover.
+    pub hash_log: String,
+
+    /// Reference hash log to compare the output against, empty if disabled.
+    pub hash_compare: String,
+
+    /// Text overlay settings, `None` if disabled.
+    pub overlay: Option<OverlaySettings>,
+
+    /// Input display settings, `None` if disabled.
+    pub input_display: Option<InputDisplaySettings>,
+
+    /// Watermark image, `None` if disabled.
+    pub watermark: Option<Arc<Watermark>>,
+
+    /// Fade-in and fade-out durations.
+    pub fade: Fade,
+
+    /// What to do with the audio peaks.
+    pub audio_limiter: Limiter,
+
+    /// Loudness normalization target, `None` if disabled.
+    pub loudness_normalization: Option<LoudnessNormalization>,
+
+    /// Audio offset relative to the video, in milliseconds.
+    pub audio_offset_ms: i64,
+
+    /// Audio and video drift above which a warning is given, in seconds. Zero disables the
+    /// check.
+    pub av_drift_threshold: f64,
+
+    /// File to write the video frame timestamps to, empty if disabled.
+    pub vfr_timecodes: String,
+
+    /// How the audio is stretched to the output time, `None` if the time isn't scaled.
+    pub audio_stretch: Option<AudioStretchMode>,
+}
+
+/// A rectangular part of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
 }
 
 enum CaptureThreadEvent {
-    CaptureStart(EncoderParameters),
+    CaptureStart((EncoderParameters, CaptureThreadParameters)),
     CaptureStop,
     VideoFrame((VideoBuffer, usize)),
     AudioFrame(AudioBuffer),
@@ -67,10 +180,88 @@ pub struct VideoBuffer {
     components: u8,
     frame: VideoFrame,
     data_is_in_frame: bool,
+
+    /// The game state at the moment of capturing, used by the overlay and the input display.
+    frame_info: FrameInfo,
+
+    /// Whether the watermark was already blended in on the GPU.
+    watermarked: bool,
+
+    /// Timestamp of the frame in `VFR_TIME_BASE` units, set in the variable frame rate mode.
+    pts: Option<i64>,
 }
 
 pub struct AudioBuffer {
     data: Vec<(i16, i16)>,
+
+    /// Samples captured by the float path, with full scale at 1.
+    float_data: Vec<(f32, f32)>,
+
+    /// Number of sample values clamped by the 16-bit path.
+    clipped: u64,
+
+    /// Speed of the game time while the samples were captured.
+    timescale: f64,
+}
+
+/// Capture thread state which lives from `cap_start` to `cap_stop`.
+struct Session {
+    /// The encoder itself.
+    encoder: Encoder,
+
+    /// Watches the free space on the output filesystem, `None` if the check stopped working.
+    disk_space_monitor: Option<DiskSpaceMonitor>,
+
+    /// Writes and compares the hashes of the captured frames and audio.
+    hash_log: Option<HashLog>,
+
+    /// Draws the text overlay.
+    overlay: Option<Overlay>,
+
+    /// Frame for drawing the overlay when every output frame needs a different overlay.
+    overlay_frame: VideoFrame,
+
+    /// Draws the pressed keys.
+    input_display: Option<InputDisplay>,
+
+    /// Number of video frames output so far.
+    frame_number: u64,
+
+    /// Watermark to blend onto the frames which weren't watermarked on the GPU.
+    watermark: Option<Arc<Watermark>>,
+
+    /// Output video resolution, used for positioning the watermark.
+    video_resolution: (u32, u32),
+
+    /// Whether the unsupported pixel format warning for the watermark was printed.
+    watermark_warned: bool,
+
+    /// Fades the video in and out.
+    video_fader: VideoFader,
+
+    /// Fades the audio in and out.
+    audio_fader: AudioFader,
+
+    /// Applies the limiter and counts the clipped samples.
+    audio_processor: AudioProcessor,
+
+    /// Buffer for the float samples on their way to the encoder.
+    audio_samples: Vec<(f32, f32)>,
+
+    /// Shifts the audio relative to the video.
+    audio_offset: AudioOffset,
+
+    /// Warns when the audio and the video drift apart, `None` if disabled.
+    drift_monitor: Option<DriftMonitor>,
+
+    /// Timestamp of the last video frame in the variable frame rate mode.
+    last_pts: Option<i64>,
+
+    /// Writes the video frame timestamps, `None` if disabled.
+    timecodes: Option<TimecodeWriter<BufWriter<File>>>,
+
+    /// Stretches the audio captured with the scaled game time, `None` if the time isn't scaled.
+    audio_stretcher: Option<AudioStretcher>,
 }
 
 struct SendOnDrop<'a, T> {
@@ -87,7 +278,10 @@ impl VideoBuffer {
                format: format::Pixel::RGB24,
                components: format::Pixel::RGB24.descriptor().unwrap().nb_components(),
                frame: VideoFrame::empty(),
-               data_is_in_frame: false }
+               data_is_in_frame: false,
+               frame_info: FrameInfo::default(),
+               watermarked: false,
+               pts: None }
     }
 
     #[inline]
@@ -135,6 +329,23 @@ impl VideoBuffer {
         &mut self.frame
     }
 
+    /// Sets the timestamp of the frame for the variable frame rate mode.
+    #[inline]
+    pub fn set_pts(&mut self, pts: i64) {
+        self.pts = Some(pts);
+    }
+
+    /// Marks the buffer as already containing the watermark.
+    #[inline]
+    pub fn set_watermarked(&mut self) {
+        self.watermarked = true;
+    }
+
+    #[inline]
+    pub fn frame_info(&self) -> &FrameInfo {
+        &self.frame_info
+    }
+
     pub fn copy_to_frame(&self, frame: &mut VideoFrame) {
         // Make sure the frame is of correct size.
         if self.width != frame.width()
@@ -144,6 +355,8 @@ impl VideoBuffer {
             *frame = VideoFrame::new(self.format, self.width, self.height);
         }
 
+        frame.set_pts(self.pts);
+
         if self.data_is_in_frame {
             for i in 0..frame.planes() {
                 frame.data_mut(i).copy_from_slice(self.frame.data(i));
@@ -176,10 +389,56 @@ impl VideoBuffer {
     }
 }
 
+impl Region {
+    /// Returns a region covering the whole area of the given size.
+    #[inline]
+    pub fn whole((width, height): (u32, u32)) -> Self {
+        Self { x: 0,
+               y: 0,
+               width,
+               height }
+    }
+
+    #[inline]
+    pub fn origin(&self) -> (u32, u32) {
+        (self.x, self.y)
+    }
+
+    #[inline]
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Converts between top-left and bottom-left origin coordinates within an area of the given
+    /// height.
+    #[inline]
+    pub fn flip_vertically(&self, height: u32) -> Self {
+        // Saturate in case the game resolution was changed during capturing.
+        Self { y: height.saturating_sub(self.y + self.height),
+               ..*self }
+    }
+}
+
 impl AudioBuffer {
     #[inline]
     fn new() -> Self {
-        Self { data: Vec::new() }
+        Self { data: Vec::new(),
+               float_data: Vec::new(),
+               clipped: 0,
+               timescale: 1f64 }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.float_data.clear();
+        self.clipped = 0;
+        self.timescale = 1f64;
+    }
+
+    #[inline]
+    pub fn set_timescale(&mut self, timescale: f64) {
+        self.timescale = timescale;
     }
 
     #[inline]
@@ -191,6 +450,21 @@ impl AudioBuffer {
     pub fn data_mut(&mut self) -> &mut Vec<(i16, i16)> {
         &mut self.data
     }
+
+    #[inline]
+    pub fn float_data(&self) -> &Vec<(f32, f32)> {
+        &self.float_data
+    }
+
+    #[inline]
+    pub fn float_data_mut(&mut self) -> &mut Vec<(f32, f32)> {
+        &mut self.float_data
+    }
+
+    #[inline]
+    pub fn add_clipped(&mut self, count: u64) {
+        self.clipped += count;
+    }
 }
 
 impl<'a, T> SendOnDrop<'a, T> {
@@ -228,117 +502,445 @@ fn capture_thread(video_buf_sender: &Sender<VideoBuffer>,
     // This is our frame which will only be reallocated on resolution changes.
     let mut frame = VideoFrame::empty();
 
-    // This is set to true on encoding error or cap_stop and reset on cap_start.
-    // When this is true, ignore any received frames.
-    let mut drop_frames = true;
-
-    // The encoder itself.
-    let mut encoder: Option<Encoder> = None;
+    // This is set on cap_start and reset on encoding error or cap_stop.
+    // When this is None, ignore any received frames.
+    let mut session: Option<Session> = None;
 
     // Event loop for the capture thread.
     loop {
         match event_receiver.recv().unwrap() {
-            CaptureThreadEvent::CaptureStart(params) => {
-                drop_frames = false;
-
-                encoder = Encoder::start(&params).context({
-                              "could not start the encoder; check your terminal (Half-Life's \
-                               standard output) for ffmpeg messages"
-                          })
-                          .map_err(|e| {
-                              *CAPTURING.write().unwrap() = false;
-                              drop_frames = true;
-
-                              event_sender.send(GameThreadEvent::Message(format_error(&e.into())))
-                                          .unwrap();
-                          })
-                          .ok();
-
-                if let Some(ref encoder) = encoder {
-                    event_sender.send(GameThreadEvent::EncoderPixelFormat(encoder.format()))
+            CaptureThreadEvent::CaptureStart((params, thread_params)) => {
+                session = Session::start(&params, &thread_params, event_sender)
+                    .map_err(|e| {
+                        *CAPTURING.write().unwrap() = false;
+
+                        event_sender.send(GameThreadEvent::Message(format_error(&e)))
+                                    .unwrap();
+                    })
+                    .ok();
+
+                if let Some(format) = session.as_ref().and_then(|s| s.encoder.format()) {
+                    event_sender.send(GameThreadEvent::EncoderPixelFormat(format))
                                 .unwrap();
                 }
             }
 
             CaptureThreadEvent::CaptureStop => {
-                stop_encoder(encoder.take(), event_sender);
-                drop_frames = true;
+                stop_session(session.take(), event_sender);
             }
 
             CaptureThreadEvent::VideoFrame((buffer, times)) => {
                 let buffer = SendOnDrop::new(buffer, video_buf_sender);
 
-                if drop_frames {
-                    continue;
-                }
+                let result = match session {
+                    Some(ref mut session) => {
+                        session.video_frame(buffer, times, &mut frame, event_sender)
+                    }
+                    None => continue,
+                };
 
-                if let Err(e) = encode(&mut encoder, buffer, times, &mut frame) {
+                if let Err(e) = result {
                     event_sender.send(GameThreadEvent::Message(format_error(&e)))
                                 .unwrap();
 
                     *CAPTURING.write().unwrap() = false;
-                    stop_encoder(encoder.take(), event_sender);
-                    drop_frames = true;
+                    stop_session(session.take(), event_sender);
                 }
             }
 
             CaptureThreadEvent::AudioFrame(buffer) => {
                 let buffer = SendOnDrop::new(buffer, audio_buf_sender);
 
-                if drop_frames {
-                    continue;
-                }
-
-                // Encode the audio.
-                let result = encoder.as_mut().unwrap().take_audio(buffer.data());
-
-                drop(buffer);
+                let result = match session {
+                    Some(ref mut session) => session.audio_frame(buffer, event_sender),
+                    None => continue,
+                };
 
                 if let Err(e) = result {
                     event_sender.send(GameThreadEvent::Message(format_error(&e)))
                                 .unwrap();
 
                     *CAPTURING.write().unwrap() = false;
-                    stop_encoder(encoder.take(), event_sender);
-                    drop_frames = true;
+                    stop_session(session.take(), event_sender);
                 }
             }
         }
     }
 }
 
-fn encode(encoder: &mut Option<Encoder>,
-          buf: SendOnDrop<'_, VideoBuffer>,
-          times: usize,
-          frame: &mut VideoFrame)
-          -> Result<()> {
-    // Copy pixels into our video frame.
-    buf.copy_to_frame(frame);
+impl Session {
+    fn start(params: &EncoderParameters,
+             thread_params: &CaptureThreadParameters,
+             event_sender: &Sender<GameThreadEvent>)
+             -> Result<Self> {
+        let mut disk_space_monitor =
+            DiskSpaceMonitor::new(params.filename.clone(), thread_params.min_free_space);
+        match disk_space_monitor.check()
+                                .context("could not check the free disk space")?
+        {
+            DiskSpace::Enough => {}
+            DiskSpace::Low(free) => {
+                event_sender.send(GameThreadEvent::Message(format!("Warning: running low on \
+                                                                    disk space, {} MB left.\n",
+                                                                   disk_space::to_mb(free))))
+                            .unwrap();
+            }
+            DiskSpace::Exhausted(free) => {
+                bail!("only {} MB of disk space left (see cap_min_free_mb)",
+                      disk_space::to_mb(free));
+            }
+        }
 
-    // We're done with buf, now it can receive the next pack of pixels.
-    drop(buf);
+        let hash_log = HashLog::new(&thread_params.hash_log, &thread_params.hash_compare)
+            .context("could not start the hash log")?;
 
-    let encoder = encoder.as_mut().unwrap();
+        let timecodes = TimecodeWriter::create(&thread_params.vfr_timecodes,
+                                               VFR_TIME_BASE.into())?;
 
-    ensure!((frame.width(), frame.height()) == (encoder.width(), encoder.height()),
-            "resolution changes are not supported");
+        let encoder = Encoder::start(params).context({
+                                                "could not start the encoder; check your \
+                                                 terminal (Half-Life's standard output) for \
+                                                 ffmpeg messages"
+                                            })?;
 
-    // Encode the frame.
-    encoder.take(frame, times)
-           .context("could not encode the frame")?;
+        // In the variable frame rate mode the video is faded by the frame timestamps.
+        let vfr_time_base = if params.vfr {
+            Some(VFR_TIME_BASE.into())
+        } else {
+            None
+        };
+
+        let video_fader = VideoFader::new(thread_params.fade,
+                                          encoder.time_base().into(),
+                                          vfr_time_base,
+                                          params.color_conversion);
+        let audio_fader = AudioFader::new(thread_params.fade, encoder.audio_input_rate());
+
+        // Drift only makes sense with both streams.
+        let check_drift = params.video && params.audio && thread_params.av_drift_threshold > 0f64;
+        let drift_monitor = if check_drift {
+            // In the variable frame rate mode the video is counted in timestamp units.
+            let video_unit = if params.vfr {
+                VFR_TIME_BASE
+            } else {
+                encoder.time_base()
+            };
 
-    Ok(())
-}
+            Some(DriftMonitor::new(video_unit.into(),
+                                   encoder.audio_input_rate(),
+                                   thread_params.av_drift_threshold))
+        } else {
+            None
+        };
+
+        Ok(Self { encoder,
+                  disk_space_monitor: Some(disk_space_monitor),
+                  hash_log,
+                  overlay: thread_params.overlay
+                                        .clone()
+                                        .map(|settings| {
+                                            Overlay::new(settings, params.color_conversion)
+                                        }),
+                  overlay_frame: VideoFrame::empty(),
+                  input_display: thread_params.input_display
+                                              .clone()
+                                              .map(|settings| {
+                                                  InputDisplay::new(settings,
+                                                                    params.color_conversion)
+                                              }),
+                  frame_number: 0,
+                  watermark: thread_params.watermark.clone(),
+                  video_resolution: params.video_resolution,
+                  watermark_warned: false,
+                  video_fader,
+                  audio_fader,
+                  audio_processor: AudioProcessor::new(thread_params.audio_limiter,
+                                                       thread_params.loudness_normalization,
+                                                       encoder.audio_input_rate()),
+                  audio_samples: Vec::new(),
+                  audio_offset: AudioOffset::new(thread_params.audio_offset_ms,
+                                                 encoder.audio_input_rate()),
+                  drift_monitor,
+                  last_pts: None,
+                  timecodes,
+                  audio_stretcher: thread_params.audio_stretch.map(|mode| {
+                                       AudioStretcher::new(mode, encoder.audio_input_rate())
+                                   }) })
+    }
+
+    fn video_frame(&mut self,
+                   buf: SendOnDrop<'_, VideoBuffer>,
+                   times: usize,
+                   frame: &mut VideoFrame,
+                   event_sender: &Sender<GameThreadEvent>)
+                   -> Result<()> {
+        // Copy pixels into our video frame.
+        buf.copy_to_frame(frame);
+
+        if let Some(pts) = buf.pts {
+            if let Some(ref mut timecodes) = self.timecodes {
+                timecodes.write(pts)?;
+            }
+
+            // Count the duration of the previous frame, known now that this one arrived.
+            if let (Some(drift_monitor), Some(last_pts)) =
+                (self.drift_monitor.as_mut(), self.last_pts)
+            {
+                drift_monitor.add_video((pts - last_pts) as usize);
+            }
+
+            self.last_pts = Some(pts);
+        } else if let Some(ref mut drift_monitor) = self.drift_monitor {
+            drift_monitor.add_video(times);
+        }
+
+        let frame_info = if self.overlay.is_some() || self.input_display.is_some() {
+            Some(buf.frame_info().clone())
+        } else {
+            None
+        };
+
+        let watermarked = buf.watermarked;
+        let pts = buf.pts;
+
+        // We're done with buf, now it can receive the next pack of pixels.
+        drop(buf);
+
+        // Hash the frame as it arrived from the game thread, before the capture thread draws on
+        // it or fades it.
+        if let Some(ref mut hash_log) = self.hash_log {
+            if let Some(message) = hash_log.video_frame(frame, times, pts)? {
+                event_sender.send(GameThreadEvent::Message(message))
+                            .unwrap();
+            }
+        }
+
+        if !watermarked {
+            if let Some(ref watermark) = self.watermark {
+                if !watermark.draw(frame, self.video_resolution) && !self.watermark_warned {
+                    self.watermark_warned = true;
+                    event_sender.send(GameThreadEvent::Message(format!("Warning: the watermark \
+                                                                        does not support the \
+                                                                        {:?} pixel format.\n",
+                                                                       frame.format())))
+                                .unwrap();
+                }
+            }
+        }
+
+        // The frame info is only present when the overlay or the input display is enabled.
+        if let (Some(input_display), Some(frame_info)) =
+            (self.input_display.as_mut(), frame_info.as_ref())
+        {
+            if let Some(message) = input_display.draw(frame, frame_info.input) {
+                event_sender.send(GameThreadEvent::Message(message))
+                            .unwrap();
+            }
+        }
+
+        if let (Some(overlay), Some(frame_info)) = (self.overlay.as_mut(), frame_info.as_ref()) {
+            if overlay.uses_frame_number() {
+                // Every output frame has a different overlay, so draw and encode them one by one.
+                for _ in 0..times {
+                    copy_frame(frame, &mut self.overlay_frame);
+
+                    if let Some(message) =
+                        overlay.draw(&mut self.overlay_frame, frame_info, self.frame_number)
+                    {
+                        event_sender.send(GameThreadEvent::Message(message))
+                                    .unwrap();
+                    }
+
+                    if let Some(message) =
+                        self.video_fader
+                            .take(&mut self.encoder, &mut self.overlay_frame, 1)?
+                    {
+                        event_sender.send(GameThreadEvent::Message(message))
+                                    .unwrap();
+                    }
+
+                    self.frame_number += 1;
+                }
+
+                return self.check_disk_space(event_sender);
+            }
+
+            if let Some(message) = overlay.draw(frame, frame_info, self.frame_number) {
+                event_sender.send(GameThreadEvent::Message(message))
+                            .unwrap();
+            }
+        }
+
+        // Encode the frame.
+        if let Some(message) = self.video_fader.take(&mut self.encoder, frame, times)? {
+            event_sender.send(GameThreadEvent::Message(message))
+                        .unwrap();
+        }
+
+        self.frame_number += times as u64;
+
+        self.check_disk_space(event_sender)
+    }
+
+    fn audio_frame(&mut self,
+                   buf: SendOnDrop<'_, AudioBuffer>,
+                   event_sender: &Sender<GameThreadEvent>)
+                   -> Result<()> {
+        // Only one of the buffers is filled, depending on cap_audio_float.
+        let float = !buf.float_data().is_empty();
+
+        if let Some(ref mut hash_log) = self.hash_log {
+            let message = if float {
+                hash_log.audio_chunk_float(buf.float_data())?
+            } else {
+                hash_log.audio_chunk(buf.data())?
+            };
+
+            if let Some(message) = message {
+                event_sender.send(GameThreadEvent::Message(message))
+                            .unwrap();
+            }
+        }
+
+        self.audio_samples.clear();
+        if float {
+            self.audio_samples.extend_from_slice(buf.float_data());
+        } else {
+            self.audio_samples
+                .extend(buf.data().iter().map(|&(l, r)| (to_float(l), to_float(r))));
+        }
+
+        self.audio_processor.add_clipped(buf.clipped);
+
+        if let Some(ref mut audio_stretcher) = self.audio_stretcher {
+            audio_stretcher.process(&mut self.audio_samples, buf.timescale);
+        }
+
+        drop(buf);
+
+        // The drift is measured on the audio as captured, before the offset is applied.
+        if let Some(ref mut drift_monitor) = self.drift_monitor {
+            drift_monitor.add_audio(self.audio_samples.len());
+
+            if let Some(message) = drift_monitor.check() {
+                event_sender.send(GameThreadEvent::Message(message))
+                            .unwrap();
+            }
+        }
+
+        self.audio_offset.apply(&mut self.audio_samples);
+
+        // Encode the audio.
+        self.audio_processor.process(&mut self.audio_samples);
+        self.audio_fader.take(&mut self.encoder, &self.audio_samples)?;
+
+        self.check_disk_space(event_sender)
+    }
 
-/// Properly closes and drops the encoder.
-fn stop_encoder(encoder: Option<Encoder>, event_sender: &Sender<GameThreadEvent>) {
-    if let Some(mut encoder) = encoder {
-        if let Err(e) = encoder.finish() {
+    /// Checks the free space on the output filesystem, printing warnings if needed.
+    ///
+    /// Returns an error if the capturing should be stopped.
+    fn check_disk_space(&mut self, event_sender: &Sender<GameThreadEvent>) -> Result<()> {
+        let result = match self.disk_space_monitor {
+            Some(ref mut disk_space_monitor) => disk_space_monitor.check(),
+            None => return Ok(()),
+        };
+
+        match result {
+            Ok(DiskSpace::Enough) => {}
+            Ok(DiskSpace::Low(free)) => {
+                event_sender.send(GameThreadEvent::Message(format!("Warning: running low on \
+                                                                    disk space, {} MB left.\n",
+                                                                   disk_space::to_mb(free))))
+                            .unwrap();
+            }
+            Ok(DiskSpace::Exhausted(free)) => {
+                bail!("only {} MB of disk space left (see cap_min_free_mb), stopping the \
+                       capturing",
+                      disk_space::to_mb(free));
+            }
+            Err(e) => {
+                // Don't stop the capturing just because the check itself doesn't work.
+                let e = e.context("could not check the free disk space; disabling the check");
+                event_sender.send(GameThreadEvent::Message(format_error(&e.into())))
+                            .unwrap();
+                self.disk_space_monitor = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Properly closes the encoder, the hash log and the timecode file.
+    fn finish(&mut self, event_sender: &Sender<GameThreadEvent>) {
+        // Output the frames and the samples held back for the fade-out.
+        match self.video_fader.finish(&mut self.encoder) {
+            Ok(Some(message)) => event_sender.send(GameThreadEvent::Message(message))
+                                             .unwrap(),
+            Ok(None) => {}
+            Err(e) => event_sender.send(GameThreadEvent::Message(format_error(&e)))
+                                  .unwrap(),
+        }
+
+        if let Err(e) = self.audio_fader.finish(&mut self.encoder) {
+            event_sender.send(GameThreadEvent::Message(format_error(&e)))
+                        .unwrap();
+        }
+
+        if let Some(message) = self.audio_processor.stats() {
+            event_sender.send(GameThreadEvent::Message(message))
+                        .unwrap();
+        }
+
+        if let Err(e) = self.encoder.finish() {
             event_sender.send(GameThreadEvent::Message(format_error(&e)))
                         .unwrap();
         }
 
-        drop(encoder);
+        if let Some(ref mut timecodes) = self.timecodes {
+            if let Err(e) = timecodes.finish() {
+                event_sender.send(GameThreadEvent::Message(format_error(&e)))
+                            .unwrap();
+            }
+        }
+
+        if let Some(ref mut hash_log) = self.hash_log {
+            match hash_log.finish() {
+                Ok(Some(message)) => event_sender.send(GameThreadEvent::Message(message))
+                                                 .unwrap(),
+                Ok(None) => {}
+                Err(e) => event_sender.send(GameThreadEvent::Message(format_error(&e)))
+                                      .unwrap(),
+            }
+        }
+    }
+}
+
+/// Copies the frame data, reallocating the destination frame if needed.
+pub fn copy_frame(src: &VideoFrame, dst: &mut VideoFrame) {
+    if src.width() != dst.width() || src.height() != dst.height() || src.format() != dst.format()
+    {
+        *dst = VideoFrame::new(src.format(), src.width(), src.height());
+    }
+
+    dst.set_pts(src.pts());
+
+    for i in 0..src.planes() {
+        dst.data_mut(i).copy_from_slice(src.data(i));
+    }
+}
+
+/// Converts the 16-bit sample value to float with full scale at 1.
+#[inline]
+fn to_float(value: i16) -> f32 {
+    f32::from(value) / 32768f32
+}
+
+/// Properly closes and drops the capture session.
+fn stop_session(session: Option<Session>, event_sender: &Sender<GameThreadEvent>) {
+    if let Some(mut session) = session {
+        session.finish(event_sender);
+        drop(session);
     }
 }
 
@@ -360,7 +962,7 @@ pub fn initialize(_: MainThreadMarker<'_>) {
 }
 
 #[inline]
-pub fn get_buffer(_: MainThreadMarker<'_>, (width, height): (u32, u32)) -> VideoBuffer {
+pub fn get_buffer(engine: &Engine, (width, height): (u32, u32)) -> VideoBuffer {
     let mut buf = VIDEO_BUF_RECEIVER.lock()
                                     .unwrap()
                                     .as_ref()
@@ -369,6 +971,16 @@ pub fn get_buffer(_: MainThreadMarker<'_>, (width, height): (u32, u32)) -> Video
                                     .unwrap();
 
     buf.set_resolution(width, height);
+    buf.watermarked = false;
+    buf.pts = None;
+
+    // Skip the engine lookups when nothing is going to draw the frame info.
+    if get_capture_parameters(engine).frame_info {
+        let marker = engine.marker().1;
+        buf.frame_info.map = hw::get_map_name(marker);
+        buf.frame_info.time = hw::get_client_time(marker);
+        buf.frame_info.input = engine.data().input_state;
+    }
 
     buf
 }
@@ -437,7 +1049,20 @@ pub fn get_capture_parameters(engine: &Engine) -> &CaptureParameters {
     engine.data().capture_parameters.as_ref().unwrap()
 }
 
+/// Finishes and closes the input log if it's open.
+pub fn finish_input_log(engine: &mut Engine) {
+    if let Some(mut input_log) = engine.data_mut().input_log.take() {
+        if let Err(ref e) = input_log.finish() {
+            engine.con_print(&format_error(e));
+        }
+    }
+}
+
 pub fn stop(engine: &mut Engine) {
+    // The capture thread could have stopped the capturing because of an error, in which case the
+    // input log is still open.
+    finish_input_log(engine);
+
     if !is_capturing() {
         return;
     }
@@ -447,6 +1072,7 @@ pub fn stop(engine: &mut Engine) {
     *CAPTURING.write().unwrap() = false;
     engine.data_mut().fps_converter = None;
     engine.data_mut().encoder_pixel_format = None;
+    engine.data_mut().watermark = None;
 
     SEND_TO_CAPTURE_THREAD.lock()
                           .unwrap()
@@ -517,11 +1143,12 @@ fn parse_exposure(string: &str) -> Result<f64> {
           .context("could not convert the string to a floating point value")
           .map_err(|e| e.into())
           .and_then(|x| {
-              if x > 0f64 && x <= 1f64 {
+              if x > 0f64 && x <= MAX_SAMPLING_EXPOSURE {
                   Ok(x)
               } else {
                   bail!("allowed exposure values range \
-                         from 0 (non-inclusive) to 1 (inclusive)")
+                         from 0 (non-inclusive) to {} (inclusive)",
+                        MAX_SAMPLING_EXPOSURE)
               }
           })
 }
@@ -537,6 +1164,89 @@ fn parse_pixel_format(string: &str) -> Result<format::Pixel> {
     }
 }
 
+/// Parses the given string into a capture region.
+///
+/// The string is either empty, which means the whole screen, or `<x> <y> <width> <height>` with
+/// the origin in the top left corner of the screen.
+fn parse_crop(string: &str, (screen_width, screen_height): (u32, u32)) -> Result<Region> {
+    if string.trim().is_empty() {
+        return Ok(Region::whole((screen_width, screen_height)));
+    }
+
+    let values = string.split_whitespace()
+                       .map(str::parse::<u32>)
+                       .collect::<result::Result<Vec<_>, _>>()
+                       .context("could not convert the values to non-negative integers")?;
+    ensure!(values.len() == 4,
+            "expected four values: <x> <y> <width> <height>");
+
+    let region = Region { x: values[0],
+                          y: values[1],
+                          width: values[2],
+                          height: values[3] };
+
+    ensure!(region.width > 0 && region.height > 0,
+            "the width and the height must be positive");
+    ensure!(u64::from(region.x) + u64::from(region.width) <= u64::from(screen_width)
+            && u64::from(region.y) + u64::from(region.height) <= u64::from(screen_height),
+            "the region does not fit into the game resolution ({}×{})",
+            screen_width,
+            screen_height);
+
+    Ok(region)
+}
+
+/// Parses the given string into an output resolution.
+///
+/// The string is either empty, which means no scaling, or `<width>x<height>`.
+fn parse_output_resolution(string: &str) -> Result<Option<(u32, u32)>> {
+    let string = string.trim();
+
+    if string.is_empty() {
+        return Ok(None);
+    }
+
+    let mut split = string.splitn(2, 'x');
+    let width = split.next().unwrap().trim();
+    let height = split.next()
+                      .ok_or_else(|| err_msg("expected <width>x<height>"))?
+                      .trim();
+
+    let width = width.parse::<u32>()
+                     .context("could not convert the width to a non-negative integer")?;
+    let height = height.parse::<u32>()
+                       .context("could not convert the height to a non-negative integer")?;
+
+    ensure!(width > 0 && height > 0,
+            "the width and the height must be positive");
+
+    Ok(Some((width, height)))
+}
+
+/// Parses the given string into a scaling algorithm.
+#[inline]
+fn parse_scaling_algorithm(string: &str) -> Result<ScalingAlgorithm> {
+    match string.trim() {
+        "bilinear" => Ok(ScalingAlgorithm::Bilinear),
+        "bicubic" => Ok(ScalingAlgorithm::Bicubic),
+        "lanczos" => Ok(ScalingAlgorithm::Lanczos),
+        "area" => Ok(ScalingAlgorithm::Area),
+        _ => bail!("allowed values are bilinear, bicubic, lanczos and area"),
+    }
+}
+
+/// Parses the given string into a `FrameDuplication`.
+#[inline]
+fn parse_frame_duplication(string: &str) -> Result<FrameDuplication> {
+    match string.trim() {
+        "encode" => Ok(FrameDuplication::Encode),
+        "stretch" => Ok(FrameDuplication::Stretch),
+        "repeat" => Ok(FrameDuplication::Repeat),
+        "auto" => Ok(FrameDuplication::Auto),
+        _ => bail!("allowed values are encode, stretch, repeat and auto"),
+    }
+}
+
 macro_rules! to_string {
     ($engine:expr, $cvar:expr) => {
         $cvar.to_string($engine)
@@ -573,24 +1283,310 @@ fn parse_encoder_parameters(engine: &mut Engine) -> Result<EncoderParameters> {
         audio_encoder_settings: to_string!(engine, cap_audio_encoder_settings),
         video_encoder_settings: to_string!(engine, cap_video_encoder_settings),
         vpx_threads: to_string!(engine, cap_vpx_threads),
-        video_resolution: hw::get_resolution(engine.marker().1),
+        vfr: parse_fps_converter(engine)?.variable_frame_rate,
+        frame_duplication: parse_frame_duplication(&to_string!(engine, cap_frame_duplication))
+            .context("invalid cap_frame_duplication")?,
+        audio_input_rate: parse_audio_input_rate(engine)?,
+        audio_float: parse!(engine, cap_audio_float, i32) != 0,
+        video: parse!(engine, cap_video, i32) != 0,
+        audio: parse_audio_enabled(engine)?,
+        video_filter: to_string!(engine, cap_video_filter),
+        audio_filter: to_string!(engine, cap_audio_filter),
+        input_resolution: parse_capture_region(engine)?.size(),
+        video_resolution: parse_video_resolution(engine)?,
+        scaling_algorithm: parse_scaling_algorithm(&to_string!(engine, cap_scaling_algorithm))
+            .context("invalid cap_scaling_algorithm")?,
+        color_conversion: parse_color_conversion(engine)?,
     })
 }
 
+/// Parses `cap_colorspace` and `cap_color_range` into `ColorConversion`.
+#[inline]
+fn parse_color_conversion(engine: &mut Engine) -> Result<ColorConversion> {
+    let space = color::parse_color_space(&to_string!(engine, cap_colorspace))
+        .context("invalid cap_colorspace")?;
+    let range = color::parse_color_range(&to_string!(engine, cap_color_range))
+        .context("invalid cap_color_range")?;
+
+    Ok(ColorConversion { space, range })
+}
+
+/// Returns the engine sound output rate, checking that the sound output can be captured.
+#[inline]
+fn parse_audio_input_rate(engine: &mut Engine) -> Result<u32> {
+    let audio = parse_audio_enabled(engine)?;
+
+    match hw::get_sound_output(engine.marker().1) {
+        Some((rate, channels, bits)) => {
+            if audio {
+                check_sound_output(channels, bits)?;
+            }
+
+            Ok(rate)
+        }
+        None => {
+            ensure!(!audio,
+                    "the sound is not initialized; set cap_audio 0 to capture without audio");
+            Ok(DEFAULT_SOUND_RATE)
+        }
+    }
+}
+
+/// Returns whether the audio is captured, which it isn't when the timelapse mode drops it.
+#[inline]
+fn parse_audio_enabled(engine: &mut Engine) -> Result<bool> {
+    let audio = parse!(engine, cap_audio, i32) != 0;
+    if parse_timelapse(engine)?.is_none() {
+        return Ok(audio);
+    }
+
+    let timelapse_audio =
+        timescale::parse_timelapse_audio(&to_string!(engine, cap_timelapse_audio))
+            .context("invalid cap_timelapse_audio")?;
+    Ok(audio && timelapse_audio != TimelapseAudio::Drop)
+}
+
+/// Parses `cap_timelapse` into the timelapse factor.
+#[inline]
+fn parse_timelapse(engine: &mut Engine) -> Result<Option<f64>> {
+    Ok(timescale::parse_timelapse(&to_string!(engine, cap_timelapse))
+        .context("invalid cap_timelapse")?)
+}
+
+/// Checks that the sound output format can be captured.
+///
+/// The sound is captured in `S_TransferStereo16()` which the engine only uses for 16-bit stereo.
+fn check_sound_output(channels: u32, bits: u32) -> Result<()> {
+    ensure!(channels == 2 && bits == 16,
+            "only 16-bit stereo sound output can be captured, but the current one is {}-bit \
+             with {} channels; set cap_audio 0 to capture without audio",
+            bits,
+            channels);
+    Ok(())
+}
+
+/// Parses `cap_crop` into the part of the screen to capture.
+#[inline]
+fn parse_capture_region(engine: &mut Engine) -> Result<Region> {
+    let screen_resolution = hw::get_resolution(engine.marker().1);
+    Ok(parse_crop(&to_string!(engine, cap_crop), screen_resolution).context("invalid cap_crop")?)
+}
+
+/// Parses `cap_output_resolution` into the output video resolution.
+#[inline]
+fn parse_video_resolution(engine: &mut Engine) -> Result<(u32, u32)> {
+    match parse_output_resolution(&to_string!(engine, cap_output_resolution))
+        .context("invalid cap_output_resolution")?
+    {
+        Some(resolution) => Ok(resolution),
+        None => Ok(parse_capture_region(engine)?.size()),
+    }
+}
+
+/// Parses `cap_fps_converter` into the selected FPS converter.
+#[inline]
+fn parse_fps_converter(engine: &mut Engine) -> Result<&'static FPSConverterInfo> {
+    let vfr = parse!(engine, cap_vfr, i32) != 0;
+    let sampling = parse_fps(&to_string!(engine, cap_sampling_sps)).is_some()
+                   || (parse_timelapse(engine)?.is_some()
+                       && parse!(engine, cap_timelapse_blend, i32) != 0);
+
+    Ok(registry::select(&to_string!(engine, registry::cap_fps_converter), vfr, sampling)
+        .context("invalid cap_fps_converter")?)
+}
+
 /// Parses the CVar values into `CaptureParameters`.
 #[inline]
 fn parse_capture_parameters(engine: &mut Engine) -> Result<CaptureParameters> {
+    let fps_converter = parse_fps_converter(engine)?;
+    let vfr = fps_converter.variable_frame_rate;
+    let sampling_time_base = parse_fps(&to_string!(engine, cap_sampling_sps));
+    ensure!(!vfr || sampling_time_base.is_none(),
+            "the {} FPS converter cannot be used together with cap_sampling_sps",
+            fps_converter.name);
+
+    let timescale = timescale::parse_timescale(&to_string!(engine, cap_timescale),
+                                               &to_string!(engine, cap_timescale_ramp))
+        .context("invalid cap_timescale or cap_timescale_ramp")?;
+
+    let timelapse = parse_timelapse(engine)?;
+    ensure!(timelapse.is_none() || timescale.is_none(),
+            "cap_timelapse cannot be used together with cap_timescale or cap_timescale_ramp");
+    ensure!(timelapse.is_none() || !vfr,
+            "cap_timelapse cannot be used together with the {} FPS converter",
+            fps_converter.name);
+
+    let timelapse_blend = timelapse.is_some() && parse!(engine, cap_timelapse_blend, i32) != 0;
+
     Ok(CaptureParameters {
-        sampling_exposure: parse_exposure(&to_string!(engine, cap_sampling_exposure))
-            .context("invalid cap_sampling_exposure")?,
-        sampling_time_base: parse_fps(&to_string!(engine, cap_sampling_sps)),
+        video: parse!(engine, cap_video, i32) != 0,
+        audio: parse_audio_enabled(engine)?,
+        capture_region: parse_capture_region(engine)?,
+        output_resolution: parse_video_resolution(engine)?,
+        scaling_algorithm: parse_scaling_algorithm(&to_string!(engine, cap_scaling_algorithm))
+            .context("invalid cap_scaling_algorithm")?,
+        sampling_shutter: parse_sampling_shutter(engine)?,
+        sampling_linear: parse!(engine, cap_sampling_linear, i32) != 0,
+        sampling_time_base,
         sound_extra: parse!(engine, cap_sound_extra),
         time_base: parse_fps(&to_string!(engine, cap_fps))
             .ok_or_else(|| err_msg("invalid cap_fps"))?,
         volume: parse!(engine, cap_volume),
+        audio_float: parse!(engine, cap_audio_float, i32) != 0,
+        vfr,
+        fps_converter,
+        color_conversion: parse_color_conversion(engine)?,
+        timescale,
+        timelapse,
+        timelapse_blend,
+        frame_info: !to_string!(engine, cap_overlay_text).is_empty()
+                    || parse!(engine, cap_input_display, i32) != 0,
     })
 }
 
+/// Parses the sampling CVar values into `Shutter`.
+#[inline]
+fn parse_sampling_shutter(engine: &mut Engine) -> Result<Shutter> {
+    let exposure = parse_exposure(&to_string!(engine, cap_sampling_exposure))
+        .context("invalid cap_sampling_exposure")?;
+    let curve = shutter::parse_curve(&to_string!(engine, cap_sampling_curve))
+        .context("invalid cap_sampling_curve")?;
+    let phase = shutter::parse_phase(&to_string!(engine, cap_sampling_phase))
+        .context("invalid cap_sampling_phase")?;
+
+    Ok(Shutter::new(exposure, &curve, phase))
+}
+
+/// Parses the CVar values into `CaptureThreadParameters`.
+#[inline]
+fn parse_capture_thread_parameters(engine: &mut Engine) -> Result<CaptureThreadParameters> {
+    let audio_limiter = audio::parse_limiter(&to_string!(engine, cap_audio_limiter))
+        .context("invalid cap_audio_limiter")?;
+    let loudness_normalization =
+        audio::parse_loudness_normalization(&to_string!(engine, cap_audio_normalize),
+                                            &to_string!(engine, cap_audio_normalize_target))
+        .context("invalid cap_audio_normalize or cap_audio_normalize_target")?;
+
+    let vfr_timecodes = to_string!(engine, cap_vfr_timecodes);
+    ensure!(vfr_timecodes.is_empty() || parse_fps_converter(engine)?.variable_frame_rate,
+            "cap_vfr_timecodes requires the vfr FPS converter (cap_vfr 1)");
+
+    // The audio only needs stretching when the game time is scaled.
+    let timescaled = timescale::parse_timescale(&to_string!(engine, cap_timescale),
+                                                &to_string!(engine, cap_timescale_ramp))
+        .context("invalid cap_timescale or cap_timescale_ramp")?
+        .is_some();
+    let audio_stretch = if timescaled {
+        Some(timescale::parse_audio_stretch_mode(&to_string!(engine, cap_timescale_audio))
+            .context("invalid cap_timescale_audio")?)
+    } else if parse_timelapse(engine)?.is_some() {
+        // Without a drop the timelapse audio is silent.
+        Some(AudioStretchMode::Mute)
+    } else {
+        None
+    };
+
+    let min_free_space = parse!(engine, cap_min_free_mb, u64)
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| err_msg("cap_min_free_mb is too large"))?;
+
+    Ok(CaptureThreadParameters { min_free_space,
+                                 hash_log: to_string!(engine, cap_hash_log),
+                                 hash_compare: to_string!(engine, cap_hash_compare),
+                                 overlay: parse_overlay_settings(engine)?,
+                                 input_display: parse_input_display_settings(engine)?,
+                                 watermark: watermark::load()?,
+                                 fade: parse_fade(engine)?,
+                                 audio_limiter,
+                                 loudness_normalization,
+                                 audio_offset_ms: parse!(engine, cap_audio_offset_ms, i64),
+                                 av_drift_threshold: parse!(engine,
+                                                            cap_av_drift_threshold_ms,
+                                                            f64)
+                                                     / 1000f64,
+                                 vfr_timecodes,
+                                 audio_stretch })
+}
+
+/// Parses the overlay CVar values into `OverlaySettings`.
+#[inline]
+fn parse_overlay_settings(engine: &mut Engine) -> Result<Option<OverlaySettings>> {
+    let text = to_string!(engine, cap_overlay_text);
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    let size = parse!(engine, cap_overlay_size, u32);
+    ensure!(size <= overlay::MAX_SIZE,
+            "cap_overlay_size must be at most {}",
+            overlay::MAX_SIZE);
+
+    Ok(Some(OverlaySettings {
+        text,
+        position: overlay::parse_position(&to_string!(engine, cap_overlay_position))
+            .context("invalid cap_overlay_position")?,
+        size,
+        color: overlay::parse_color(&to_string!(engine, cap_overlay_color))
+            .context("invalid cap_overlay_color")?,
+    }))
+}
+
+/// Parses the fade CVar values into `Fade`.
+#[inline]
+fn parse_fade(engine: &mut Engine) -> Result<Fade> {
+    let fade = Fade { fade_in: parse!(engine, cap_fade_in, f64),
+                      fade_out: parse!(engine, cap_fade_out, f64) };
+
+    ensure!(fade.fade_in.is_finite(), "cap_fade_in must be a finite number");
+    ensure!(fade.fade_out.is_finite(), "cap_fade_out must be a finite number");
+    ensure!(fade.fade_in >= 0f64, "cap_fade_in cannot be negative");
+    ensure!(fade.fade_out >= 0f64, "cap_fade_out cannot be negative");
+
+    // The frames covering the fade-out are held back in memory until the end of the capture.
+    if let Some(time_base) = parse_fps(&to_string!(engine, cap_fps)) {
+        let memory = fade.fade_out_memory(time_base.into(), parse_capture_region(engine)?.size());
+        ensure!(memory <= MAX_FADE_OUT_MEMORY,
+                "cap_fade_out is too long for this resolution and cap_fps: the held back frames \
+                 would take {} MB out of at most {} MB",
+                memory / 1024 / 1024,
+                MAX_FADE_OUT_MEMORY / 1024 / 1024);
+    }
+
+    Ok(fade)
+}
+
+/// Parses the input display CVar values into `InputDisplaySettings`.
+#[inline]
+fn parse_input_display_settings(engine: &mut Engine) -> Result<Option<InputDisplaySettings>> {
+    if parse!(engine, cap_input_display, i32) == 0 {
+        return Ok(None);
+    }
+
+    let size = parse!(engine, cap_input_display_size, u32);
+    ensure!(size <= overlay::MAX_SIZE,
+            "cap_input_display_size must be at most {}",
+            overlay::MAX_SIZE);
+
+    Ok(Some(InputDisplaySettings {
+        position: overlay::parse_position(&to_string!(engine, cap_input_display_position))
+            .context("invalid cap_input_display_position")?,
+        size,
+        color: overlay::parse_color(&to_string!(engine, cap_input_display_color))
+            .context("invalid cap_input_display_color")?,
+    }))
+}
+
+/// Opens the input log if it's enabled.
+#[inline]
+fn open_input_log(engine: &mut Engine) -> Result<Option<InputLog>> {
+    let filename = to_string!(engine, cap_input_log);
+    if filename.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(InputLog::new(&filename)?))
+}
+
 /// Starts and stops the encoder.
 fn test_encoder(parameters: &EncoderParameters) -> Result<()> {
     let mut encoder = Encoder::start(parameters).context({
@@ -616,6 +1612,14 @@ command!(cap_start, |mut engine| {
         }
     };
 
+    let thread_parameters = match parse_capture_thread_parameters(&mut engine) {
+        Ok(p) => p,
+        Err(ref e) => {
+            engine.con_print(&format_error(e));
+            return;
+        }
+    };
+
     engine.data_mut().capture_parameters = match parse_capture_parameters(&mut engine) {
         Ok(p) => Some(p),
         Err(ref e) => {
@@ -624,19 +1628,37 @@ command!(cap_start, |mut engine| {
         }
     };
 
-    engine.data_mut().fps_converter = if engine.data()
-                                               .capture_parameters
-                                               .as_ref()
-                                               .unwrap()
-                                               .sampling_time_base
-                                               .is_some()
-    {
-        Some(FPSConverters::Sampling(SamplingConverter::new(&mut engine,
-                                                            parameters.time_base.into(),
-                                                            parameters.video_resolution)))
+    engine.data_mut().input_log = match open_input_log(&mut engine) {
+        Ok(log) => log,
+        Err(ref e) => {
+            engine.con_print(&format_error(e));
+            return;
+        }
+    };
+
+    let (video, fps_converter_info) = {
+        let capture_parameters = engine.data().capture_parameters.as_ref().unwrap();
+        (capture_parameters.video, capture_parameters.fps_converter)
+    };
+
+    let fps_converter = if !video {
+        // Without video there are no frames to capture.
+        None
     } else {
-        Some(FPSConverters::Simple(SimpleConverter::new(parameters.time_base.into())))
+        let fps_converter = (fps_converter_info.create)(&mut engine,
+                                                        parameters.time_base.into(),
+                                                        parameters.video_resolution);
+
+        // With the timescale or the timelapse the chosen converter receives the output time.
+        Some(registry::wrap(fps_converter, get_capture_parameters(&engine)))
     };
+    engine.data_mut().fps_converter = fps_converter;
+    engine.data_mut().timescale = 1f64;
+
+    engine.data_mut().watermark = thread_parameters.watermark.clone();
+    engine.data_mut().ocl_watermark = None;
+
+    engine.data_mut().capture_time = 0f64;
 
     *CAPTURING.write().unwrap() = true;
 
@@ -644,7 +1666,7 @@ command!(cap_start, |mut engine| {
                           .unwrap()
                           .as_ref()
                           .unwrap()
-                          .send(CaptureThreadEvent::CaptureStart(parameters))
+                          .send(CaptureThreadEvent::CaptureStart((parameters, thread_parameters)))
                           .unwrap();
 
     // GAME_THREAD_PROFILER.with(|p| *p.borrow_mut() = Some(Profiler::new()));
@@ -674,6 +1696,14 @@ command!(cap_test, |mut engine| {
         }
     };
 
+    let _thread_parameters = match parse_capture_thread_parameters(&mut engine) {
+        Ok(p) => p,
+        Err(ref e) => {
+            engine.con_print(&format_error(e));
+            return;
+        }
+    };
+
     if let Err(ref e) = test_encoder(&parameters) {
         engine.con_print(&format_error(e));
     } else {
@@ -689,20 +1719,128 @@ command!(cap_version, |engine| {
 });
 
 // Encoder parameters.
+cvar!(cap_audio, "1");
+cvar!(cap_video, "1");
 cvar!(cap_video_bitrate, "0");
 cvar!(cap_audio_bitrate, "256");
 cvar!(cap_crf, "15");
 cvar!(cap_filename, "capture.mp4");
 cvar!(cap_fps, "60");
+cvar!(cap_frame_duplication, "auto");
 cvar!(cap_muxer_settings, "movflags=+faststart");
+cvar!(cap_output_resolution, "");
 cvar!(cap_pixel_format, "");
 cvar!(cap_audio_encoder_settings, "");
+cvar!(cap_audio_filter, "");
 cvar!(cap_video_encoder_settings, "");
+cvar!(cap_video_filter, "");
 cvar!(cap_vpx_threads, "8");
 cvar!(cap_x264_preset, "veryfast");
 
 // Capture parameters.
-cvar!(cap_sampling_exposure, "0.5");
-cvar!(cap_sampling_sps, "");
+cvar!(cap_audio_float, "0");
+cvar!(cap_audio_limiter, "none");
+cvar!(cap_audio_normalize, "none");
+cvar!(cap_audio_normalize_target, "-14");
+cvar!(cap_audio_offset_ms, "0");
+cvar!(cap_av_drift_threshold_ms, "100");
+cvar!(cap_color_range, "limited");
+cvar!(cap_colorspace, "bt601");
+cvar!(cap_crop, "");
+cvar!(cap_fade_in, "0");
+cvar!(cap_fade_out, "0");
+cvar!(cap_hash_compare, "");
+cvar!(cap_hash_log, "");
+cvar!(cap_input_display, "0");
+cvar!(cap_input_display_color, "255 255 255");
+cvar!(cap_input_display_position, "16 -16");
+cvar!(cap_input_display_size, "16");
+cvar!(cap_input_log, "");
+cvar!(cap_min_free_mb, "100");
+cvar!(cap_overlay_color, "255 255 255");
+cvar!(cap_overlay_position, "16 16");
+cvar!(cap_overlay_size, "16");
+cvar!(cap_overlay_text, "");
+cvar!(cap_scaling_algorithm, "bicubic");
 cvar!(cap_sound_extra, "0");
 cvar!(cap_volume, "0.4");
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_crop_test() {
+        assert_eq!(parse_crop("", (1920, 1080)).unwrap(),
+                   Region::whole((1920, 1080)));
+        assert_eq!(parse_crop("656 0 608 1080", (1920, 1080)).unwrap(),
+                   Region { x: 656,
+                            y: 0,
+                            width: 608,
+                            height: 1080 });
+        assert_eq!(parse_crop("  0  40 1920   1000 ", (1920, 1080)).unwrap(),
+                   Region { x: 0,
+                            y: 40,
+                            width: 1920,
+                            height: 1000 });
+    }
+
+    #[test]
+    fn parse_crop_invalid_test() {
+        assert!(parse_crop("0 0 100", (1920, 1080)).is_err());
+        assert!(parse_crop("0 0 100 100 100", (1920, 1080)).is_err());
+        assert!(parse_crop("-1 0 100 100", (1920, 1080)).is_err());
+        assert!(parse_crop("0 0 0 100", (1920, 1080)).is_err());
+        assert!(parse_crop("1000 0 1000 100", (1920, 1080)).is_err());
+        assert!(parse_crop("0 1 1920 1080", (1920, 1080)).is_err());
+    }
+
+    #[test]
+    fn parse_output_resolution_test() {
+        assert_eq!(parse_output_resolution("").unwrap(), None);
+        assert_eq!(parse_output_resolution("1920x1080").unwrap(),
+                   Some((1920, 1080)));
+        assert_eq!(parse_output_resolution(" 1080 x 1920 ").unwrap(),
+                   Some((1080, 1920)));
+
+        assert!(parse_output_resolution("1920").is_err());
+        assert!(parse_output_resolution("1920x").is_err());
+        assert!(parse_output_resolution("0x1080").is_err());
+        assert!(parse_output_resolution("-1x1080").is_err());
+        assert!(parse_output_resolution("1920 1080").is_err());
+    }
+
+    #[test]
+    fn parse_scaling_algorithm_test() {
+        assert_eq!(parse_scaling_algorithm("lanczos").unwrap(),
+                   ScalingAlgorithm::Lanczos);
+        assert!(parse_scaling_algorithm("nearest").is_err());
+    }
+
+    #[test]
+    fn parse_frame_duplication_test() {
+        assert_eq!(parse_frame_duplication(" stretch ").unwrap(),
+                   FrameDuplication::Stretch);
+        assert!(parse_frame_duplication("skip").is_err());
+    }
+
+    #[test]
+    fn region_flip_vertically_test() {
+        let region = Region { x: 10,
+                              y: 20,
+                              width: 100,
+                              height: 200 };
+
+        assert_eq!(region.flip_vertically(1080),
+                   Region { y: 860,
+                            ..region });
+        assert_eq!(region.flip_vertically(1080).flip_vertically(1080), region);
+    }
+
+    #[test]
+    fn check_sound_output_test() {
+        assert!(check_sound_output(2, 16).is_ok());
+        assert!(check_sound_output(1, 16).is_err());
+        assert!(check_sound_output(2, 8).is_err());
+    }
+}
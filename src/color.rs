@@ -0,0 +1,123 @@
+use failure::{bail, Error};
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Matrix used for converting RGB into YUV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// ITU-R BT.601, the standard definition one.
+    BT601,
+
+    /// ITU-R BT.709, the high definition one.
+    BT709,
+}
+
+/// Range of the YUV values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRange {
+    /// Y from 16 to 235 and U and V from 16 to 240.
+    Limited,
+
+    /// All values from 0 to 255.
+    Full,
+}
+
+/// How the RGB frames are converted into YUV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorConversion {
+    pub space: ColorSpace,
+    pub range: ColorRange,
+}
+
+impl ColorSpace {
+    /// Returns the red and blue luma coefficients.
+    #[inline]
+    fn coefficients(self) -> (f32, f32) {
+        match self {
+            ColorSpace::BT601 => (0.299, 0.114),
+            ColorSpace::BT709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+impl ColorConversion {
+    /// Converts the color into YUV, matching the OpenCL conversion kernels.
+    pub fn rgb_to_yuv(self, [r, g, b, _]: [u8; 4]) -> [u8; 3] {
+        let (r, g, b) = (f32::from(r) / 255f32, f32::from(g) / 255f32, f32::from(b) / 255f32);
+        let (kr, kb) = self.space.coefficients();
+
+        let y = kr * r + (1f32 - kr - kb) * g + kb * b;
+        let pb = (b - y) / (2f32 * (1f32 - kb));
+        let pr = (r - y) / (2f32 * (1f32 - kr));
+
+        let (y, u, v) = match self.range {
+            ColorRange::Limited => (16f32 + 219f32 * y, 128f32 + 224f32 * pb, 128f32 + 224f32 * pr),
+            ColorRange::Full => (255f32 * y, 128f32 + 255f32 * pb, 128f32 + 255f32 * pr),
+        };
+
+        let to_u8 = |x: f32| x.round().max(0f32).min(255f32) as u8;
+        [to_u8(y), to_u8(u), to_u8(v)]
+    }
+
+    /// Returns black in YUV.
+    #[inline]
+    pub fn black(self) -> [u8; 3] {
+        self.rgb_to_yuv([0, 0, 0, 255])
+    }
+}
+
+/// Parses the given string into a `ColorSpace`.
+pub fn parse_color_space(string: &str) -> Result<ColorSpace> {
+    match string.trim() {
+        "bt601" => Ok(ColorSpace::BT601),
+        "bt709" => Ok(ColorSpace::BT709),
+        _ => bail!("allowed values are bt601 and bt709"),
+    }
+}
+
+/// Parses the given string into a `ColorRange`.
+pub fn parse_color_range(string: &str) -> Result<ColorRange> {
+    match string.trim() {
+        "limited" => Ok(ColorRange::Limited),
+        "full" => Ok(ColorRange::Full),
+        _ => bail!("allowed values are limited and full"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conversion(space: ColorSpace, range: ColorRange) -> ColorConversion {
+        ColorConversion { space, range }
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse_color_space("bt601").unwrap(), ColorSpace::BT601);
+        assert_eq!(parse_color_space(" bt709 ").unwrap(), ColorSpace::BT709);
+        assert!(parse_color_space("bt2020").is_err());
+
+        assert_eq!(parse_color_range("limited").unwrap(), ColorRange::Limited);
+        assert_eq!(parse_color_range("full").unwrap(), ColorRange::Full);
+        assert!(parse_color_range("tv").is_err());
+    }
+
+    #[test]
+    fn rgb_to_yuv_test() {
+        let bt601_limited = conversion(ColorSpace::BT601, ColorRange::Limited);
+        assert_eq!(bt601_limited.black(), [16, 128, 128]);
+        assert_eq!(bt601_limited.rgb_to_yuv([255, 255, 255, 255]), [235, 128, 128]);
+        assert_eq!(bt601_limited.rgb_to_yuv([255, 0, 0, 255]), [81, 90, 240]);
+
+        let bt709_limited = conversion(ColorSpace::BT709, ColorRange::Limited);
+        assert_eq!(bt709_limited.rgb_to_yuv([255, 0, 0, 255]), [63, 102, 240]);
+        assert_eq!(bt709_limited.rgb_to_yuv([0, 255, 0, 255]), [173, 42, 26]);
+
+        let bt709_full = conversion(ColorSpace::BT709, ColorRange::Full);
+        assert_eq!(bt709_full.black(), [0, 128, 128]);
+        assert_eq!(bt709_full.rgb_to_yuv([255, 255, 255, 255]), [255, 128, 128]);
+        assert_eq!(bt709_full.rgb_to_yuv([0, 0, 255, 255]), [18, 255, 116]);
+    }
+}
@@ -94,6 +94,18 @@ impl CVar {
         Ok(())
     }
 
+    /// Returns the name of this variable.
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Sets this variable to the given value.
+    #[inline]
+    pub fn set(&self, engine: &mut Engine, value: &str) -> Result<()> {
+        engine.set_variable(self, value)
+    }
+
     /// Returns the string this variable is set to.
     pub fn to_string(&self, engine: &mut Engine) -> Result<String> {
         let engine_cvar = engine.get_engine_cvar(self);
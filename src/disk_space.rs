@@ -0,0 +1,103 @@
+use failure::{bail, Error, ResultExt};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::result;
+use std::time::{Duration, Instant};
+
+type Result<T> = result::Result<T, Error>;
+
+/// How often the free space is checked during capturing.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of the free space on the output filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskSpace {
+    /// There's plenty of free space.
+    Enough,
+
+    /// The free space is getting close to the threshold.
+    Low(u64),
+
+    /// The free space is below the threshold.
+    Exhausted(u64),
+}
+
+/// Periodically checks the free space on the filesystem where the output file is located.
+pub struct DiskSpaceMonitor {
+    /// The output file name.
+    filename: String,
+
+    /// Minimum free space, in bytes.
+    min_free_space: u64,
+
+    /// When the free space was last checked.
+    last_check: Option<Instant>,
+
+    /// Whether the low disk space warning was already given.
+    warned: bool,
+}
+
+impl DiskSpaceMonitor {
+    #[inline]
+    pub fn new(filename: String, min_free_space: u64) -> Self {
+        Self { filename,
+               min_free_space,
+               last_check: None,
+               warned: false }
+    }
+
+    /// Checks the free space if enough time has passed since the last check.
+    ///
+    /// `DiskSpace::Low` is returned only once, the first time the free space drops below twice
+    /// the threshold.
+    pub fn check(&mut self) -> Result<DiskSpace> {
+        if self.min_free_space == 0 {
+            return Ok(DiskSpace::Enough);
+        }
+
+        let now = Instant::now();
+        if let Some(last_check) = self.last_check {
+            if now.duration_since(last_check) < CHECK_INTERVAL {
+                return Ok(DiskSpace::Enough);
+            }
+        }
+        self.last_check = Some(now);
+
+        let free = free_space(&self.filename)?;
+
+        if free < self.min_free_space {
+            Ok(DiskSpace::Exhausted(free))
+        } else if free < self.min_free_space.saturating_mul(2) && !self.warned {
+            self.warned = true;
+            Ok(DiskSpace::Low(free))
+        } else {
+            Ok(DiskSpace::Enough)
+        }
+    }
+}
+
+/// Returns the space, in bytes, available to unprivileged users on the filesystem containing
+/// the given file.
+pub fn free_space(filename: &str) -> Result<u64> {
+    let dir = Path::new(filename).parent()
+                                 .filter(|p| !p.as_os_str().is_empty())
+                                 .unwrap_or_else(|| Path::new("."));
+    let dir = CString::new(dir.as_os_str().as_bytes())
+        .context("could not convert the output directory to a CString")?;
+
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(dir.as_ptr(), &mut stat) } != 0 {
+        bail!("statvfs failed with `{}`", io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Converts a size in bytes into mebibytes for printing.
+#[inline]
+pub fn to_mb(bytes: u64) -> u64 {
+    bytes / (1024 * 1024)
+}
@@ -1,15 +1,20 @@
-use failure::{bail, ensure, Error, ResultExt};
+use failure::{bail, ensure, format_err, Error, ResultExt};
 use ffmpeg::channel_layout::{self, ChannelLayout};
 use ffmpeg::codec::{self, encoder};
 use ffmpeg::format::{self, context};
 use ffmpeg::software::{self, resampling, scaling};
 use ffmpeg::util::frame;
-use ffmpeg::{self, color, Packet, Rational};
+use ffmpeg::{self, color, ffi, Packet, Rational};
 use lazy_static::lazy_static;
+use libc::c_int;
 use std::cmp;
+use std::collections::HashMap;
+use std::iter;
 use std::result;
 use std::sync::{Mutex, Once, ONCE_INIT};
 
+use crate::color::{ColorConversion, ColorRange, ColorSpace};
+use crate::filter_graph::{AudioFilter, VideoFilter};
 use crate::utils::format_error;
 
 type Result<T> = result::Result<T, Error>;
@@ -20,35 +25,75 @@ lazy_static! {
 }
 
 const HL_SAMPLE_FORMAT: format::Sample = format::Sample::I16(format::sample::Type::Packed);
-const HL_SAMPLE_RATE: i32 = 22050;
+const INPUT_SAMPLE_FORMAT: format::Sample = format::Sample::F32(format::sample::Type::Packed);
 const HL_CHANNEL_LAYOUT: ChannelLayout = channel_layout::STEREO;
 
+/// swscale color space identifiers, from `libswscale/swscale.h`.
+const SWS_CS_ITU709: c_int = 1;
+const SWS_CS_ITU601: c_int = 5;
+
+/// Time base of the video in the variable frame rate mode.
+pub const VFR_TIME_BASE: Rational = Rational(1, 90_000);
+
 /// An encoder used to encode video and audio to a file.
 ///
 /// Call `Encoder::start()` to start the encoding, then encode some frames with `Encoder::encode()`.
 /// The encoder will flush and save the output file automatically upon being dropped.
 pub struct Encoder {
-    converter: PixFmtConverter,
-    resampler: resampling::Context,
     context: context::Output,
-    video_encoder: encoder::Video,
-    audio_encoder: encoder::Audio,
-    video_stream_index: usize,
-    audio_stream_index: usize,
-    audio_output_frame: frame::Audio,
-    audio_input_frame: frame::Audio,
+    video: Option<VideoOutput>,
+    audio: Option<AudioOutput>,
     packet: Packet,
     finished: bool,
+    time_base: Rational,
 
+    /// Sample rate of the audio passed to `take_audio()`.
+    audio_input_rate: u32,
+}
+
+/// The video stream of the output file.
+struct VideoOutput {
+    converter: PixFmtConverter,
+    input_resolution: (u32, u32),
+    encoder: encoder::Video,
+    filter: Option<VideoFilter>,
+    stream_index: usize,
+    stream_time_base: Rational,
+    pts: i64,
+
+    /// Time base of the encoder.
     time_base: Rational,
-    video_stream_time_base: Rational,
-    audio_stream_time_base: Rational,
 
-    video_pts: i64,
-    audio_pts: i64,
+    /// Whether the frames come with their own timestamps rather than at a constant rate.
+    vfr: bool,
+
+    /// Writes the packets of the frames taken several times.
+    duplicator: Duplicator,
+}
+
+/// Writes the video packets, extending the frames which were taken several times but encoded
+/// once.
+struct Duplicator {
+    /// How the frames taken several times are encoded, never `Auto`.
+    mode: FrameDuplication,
+
+    /// Number of times each frame encoded once should be output, by timestamp.
+    pending: HashMap<i64, usize>,
+}
+
+/// The audio stream of the output file.
+struct AudioOutput {
+    resampler: resampling::Context,
+    encoder: encoder::Audio,
+    filter: Option<AudioFilter>,
+    stream_index: usize,
+    stream_time_base: Rational,
+    output_frame: frame::Audio,
+    input_frame: frame::Audio,
+    pts: i64,
 
     /// Current position, in samples, in the audio frame.
-    audio_position: usize,
+    position: usize,
 }
 
 /// Parameters for encoding and muxing.
@@ -64,13 +109,80 @@ pub struct EncoderParameters {
     pub audio_encoder_settings: String,
     pub video_encoder_settings: String,
     pub vpx_threads: String,
+
+    /// Whether the video has a variable frame rate, with the timestamps set on the frames in
+    /// `VFR_TIME_BASE` units. `time_base` is then only the nominal frame duration.
+    pub vfr: bool,
+
+    /// How the frames taken several times are encoded.
+    pub frame_duplication: FrameDuplication,
+
+    /// Sample rate of the engine sound output.
+    pub audio_input_rate: u32,
+
+    /// Whether the audio is captured as float, in which case a float sample format is preferred
+    /// for the encoder to keep the peaks above full scale.
+    pub audio_float: bool,
+
+    /// Whether the output file has a video stream.
+    pub video: bool,
+
+    /// Whether the output file has an audio stream.
+    pub audio: bool,
+
+    /// libavfilter graph description for the video, empty if disabled.
+    pub video_filter: String,
+
+    /// libavfilter graph description for the audio, empty if disabled.
+    pub audio_filter: String,
+
+    /// Resolution of the captured frames.
+    pub input_resolution: (u32, u32),
+
+    /// Resolution of the output video.
     pub video_resolution: (u32, u32),
+
+    /// Algorithm used when the input and the output resolutions differ.
+    pub scaling_algorithm: ScalingAlgorithm,
+
+    /// How the RGB frames are converted into YUV.
+    pub color_conversion: ColorConversion,
+}
+
+/// Algorithm used for scaling the video frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalingAlgorithm {
+    Bilinear,
+    Bicubic,
+    Lanczos,
+    Area,
+}
+
+/// How a frame taken several times is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDuplication {
+    /// Every copy of the frame is encoded.
+    Encode,
+
+    /// The frame is encoded once and lasts as long as all copies together. Needs a container
+    /// supporting variable frame rate.
+    Stretch,
+
+    /// The frame is encoded once and its packet is written once per copy. Needs an intra-only
+    /// codec.
+    Repeat,
+
+    /// `Stretch` or `Repeat` if possible, `Encode` otherwise.
+    Auto,
 }
 
 /// Lazily-initialized pixel format converter.
 struct PixFmtConverter {
     inner: Option<PixFmtConverterInner>,
     output_format: format::Pixel,
+    output_resolution: (u32, u32),
+    flags: scaling::Flags,
+    color_conversion: ColorConversion,
 }
 
 /// Pixel format converter.
@@ -79,14 +191,23 @@ struct PixFmtConverterInner {
     output_frame: frame::Video,
 }
 
+/// Parses the `key=value` pairs separated by whitespace.
+fn parse_settings(settings: &str) -> impl Iterator<Item = (&str, &str)> {
+    settings.split_whitespace().filter_map(|s| {
+                                   let mut split = s.splitn(2, '=');
+
+                                   if let (Some(key), Some(value)) = (split.next(), split.next()) {
+                                       return Some((key, value));
+                                   }
+
+                                   None
+                               })
+}
+
 impl Encoder {
     pub fn start(parameters: &EncoderParameters) -> Result<Self> {
-        let video_codec = VIDEO_ENCODER.lock().unwrap();
-        ensure!(video_codec.is_some(), "video encoder was not set");
-        let video_codec = video_codec.unwrap();
-        let audio_codec = AUDIO_ENCODER.lock().unwrap();
-        ensure!(audio_codec.is_some(), "audio encoder was not set");
-        let audio_codec = audio_codec.unwrap();
+        ensure!(parameters.video || parameters.audio,
+                "cap_video and cap_audio cannot both be disabled");
 
         let mut context =
             format::output(&parameters.filename).context({
@@ -96,400 +217,698 @@ impl Encoder {
                             .flags()
                             .contains(format::flag::GLOBAL_HEADER);
 
-        let (video_encoder, video_stream_index) = {
-            let mut stream = context.add_stream(video_codec)
-                                    .context("could not add the video stream")?;
-
-            let mut encoder = stream.codec()
-                                    .encoder()
-                                    .video()
-                                    .context("could not retrieve the video encoder")?;
+        let mut video = if parameters.video {
+            Some(VideoOutput::add_stream(&mut context, parameters, global)?)
+        } else {
+            None
+        };
 
-            if global {
-                encoder.set_flags(codec::flag::GLOBAL_HEADER);
-            }
+        let mut audio = if parameters.audio {
+            Some(AudioOutput::add_stream(&mut context, parameters, global)?)
+        } else {
+            None
+        };
 
-            let (width, height) = parameters.video_resolution;
-            encoder.set_width(width);
-            encoder.set_height(height);
-            encoder.set_time_base(parameters.time_base);
-            encoder.set_bit_rate(parameters.video_bitrate);
-
-            if let Some(mut formats) = video_codec.formats() {
-                if parameters.pixel_format == format::Pixel::None {
-                    encoder.set_format(formats.next().unwrap());
-                } else {
-                    ensure!(formats.any(|x| x == parameters.pixel_format),
-                            "the selected video encoder does not support the chosen pixel format");
-                    encoder.set_format(parameters.pixel_format);
-                }
-            } else {
-                if parameters.pixel_format == format::Pixel::None {
-                    encoder.set_format(format::Pixel::YUV420P);
-                } else {
-                    encoder.set_format(parameters.pixel_format);
-                }
-            }
+        let muxer_settings = parse_settings(&parameters.muxer_settings).collect();
 
-            if encoder.format() == format::Pixel::YUV420P
-               || encoder.format() == format::Pixel::YUV444P
-            {
-                // Write the color space and range into the output file so everything knows how to
-                // display it.
-                encoder.set_colorspace(color::Space::BT470BG);
-                encoder.set_color_range(color::Range::MPEG);
-            }
+        context.write_header_with(muxer_settings)
+               .context("could not write the header")?;
 
-            let extra_settings = [("crf", &parameters.crf),
-                                  ("preset", &parameters.preset),
-                                  ("threads", &parameters.vpx_threads)];
-            let extra_settings =
-                extra_settings.iter().filter_map(|&(name, value)| {
-                                         value.split_whitespace().next().map(|v| (name, v))
-                                     });
-
-            let encoder_settings =
-                parameters.video_encoder_settings
-                          .split_whitespace()
-                          .filter_map(|s| {
-                              let mut split = s.splitn(2, '=');
-
-                              if let (Some(key), Some(value)) = (split.next(), split.next()) {
-                                  return Some((key, value));
-                              }
-
-                              None
-                          })
-                          .chain(extra_settings)
-                          .collect();
-
-            let encoder = encoder.open_as_with(video_codec, encoder_settings)
-                                 .context("could not open the video encoder")?;
-            stream.set_parameters(&encoder);
-
-            stream.set_time_base(parameters.time_base);
-            stream.set_avg_frame_rate(parameters.time_base.invert());
-
-            (encoder, stream.index())
-        };
+        if let Some(ref mut video) = video {
+            video.stream_time_base = context.stream(video.stream_index).unwrap().time_base();
+        }
 
-        let (audio_encoder, audio_stream_index) = {
-            let mut stream = context.add_stream(audio_codec)
-                                    .context("could not add the audio stream")?;
+        if let Some(ref mut audio) = audio {
+            audio.stream_time_base = context.stream(audio.stream_index).unwrap().time_base();
+        }
 
-            let mut encoder = stream.codec()
-                                    .encoder()
-                                    .audio()
-                                    .context("could not retrieve the audio encoder")?;
+        Ok(Self { context,
+                  video,
+                  audio,
+                  packet: Packet::empty(),
+                  finished: false,
+                  time_base: parameters.time_base,
+                  audio_input_rate: parameters.audio_input_rate })
+    }
 
-            if global {
-                encoder.set_flags(codec::flag::GLOBAL_HEADER);
-            }
+    /// Takes the given frame the specified number of times.
+    ///
+    /// The frame should be either of the input or of the output resolution. In the variable frame
+    /// rate mode the frame should have its timestamp set and can only be taken once. Does nothing
+    /// if the output has no video stream.
+    pub fn take(&mut self, frame: &mut frame::Video, times: usize) -> Result<()> {
+        match self.video {
+            Some(ref mut video) => video.take(frame, times, &mut self.packet, &mut self.context),
+            None => Ok(()),
+        }
+    }
 
-            encoder.set_bit_rate(parameters.audio_bitrate);
+    /// Encodes float interleaved 2-channel stereo sound, with full scale at 1.
+    ///
+    /// Does nothing if the output has no audio stream.
+    pub fn take_audio(&mut self, samples: &[(f32, f32)]) -> Result<()> {
+        match self.audio {
+            Some(ref mut audio) => audio.take(samples, &mut self.packet, &mut self.context),
+            None => Ok(()),
+        }
+    }
 
-            let rate = if let Some(mut rates) = audio_codec.rates() {
-                let mut best_rate = rates.next().unwrap();
+    fn flush(&mut self) -> Result<()> {
+        if let Some(ref mut video) = self.video {
+            video.flush(&mut self.packet, &mut self.context)?;
+        }
 
-                for r in rates {
-                    if (r - HL_SAMPLE_RATE).abs() < (best_rate - HL_SAMPLE_RATE).abs() {
-                        best_rate = r;
-                    }
-                }
+        if let Some(ref mut audio) = self.audio {
+            audio.flush(&mut self.packet, &mut self.context)?;
+        }
 
-                best_rate
-            } else {
-                HL_SAMPLE_RATE
-            };
+        Ok(())
+    }
 
-            encoder.set_rate(rate);
-            encoder.set_time_base((1, rate));
+    pub fn finish(&mut self) -> Result<()> {
+        // This should be at the beginning because we want to be able to drop the Encoder even if
+        // stuff here fails.
+        self.finished = true;
 
-            if let Some(mut formats) = audio_codec.formats() {
-                encoder.set_format(formats.next().unwrap());
-            } else {
-                encoder.set_format(HL_SAMPLE_FORMAT);
-            }
+        self.flush().context("unable to flush the encoder")?;
+        self.context
+            .write_trailer()
+            .context("could not write the trailer")?;
 
-            let channel_layout = audio_codec.channel_layouts()
-                                            .map(|cls| cls.best(HL_CHANNEL_LAYOUT.channels()))
-                                            .unwrap_or(channel_layout::STEREO);
-            encoder.set_channel_layout(channel_layout);
-            encoder.set_channels(channel_layout.channels());
+        Ok(())
+    }
 
-            let encoder_settings =
-                parameters.audio_encoder_settings
-                          .split_whitespace()
-                          .filter_map(|s| {
-                              let mut split = s.splitn(2, '=');
+    /// Returns the pixel format of the video stream, or `None` if there's no video stream.
+    #[inline]
+    pub fn format(&self) -> Option<format::Pixel> {
+        self.video.as_ref().map(|video| video.encoder.format())
+    }
 
-                              if let (Some(key), Some(value)) = (split.next(), split.next()) {
-                                  return Some((key, value));
-                              }
+    /// Returns the duration of one video frame, nominal in the variable frame rate mode.
+    #[inline]
+    pub fn time_base(&self) -> Rational {
+        self.time_base
+    }
 
-                              None
-                          })
-                          .collect();
+    /// Returns the sample rate of the audio passed to `take_audio()`.
+    #[inline]
+    pub fn audio_input_rate(&self) -> u32 {
+        self.audio_input_rate
+    }
+}
 
-            let encoder = encoder.open_as_with(audio_codec, encoder_settings)
-                                 .context("could not open the audio encoder")?;
-            stream.set_parameters(&encoder);
+impl VideoOutput {
+    /// Adds and sets up the video stream.
+    fn add_stream(context: &mut context::Output,
+                  parameters: &EncoderParameters,
+                  global: bool)
+                  -> Result<Self> {
+        let video_codec = VIDEO_ENCODER.lock().unwrap();
+        ensure!(video_codec.is_some(), "video encoder was not set");
+        let video_codec = video_codec.unwrap();
 
-            stream.set_time_base((1, rate));
+        let variable_fps = context.format()
+                                  .flags()
+                                  .contains(format::flag::VARIABLE_FPS);
+        let frame_duplication = resolve_frame_duplication(parameters,
+                                                          variable_fps,
+                                                          is_intra_only(video_codec.id()))?;
 
-            (encoder, stream.index())
-        };
+        let mut stream = context.add_stream(video_codec)
+                                .context("could not add the video stream")?;
 
-        let muxer_settings =
-            parameters.muxer_settings
-                      .split_whitespace()
-                      .filter_map(|s| {
-                          let mut split = s.splitn(2, '=');
+        let mut encoder = stream.codec()
+                                .encoder()
+                                .video()
+                                .context("could not retrieve the video encoder")?;
 
-                          if let (Some(key), Some(value)) = (split.next(), split.next()) {
-                              return Some((key, value));
-                          }
+        if global {
+            encoder.set_flags(codec::flag::GLOBAL_HEADER);
+        }
 
-                          None
-                      })
-                      .collect();
+        let time_base = if parameters.vfr {
+            VFR_TIME_BASE
+        } else {
+            parameters.time_base
+        };
 
-        context.write_header_with(muxer_settings)
-               .context("could not write the header")?;
+        let (width, height) = parameters.video_resolution;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_time_base(time_base);
+        encoder.set_bit_rate(parameters.video_bitrate);
 
-        let video_stream_time_base = context.stream(video_stream_index).unwrap().time_base();
-        let audio_stream_time_base = context.stream(audio_stream_index).unwrap().time_base();
+        if let Some(mut formats) = video_codec.formats() {
+            if parameters.pixel_format == format::Pixel::None {
+                encoder.set_format(formats.next().unwrap());
+            } else {
+                ensure!(formats.any(|x| x == parameters.pixel_format),
+                        "the selected video encoder does not support the chosen pixel format");
+                encoder.set_format(parameters.pixel_format);
+            }
+        } else {
+            if parameters.pixel_format == format::Pixel::None {
+                encoder.set_format(format::Pixel::YUV420P);
+            } else {
+                encoder.set_format(parameters.pixel_format);
+            }
+        }
 
-        let mut audio_frame_size = audio_encoder.frame_size() as usize;
-        if audio_frame_size == 0 {
-            audio_frame_size = 1024;
+        if encoder.format() == format::Pixel::YUV420P || encoder.format() == format::Pixel::YUV444P
+        {
+            // Write the color space and range into the output file so everything knows how to
+            // display it.
+            encoder.set_colorspace(ffmpeg_color_space(parameters.color_conversion.space));
+            encoder.set_color_range(ffmpeg_color_range(parameters.color_conversion.range));
+
+            // BT.709 video is also tagged with the BT.709 primaries and transfer characteristics,
+            // otherwise players may pick different ones.
+            if parameters.color_conversion.space == ColorSpace::BT709 {
+                unsafe {
+                    let context = encoder.as_mut_ptr();
+                    (*context).color_primaries = color::Primaries::BT709.into();
+                    (*context).color_trc = color::TransferCharacteristic::BT709.into();
+                }
+            }
         }
 
-        let mut audio_output_frame = frame::Audio::new(audio_encoder.format(),
-                                                       audio_frame_size,
-                                                       audio_encoder.channel_layout());
-        audio_output_frame.set_rate(audio_encoder.rate());
+        let extra_settings = [("crf", &parameters.crf),
+                              ("preset", &parameters.preset),
+                              ("threads", &parameters.vpx_threads)];
+        let extra_settings =
+            extra_settings.iter().filter_map(|&(name, value)| {
+                                     value.split_whitespace().next().map(|v| (name, v))
+                                 });
 
-        let mut audio_input_frame =
-            frame::Audio::new(HL_SAMPLE_FORMAT, audio_frame_size, HL_CHANNEL_LAYOUT);
-        audio_input_frame.set_rate(HL_SAMPLE_RATE as u32);
+        let encoder_settings = parse_settings(&parameters.video_encoder_settings)
+            .chain(extra_settings)
+            .collect();
 
-        let resampler = software::resampler(
-            (
-                audio_input_frame.format(),
-                audio_input_frame.channel_layout(),
-                audio_input_frame.rate(),
-            ),
-            (
-                audio_output_frame.format(),
-                audio_output_frame.channel_layout(),
-                audio_output_frame.rate(),
-            ),
-        ).context("could not get the resampling context")?;
+        let encoder = encoder.open_as_with(video_codec, encoder_settings)
+                             .context("could not open the video encoder")?;
+        stream.set_parameters(&encoder);
 
-        let packet = Packet::empty();
-
-        Ok(Self { converter: PixFmtConverter::new(video_encoder.format()),
-                  resampler,
-                  context,
-                  video_encoder,
-                  audio_encoder,
-                  audio_output_frame,
-                  audio_input_frame,
-                  video_stream_index,
-                  audio_stream_index,
-                  packet,
-                  finished: false,
+        stream.set_time_base(time_base);
+        stream.set_avg_frame_rate(parameters.time_base.invert());
 
-                  time_base: parameters.time_base,
-                  video_stream_time_base,
-                  audio_stream_time_base,
+        let stream_index = stream.index();
 
-                  video_pts: 0,
-                  audio_pts: 0,
+        // Set up the filter before writing the header so errors don't leave a broken file.
+        let filter = if parameters.video_filter.trim().is_empty() {
+            None
+        } else {
+            let filter = VideoFilter::new(&parameters.video_filter,
+                                          (encoder.width(), encoder.height()),
+                                          encoder.format(),
+                                          time_base)
+                .context("could not set up the video filter (cap_video_filter)")?;
+            Some(filter)
+        };
 
-                  audio_position: 0 })
+        Ok(Self { converter: PixFmtConverter::new(encoder.format(),
+                                                  parameters.video_resolution,
+                                                  parameters.scaling_algorithm.flags(),
+                                                  parameters.color_conversion),
+                  input_resolution: parameters.input_resolution,
+                  encoder,
+                  filter,
+                  stream_index,
+                  // Set after writing the header.
+                  stream_time_base: time_base,
+                  pts: 0,
+                  time_base,
+                  vfr: parameters.vfr,
+                  duplicator: Duplicator::new(frame_duplication) })
     }
 
-    fn push_frame(&mut self, frame: Option<&mut frame::Video>, times: usize) -> Result<()> {
-        let frame = frame.or(self.converter.output_frame()).unwrap();
+    fn take(&mut self,
+            frame: &mut frame::Video,
+            times: usize,
+            packet: &mut Packet,
+            context: &mut context::Output)
+            -> Result<()> {
+        // Read the timestamp before the conversion into a different frame.
+        let vfr_pts = if self.vfr {
+            ensure!(times == 1, "frames cannot be duplicated in the variable frame rate mode");
+            Some(frame.pts()
+                      .ok_or_else(|| format_err!("the frame has no timestamp"))?)
+        } else {
+            None
+        };
+
+        let resolution = (frame.width(), frame.height());
+        let output_resolution = (self.encoder.width(), self.encoder.height());
+        ensure!(resolution == self.input_resolution || resolution == output_resolution,
+                "resolution changes are not supported");
+
+        let frame = if frame.format() != self.encoder.format() || resolution != output_resolution
+        {
+            self.converter.convert(frame)?
+        } else {
+            frame
+        };
 
-        for _ in 0..times {
-            frame.set_pts(Some(self.video_pts));
-            self.video_pts += 1;
+        // Frames encoded once are output the given number of times by the duplicator.
+        let (encodes, copies) = if self.duplicator.mode == FrameDuplication::Encode {
+            (times, 1)
+        } else {
+            (1, times)
+        };
 
-            if self.video_encoder
-                   .encode(frame, &mut self.packet)
-                   .context("could not encode the video frame")?
-            {
-                self.packet
-                    .rescale_ts(self.time_base, self.video_stream_time_base);
-                self.packet.set_stream(self.video_stream_index);
+        for _ in 0..encodes {
+            frame.set_pts(Some(vfr_pts.unwrap_or(self.pts)));
 
-                self.packet
-                    .write_interleaved(&mut self.context)
-                    .context("could not write the video packet")?;
+            if copies > 1 {
+                self.duplicator.pending.insert(self.pts, copies);
+            }
+
+            self.pts += copies as i64;
+
+            if let Some(ref mut filter) = self.filter {
+                filter.push(frame)?;
+
+                while let Some(filtered_frame) = filter.pull()? {
+                    encode_video_frame(&mut self.encoder,
+                                       filtered_frame,
+                                       packet,
+                                       context,
+                                       (self.time_base, self.stream_time_base),
+                                       self.stream_index,
+                                       &mut self.duplicator)?;
+                }
+            } else {
+                encode_video_frame(&mut self.encoder,
+                                   frame,
+                                   packet,
+                                   context,
+                                   (self.time_base, self.stream_time_base),
+                                   self.stream_index,
+                                   &mut self.duplicator)?;
             }
         }
 
         Ok(())
     }
 
-    fn push_audio_frame(&mut self) -> Result<()> {
-        self.audio_output_frame.set_pts(Some(self.audio_pts));
-        self.audio_pts += self.audio_output_frame.samples() as i64;
+    fn flush(&mut self, packet: &mut Packet, context: &mut context::Output) -> Result<()> {
+        if let Some(ref mut filter) = self.filter {
+            filter.flush()?;
+
+            while let Some(filtered_frame) = filter.pull()? {
+                encode_video_frame(&mut self.encoder,
+                                   filtered_frame,
+                                   packet,
+                                   context,
+                                   (self.time_base, self.stream_time_base),
+                                   self.stream_index,
+                                   &mut self.duplicator)?;
+            }
+        }
 
-        if self.audio_encoder
-               .encode(&self.audio_output_frame, &mut self.packet)
-               .context("could not encode the audio frame")?
+        while self.encoder
+                  .flush(packet)
+                  .context("could not get the packet")?
         {
-            self.packet
-                .rescale_ts((1, self.audio_output_frame.rate() as i32),
-                            self.audio_stream_time_base);
-            self.packet.set_stream(self.audio_stream_index);
-
-            self.packet
-                .write_interleaved(&mut self.context)
-                .context("could not write the audio packet")?;
+            self.duplicator.write(packet,
+                                  context,
+                                  (self.time_base, self.stream_time_base),
+                                  self.stream_index)?;
         }
 
         Ok(())
     }
+}
 
-    /// Takes the given frame the specified number of times.
-    pub fn take(&mut self, frame: &mut frame::Video, times: usize) -> Result<()> {
-        if frame.height() != self.height() || frame.width() != self.width() {
-            panic!("the given frame's size doesn't match the encoder frame size");
+impl Duplicator {
+    #[inline]
+    fn new(mode: FrameDuplication) -> Self {
+        assert!(mode != FrameDuplication::Auto);
+
+        Self { mode,
+               pending: HashMap::new() }
+    }
+
+    /// Writes the video packet, stretching or repeating it if its frame was taken several times.
+    fn write(&mut self,
+             packet: &mut Packet,
+             context: &mut context::Output,
+             (time_base, stream_time_base): (Rational, Rational),
+             stream_index: usize)
+             -> Result<()> {
+        // Packets come in the decoding order, so look the frames up by the timestamp.
+        let times = packet.pts()
+                          .and_then(|pts| self.pending.remove(&pts))
+                          .unwrap_or(1);
+
+        if self.mode == FrameDuplication::Stretch {
+            packet.set_duration(times as i64);
+        }
+
+        // Intra-only packets don't depend on each other, so they can be repeated as is.
+        let mut copies: Vec<Packet> = if self.mode == FrameDuplication::Repeat {
+            (1..times).map(|i| {
+                          let mut copy = packet.clone();
+                          copy.set_pts(packet.pts().map(|pts| pts + i as i64));
+                          copy.set_dts(packet.dts().map(|dts| dts + i as i64));
+                          copy
+                      })
+                      .collect()
+        } else {
+            Vec::new()
+        };
+
+        for packet in iter::once(packet).chain(copies.iter_mut()) {
+            packet.rescale_ts(time_base, stream_time_base);
+            packet.set_stream(stream_index);
+
+            packet.write_interleaved(context)
+                  .context("could not write the video packet")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Picks the concrete frame duplication mode, checking that it's supported.
+fn resolve_frame_duplication(parameters: &EncoderParameters,
+                             variable_fps: bool,
+                             intra_only: bool)
+                             -> Result<FrameDuplication> {
+    // The filters may depend on the frames coming at the constant rate.
+    let filtered = !parameters.video_filter.trim().is_empty();
+
+    let mode = match parameters.frame_duplication {
+        FrameDuplication::Auto if filtered => FrameDuplication::Encode,
+        FrameDuplication::Auto if variable_fps => FrameDuplication::Stretch,
+        FrameDuplication::Auto if intra_only => FrameDuplication::Repeat,
+        FrameDuplication::Auto => FrameDuplication::Encode,
+        mode => mode,
+    };
+
+    ensure!(mode == FrameDuplication::Encode || !filtered,
+            "cap_frame_duplication must be encode or auto when cap_video_filter is set");
+    ensure!(mode != FrameDuplication::Stretch || variable_fps,
+            "the output format does not support variable frame rate (cap_frame_duplication \
+             stretch)");
+    ensure!(mode != FrameDuplication::Repeat || intra_only,
+            "the video encoder is not intra-only (cap_frame_duplication repeat)");
+
+    Ok(mode)
+}
+
+/// Returns whether every frame of the codec is encoded on its own.
+fn is_intra_only(id: codec::Id) -> bool {
+    unsafe {
+        let descriptor = ffi::avcodec_descriptor_get(id.into());
+        !descriptor.is_null()
+        && ((*descriptor).props & ffi::AV_CODEC_PROP_INTRA_ONLY as c_int) != 0
+    }
+}
+
+impl AudioOutput {
+    /// Adds and sets up the audio stream.
+    fn add_stream(context: &mut context::Output,
+                  parameters: &EncoderParameters,
+                  global: bool)
+                  -> Result<Self> {
+        let audio_codec = AUDIO_ENCODER.lock().unwrap();
+        ensure!(audio_codec.is_some(), "audio encoder was not set");
+        let audio_codec = audio_codec.unwrap();
+
+        let mut stream = context.add_stream(audio_codec)
+                                .context("could not add the audio stream")?;
+
+        let mut encoder = stream.codec()
+                                .encoder()
+                                .audio()
+                                .context("could not retrieve the audio encoder")?;
+
+        if global {
+            encoder.set_flags(codec::flag::GLOBAL_HEADER);
+        }
+
+        encoder.set_bit_rate(parameters.audio_bitrate);
+
+        // Pick the supported rate closest to the input one to avoid needless resampling.
+        let input_rate = parameters.audio_input_rate as i32;
+        let rate = if let Some(mut rates) = audio_codec.rates() {
+            let mut best_rate = rates.next().unwrap();
+
+            for r in rates {
+                if (r - input_rate).abs() < (best_rate - input_rate).abs() {
+                    best_rate = r;
+                }
+            }
+
+            best_rate
+        } else {
+            input_rate
+        };
+
+        encoder.set_rate(rate);
+        encoder.set_time_base((1, rate));
+
+        if let Some(formats) = audio_codec.formats() {
+            let formats = formats.collect::<Vec<_>>();
+            let format = if parameters.audio_float {
+                formats.iter().cloned().find(|&f| is_float_format(f))
+            } else {
+                None
+            };
+
+            encoder.set_format(format.unwrap_or(formats[0]));
+        } else if parameters.audio_float {
+            encoder.set_format(INPUT_SAMPLE_FORMAT);
+        } else {
+            encoder.set_format(HL_SAMPLE_FORMAT);
         }
 
-        let input = if frame.format() != self.format() {
-            self.converter.convert(frame)?;
+        let channel_layout = audio_codec.channel_layouts()
+                                        .map(|cls| cls.best(HL_CHANNEL_LAYOUT.channels()))
+                                        .unwrap_or(channel_layout::STEREO);
+        encoder.set_channel_layout(channel_layout);
+        encoder.set_channels(channel_layout.channels());
+
+        let encoder_settings = parse_settings(&parameters.audio_encoder_settings).collect();
+
+        let encoder = encoder.open_as_with(audio_codec, encoder_settings)
+                             .context("could not open the audio encoder")?;
+        stream.set_parameters(&encoder);
+
+        stream.set_time_base((1, rate));
+
+        let stream_index = stream.index();
+
+        let mut frame_size = encoder.frame_size() as usize;
+        if frame_size == 0 {
+            frame_size = 1024;
+        }
+
+        // Set up the filter before writing the header so errors don't leave a broken file.
+        let filter = if parameters.audio_filter.trim().is_empty() {
             None
         } else {
-            Some(frame)
+            let filter = AudioFilter::new(&parameters.audio_filter,
+                                          encoder.format(),
+                                          encoder.channel_layout(),
+                                          encoder.rate(),
+                                          frame_size as u32)
+                .context("could not set up the audio filter (cap_audio_filter)")?;
+            Some(filter)
         };
 
-        self.push_frame(input, times)?;
+        let mut output_frame =
+            frame::Audio::new(encoder.format(), frame_size, encoder.channel_layout());
+        output_frame.set_rate(encoder.rate());
+
+        let mut input_frame = frame::Audio::new(INPUT_SAMPLE_FORMAT, frame_size, HL_CHANNEL_LAYOUT);
+        input_frame.set_rate(parameters.audio_input_rate);
+
+        let resampler = software::resampler(
+            (
+                input_frame.format(),
+                input_frame.channel_layout(),
+                input_frame.rate(),
+            ),
+            (
+                output_frame.format(),
+                output_frame.channel_layout(),
+                output_frame.rate(),
+            ),
+        ).context("could not get the resampling context")?;
+
+        Ok(Self { resampler,
+                  encoder,
+                  filter,
+                  stream_index,
+                  // Set after writing the header.
+                  stream_time_base: (1, rate).into(),
+                  output_frame,
+                  input_frame,
+                  pts: 0,
+                  position: 0 })
+    }
+
+    fn push_frame(&mut self, packet: &mut Packet, context: &mut context::Output) -> Result<()> {
+        self.output_frame.set_pts(Some(self.pts));
+        self.pts += self.output_frame.samples() as i64;
+
+        if let Some(ref mut filter) = self.filter {
+            filter.push(&self.output_frame)?;
+
+            while let Some(filtered_frame) = filter.pull()? {
+                encode_audio_frame(&mut self.encoder,
+                                   filtered_frame,
+                                   packet,
+                                   context,
+                                   self.stream_time_base,
+                                   self.stream_index)?;
+            }
+        } else {
+            encode_audio_frame(&mut self.encoder,
+                               &self.output_frame,
+                               packet,
+                               context,
+                               self.stream_time_base,
+                               self.stream_index)?;
+        }
 
         Ok(())
     }
 
-    /// Encodes 16-bit signed interleaved 2-channel stereo sound.
-    pub fn take_audio(&mut self, samples: &[(i16, i16)]) -> Result<()> {
+    /// Resamples and encodes the full input frame.
+    fn encode_input_frame(&mut self,
+                          packet: &mut Packet,
+                          context: &mut context::Output)
+                          -> Result<()> {
+        self.resampler
+            .run(&self.input_frame, &mut self.output_frame)
+            .context("could not resample the sound")?;
+        self.push_frame(packet, context)?;
+
+        while let Some(_) = self.resampler.delay() {
+            self.resampler
+                .flush(&mut self.output_frame)
+                .context("could not resample the sound")?;
+            self.push_frame(packet, context)?;
+        }
+
+        self.position = 0;
+
+        Ok(())
+    }
+
+    fn take(&mut self,
+            samples: &[(f32, f32)],
+            packet: &mut Packet,
+            context: &mut context::Output)
+            -> Result<()> {
         let mut samples_pos = 0;
         while samples_pos < samples.len() {
             let available_samples = samples.len() - samples_pos;
-            let available_space = self.audio_input_frame.samples() - self.audio_position;
+            let available_space = self.input_frame.samples() - self.position;
             let to_move = cmp::min(available_samples, available_space);
 
             for i in 0..to_move {
-                self.audio_input_frame.plane_mut(0)[self.audio_position + i] =
-                    samples[samples_pos + i];
+                self.input_frame.plane_mut(0)[self.position + i] = samples[samples_pos + i];
             }
 
             samples_pos += to_move;
-            self.audio_position += to_move;
-
-            if self.audio_position == self.audio_input_frame.samples() {
-                self.resampler
-                    .run(&self.audio_input_frame, &mut self.audio_output_frame)
-                    .context("could not resample the sound")?;
-                self.push_audio_frame()?;
-
-                while let Some(_) = self.resampler.delay() {
-                    self.resampler
-                        .flush(&mut self.audio_output_frame)
-                        .context("could not resample the sound")?;
-                    self.push_audio_frame()?;
-                }
+            self.position += to_move;
 
-                self.audio_position = 0;
+            if self.position == self.input_frame.samples() {
+                self.encode_input_frame(packet, context)?;
             }
         }
 
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
-        while self.video_encoder
-                  .flush(&mut self.packet)
-                  .context("could not get the packet")?
-        {
-            self.packet
-                .rescale_ts(self.time_base, self.video_stream_time_base);
-            self.packet.set_stream(self.video_stream_index);
-
-            self.packet
-                .write_interleaved(&mut self.context)
-                .context("could not write the packet")?;
-        }
-
+    fn flush(&mut self, packet: &mut Packet, context: &mut context::Output) -> Result<()> {
         // Fill the remaining audio buffer with silence and encode it.
-        if self.audio_position > 0 {
-            let available_space = self.audio_input_frame.samples() - self.audio_position;
+        if self.position > 0 {
+            let available_space = self.input_frame.samples() - self.position;
             for i in 0..available_space {
-                self.audio_input_frame.plane_mut(0)[i] = (0i16, 0i16);
+                self.input_frame.plane_mut(0)[i] = (0f32, 0f32);
             }
 
-            self.resampler
-                .run(&self.audio_input_frame, &mut self.audio_output_frame)
-                .context("could not resample the sound")?;
-            self.push_audio_frame()?;
+            self.encode_input_frame(packet, context)?;
+        }
 
-            while let Some(_) = self.resampler.delay() {
-                self.resampler
-                    .flush(&mut self.audio_output_frame)
-                    .context("could not resample the sound")?;
-                self.push_audio_frame()?;
-            }
+        if let Some(ref mut filter) = self.filter {
+            filter.flush()?;
 
-            self.audio_position = 0;
+            while let Some(filtered_frame) = filter.pull()? {
+                encode_audio_frame(&mut self.encoder,
+                                   filtered_frame,
+                                   packet,
+                                   context,
+                                   self.stream_time_base,
+                                   self.stream_index)?;
+            }
         }
 
-        while self.audio_encoder
-                  .flush(&mut self.packet)
+        while self.encoder
+                  .flush(packet)
                   .context("could not get the packet")?
         {
-            self.packet
-                .rescale_ts((1, self.audio_output_frame.rate() as i32),
-                            self.audio_stream_time_base);
-            self.packet.set_stream(self.audio_stream_index);
-
-            self.packet
-                .write_interleaved(&mut self.context)
-                .context("could not write the packet")?;
+            packet.rescale_ts((1, self.output_frame.rate() as i32), self.stream_time_base);
+            packet.set_stream(self.stream_index);
+
+            packet.write_interleaved(context)
+                  .context("could not write the packet")?;
         }
 
         Ok(())
     }
+}
 
-    pub fn finish(&mut self) -> Result<()> {
-        // This should be at the beginning because we want to be able to drop the Encoder even if
-        // stuff here fails.
-        self.finished = true;
-
-        self.flush().context("unable to flush the encoder")?;
-        self.context
-            .write_trailer()
-            .context("could not write the trailer")?;
-
-        Ok(())
+/// Encodes the video frame and writes the resulting packet.
+fn encode_video_frame(encoder: &mut encoder::Video,
+                      frame: &frame::Video,
+                      packet: &mut Packet,
+                      context: &mut context::Output,
+                      time_bases: (Rational, Rational),
+                      stream_index: usize,
+                      duplicator: &mut Duplicator)
+                      -> Result<()> {
+    if encoder.encode(frame, packet)
+              .context("could not encode the video frame")?
+    {
+        duplicator.write(packet, context, time_bases, stream_index)?;
     }
 
-    #[inline]
-    pub fn width(&self) -> u32 {
-        self.video_encoder.width()
-    }
+    Ok(())
+}
 
-    #[inline]
-    pub fn height(&self) -> u32 {
-        self.video_encoder.height()
+/// Returns whether the sample format stores float values.
+#[inline]
+fn is_float_format(format: format::Sample) -> bool {
+    match format {
+        format::Sample::F32(_) | format::Sample::F64(_) => true,
+        _ => false,
     }
+}
 
-    #[inline]
-    pub fn format(&self) -> format::Pixel {
-        self.video_encoder.format()
+/// Encodes the audio frame and writes the resulting packet.
+fn encode_audio_frame(encoder: &mut encoder::Audio,
+                      frame: &frame::Audio,
+                      packet: &mut Packet,
+                      context: &mut context::Output,
+                      stream_time_base: Rational,
+                      stream_index: usize)
+                      -> Result<()> {
+    if encoder.encode(frame, packet)
+              .context("could not encode the audio frame")?
+    {
+        packet.rescale_ts((1, encoder.rate() as i32), stream_time_base);
+        packet.set_stream(stream_index);
+
+        packet.write_interleaved(context)
+              .context("could not write the audio packet")?;
     }
+
+    Ok(())
 }
 
 impl Drop for Encoder {
@@ -501,40 +920,92 @@ impl Drop for Encoder {
     }
 }
 
+impl ScalingAlgorithm {
+    #[inline]
+    fn flags(self) -> scaling::Flags {
+        match self {
+            ScalingAlgorithm::Bilinear => scaling::flag::BILINEAR,
+            ScalingAlgorithm::Bicubic => scaling::flag::BICUBIC,
+            ScalingAlgorithm::Lanczos => scaling::flag::LANCZOS,
+            ScalingAlgorithm::Area => scaling::flag::AREA,
+        }
+    }
+}
+
 impl PixFmtConverter {
     #[inline]
-    fn new(output_format: format::Pixel) -> Self {
+    fn new(output_format: format::Pixel,
+           output_resolution: (u32, u32),
+           flags: scaling::Flags,
+           color_conversion: ColorConversion)
+           -> Self {
         Self { inner: None,
-               output_format }
+               output_format,
+               output_resolution,
+               flags,
+               color_conversion }
     }
 
     fn convert(&mut self, frame: &frame::Video) -> Result<&mut frame::Video> {
-        if self.inner.is_none() || self.inner.as_ref().unwrap().format() != frame.format() {
-            self.inner = Some(PixFmtConverterInner::new((frame.width(), frame.height()),
+        let input_resolution = (frame.width(), frame.height());
+
+        if self.inner.is_none()
+           || self.inner.as_ref().unwrap().format() != frame.format()
+           || self.inner.as_ref().unwrap().resolution() != input_resolution
+        {
+            self.inner = Some(PixFmtConverterInner::new(input_resolution,
                                                         frame.format(),
-                                                        self.output_format)?);
+                                                        self.output_resolution,
+                                                        self.output_format,
+                                                        self.flags,
+                                                        self.color_conversion)?);
         }
 
         self.inner.as_mut().unwrap().convert(frame)
     }
-
-    #[inline]
-    fn output_frame(&mut self) -> Option<&mut frame::Video> {
-        self.inner.as_mut().map(|x| &mut x.output_frame)
-    }
 }
 
 impl PixFmtConverterInner {
     #[inline]
     fn new((width, height): (u32, u32),
            input: format::Pixel,
-           output: format::Pixel)
+           (output_width, output_height): (u32, u32),
+           output: format::Pixel,
+           flags: scaling::Flags,
+           color_conversion: ColorConversion)
            -> Result<Self> {
-        Ok(Self {
-            context: software::converter((width, height), input, output)
-                .context("could not initialize the color conversion context")?,
-            output_frame: frame::Video::new(output, width, height),
-        })
+        let mut context = scaling::Context::get(input,
+                                                width,
+                                                height,
+                                                output,
+                                                output_width,
+                                                output_height,
+                                                flags)
+            .context("could not initialize the scaling context")?;
+
+        let table = unsafe {
+            ffi::sws_getCoefficients(match color_conversion.space {
+                                         ColorSpace::BT601 => SWS_CS_ITU601,
+                                         ColorSpace::BT709 => SWS_CS_ITU709,
+                                     })
+        };
+        let full_range = (color_conversion.range == ColorRange::Full) as c_int;
+
+        // The range of RGB is ignored, so YUV input is assumed to already be in the output range.
+        // This fails for the non-YUV outputs which don't need it anyway.
+        unsafe {
+            ffi::sws_setColorspaceDetails(context.as_mut_ptr(),
+                                          table,
+                                          full_range,
+                                          table,
+                                          full_range,
+                                          0,
+                                          1 << 16,
+                                          1 << 16);
+        }
+
+        Ok(Self { context,
+                  output_frame: frame::Video::new(output, output_width, output_height) })
     }
 
     #[inline]
@@ -550,6 +1021,27 @@ impl PixFmtConverterInner {
     fn format(&self) -> format::Pixel {
         self.context.input().format
     }
+
+    #[inline]
+    fn resolution(&self) -> (u32, u32) {
+        (self.context.input().width, self.context.input().height)
+    }
+}
+
+/// Returns the ffmpeg color space matching the `ColorSpace`.
+fn ffmpeg_color_space(space: ColorSpace) -> color::Space {
+    match space {
+        ColorSpace::BT601 => color::Space::BT470BG,
+        ColorSpace::BT709 => color::Space::BT709,
+    }
+}
+
+/// Returns the ffmpeg color range matching the `ColorRange`.
+fn ffmpeg_color_range(range: ColorRange) -> color::Range {
+    match range {
+        ColorRange::Limited => color::Range::MPEG,
+        ColorRange::Full => color::Range::JPEG,
+    }
 }
 
 /// Initialize the encoding stuff.
@@ -568,6 +1060,40 @@ pub fn initialize() {
         });
 }
 
+/// Returns the name of the selected video encoder.
+pub fn video_encoder_name() -> Option<String> {
+    VIDEO_ENCODER.lock().unwrap().map(|e| e.name().to_owned())
+}
+
+/// Returns the name of the selected audio encoder.
+pub fn audio_encoder_name() -> Option<String> {
+    AUDIO_ENCODER.lock().unwrap().map(|e| e.name().to_owned())
+}
+
+/// Selects the video encoder by name.
+pub fn set_video_encoder(name: &str) -> Result<()> {
+    let encoder = encoder::find_by_name(name).ok_or_else(|| {
+                                                  format_err!("unknown encoder '{}'", name)
+                                              })?;
+    let video = encoder.video()
+                       .map_err(|_| format_err!("invalid encoder type '{}'", name))?;
+
+    *VIDEO_ENCODER.lock().unwrap() = Some(video);
+    Ok(())
+}
+
+/// Selects the audio encoder by name.
+pub fn set_audio_encoder(name: &str) -> Result<()> {
+    let encoder = encoder::find_by_name(name).ok_or_else(|| {
+                                                  format_err!("unknown encoder '{}'", name)
+                                              })?;
+    let audio = encoder.audio()
+                       .map_err(|_| format_err!("invalid encoder type '{}'", name))?;
+
+    *AUDIO_ENCODER.lock().unwrap() = Some(audio);
+    Ok(())
+}
+
 /// Outputs information about the selected video encoder.
 fn video_encoder_info(buf: &mut String) {
     if let Some(encoder) = *VIDEO_ENCODER.lock().unwrap() {
@@ -629,19 +1155,14 @@ command!(cap_video_encoder, |engine| {
 
     let encoder_name = args.nth(1).unwrap();
 
-    if let Some(encoder) = encoder::find_by_name(&encoder_name) {
-        if let Ok(video) = encoder.video() {
-            *VIDEO_ENCODER.lock().unwrap() = Some(video);
-
-            let mut buf = String::new();
-            video_encoder_info(&mut buf);
-            engine.con_print(&buf);
-        } else {
-            engine.con_print(&format!("Invalid encoder type '{}'\n", encoder_name));
-        }
-    } else {
-        engine.con_print(&format!("Unknown encoder '{}'\n", encoder_name));
+    if let Err(ref e) = set_video_encoder(&encoder_name) {
+        engine.con_print(&format_error(e));
+        return;
     }
+
+    let mut buf = String::new();
+    video_encoder_info(&mut buf);
+    engine.con_print(&buf);
 });
 
 command!(cap_audio_encoder, |engine| {
@@ -666,17 +1187,12 @@ command!(cap_audio_encoder, |engine| {
 
     let encoder_name = args.nth(1).unwrap();
 
-    if let Some(encoder) = encoder::find_by_name(&encoder_name) {
-        if let Ok(audio) = encoder.audio() {
-            *AUDIO_ENCODER.lock().unwrap() = Some(audio);
-
-            let mut buf = String::new();
-            audio_encoder_info(&mut buf);
-            engine.con_print(&buf);
-        } else {
-            engine.con_print(&format!("Invalid encoder type '{}'\n", encoder_name));
-        }
-    } else {
-        engine.con_print(&format!("Unknown encoder '{}'\n", encoder_name));
+    if let Err(ref e) = set_audio_encoder(&encoder_name) {
+        engine.con_print(&format_error(e));
+        return;
     }
+
+    let mut buf = String::new();
+    audio_encoder_info(&mut buf);
+    engine.con_print(&buf);
 });
@@ -1,5 +1,6 @@
-use failure::{bail, ensure, Error};
+use failure::{bail, ensure, Error, ResultExt};
 use ocl;
+use std::ffi::CString;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::result;
@@ -23,7 +24,16 @@ static mut MAIN_THREAD_DATA: MainThreadDataContainer =
                                                      encoder_pixel_format: None,
                                                      pro_que: MaybeUnavailable::NotChecked,
                                                      ocl_yuv_buffers:
-                                                         MaybeUnavailable::NotChecked } };
+                                                         MaybeUnavailable::NotChecked,
+                                                     ocl_scaled_image: None,
+                                                     watermark: None,
+                                                     ocl_watermark: None,
+                                                     ocl_watermarked_image: None,
+                                                     input_state:
+                                                         crate::input::InputState::new(),
+                                                     input_log: None,
+                                                     capture_time: 0f64,
+                                                     timescale: 1f64 } };
 
 /// Global variables accessible from the main game thread.
 pub struct MainThreadData {
@@ -33,10 +43,21 @@ pub struct MainThreadData {
     pub sound_capture_mode: crate::hooks::hw::SoundCaptureMode,
     pub inside_key_event: bool,
     pub inside_gl_setmode: bool,
-    pub fps_converter: Option<crate::fps_converter::FPSConverters>,
+    pub fps_converter: Option<Box<dyn crate::fps_converter::FPSConverter>>,
     pub encoder_pixel_format: Option<::ffmpeg::format::Pixel>,
     pub pro_que: MaybeUnavailable<ocl::ProQue>,
     pub ocl_yuv_buffers: MaybeUnavailable<(ocl::Buffer<u8>, ocl::Buffer<u8>, ocl::Buffer<u8>)>,
+    pub ocl_scaled_image: Option<ocl::Image<ocl::prm::Float>>,
+    pub watermark: Option<std::sync::Arc<crate::watermark::Watermark>>,
+    pub ocl_watermark: Option<ocl::Image<u8>>,
+    pub ocl_watermarked_image: Option<ocl::Image<ocl::prm::Float>>,
+    pub input_state: crate::input::InputState,
+    pub input_log: Option<crate::input::InputLog>,
+    pub capture_time: f64,
+
+    /// Current speed of the game time relative to the output time set by `cap_timescale` and
+    /// `cap_timescale_ramp`.
+    pub timescale: f64,
 }
 
 /// A Send+Sync container to allow putting `MainThreadData` into a global variable.
@@ -148,6 +169,20 @@ impl Engine {
         Ok(())
     }
 
+    /// Sets the given console variable to the given value.
+    #[inline]
+    pub fn set_variable(&mut self, cvar: &CVar, value: &str) -> Result<()> {
+        let name = CString::new(cvar.name()).context("could not convert the CVar name to \
+                                                       CString")?;
+        let value = CString::new(value).context("could not convert the value to CString")?;
+
+        unsafe {
+            hw::cvar_set(&name, &value);
+        }
+
+        Ok(())
+    }
+
     /// Returns the engine CVar wrapped by the given CVar.
     ///
     /// Takes a mutable reference to Engine to statically ensure
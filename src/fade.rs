@@ -0,0 +1,480 @@
+use failure::{Error, ResultExt};
+use ffmpeg::format;
+use ffmpeg::frame::Video as VideoFrame;
+use std::collections::VecDeque;
+use std::result;
+
+use crate::capture::copy_frame;
+use crate::color::ColorConversion;
+use crate::encode::Encoder;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Most memory the frames held back for the fade-out can take, in bytes.
+pub const MAX_FADE_OUT_MEMORY: u64 = 256 * 1024 * 1024;
+
+/// Fade-in and fade-out durations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fade {
+    /// Fade-in duration, in seconds. Zero disables the fade-in.
+    pub fade_in: f64,
+
+    /// Fade-out duration, in seconds. Zero disables the fade-out.
+    pub fade_out: f64,
+}
+
+impl Fade {
+    /// Returns whether the item starting at `time` is affected by the fade-in.
+    #[inline]
+    pub fn is_fading_in(&self, time: f64) -> bool {
+        time < self.fade_in
+    }
+
+    /// Returns the fade-in gain for the item starting at `time`.
+    ///
+    /// The first item is fully faded out.
+    #[inline]
+    pub fn fade_in_gain(&self, time: f64) -> f64 {
+        if self.fade_in <= 0f64 {
+            return 1f64;
+        }
+
+        clamp(time / self.fade_in)
+    }
+
+    /// Returns the fade-out gain for the item starting at `time`, lasting `duration`, when the
+    /// output ends at `end`.
+    ///
+    /// The last item is fully faded out.
+    #[inline]
+    pub fn fade_out_gain(&self, time: f64, duration: f64, end: f64) -> f64 {
+        if self.fade_out <= 0f64 {
+            return 1f64;
+        }
+
+        clamp((end - duration - time) / self.fade_out)
+    }
+
+    /// Returns the memory needed for holding back the fade-out frames of the given resolution and
+    /// duration, in bytes.
+    #[inline]
+    pub fn fade_out_memory(&self, frame_duration: f64, (width, height): (u32, u32)) -> u64 {
+        let frames = (self.fade_out / frame_duration).ceil();
+        if frames.is_nan() || frames >= u64::max_value() as f64 {
+            return u64::max_value();
+        }
+
+        // The captured frames take at most four bytes per pixel.
+        (frames as u64).saturating_mul(u64::from(width))
+                       .saturating_mul(u64::from(height))
+                       .saturating_mul(4)
+    }
+}
+
+/// Fades the video frames on their way to the encoder.
+///
+/// For the fade-out, the frames covering the fade-out duration are held back until `finish()`,
+/// when the end of the video is known. At most `MAX_FADE_OUT_MEMORY` bytes of frames are held
+/// back, beyond that the fade-out is cut short.
+pub struct VideoFader {
+    fade: Fade,
+
+    /// Duration of one video frame, in seconds.
+    frame_duration: f64,
+
+    /// Duration of one timestamp unit in the variable frame rate mode, in seconds. The frame
+    /// times then come from the frame timestamps.
+    vfr_time_base: Option<f64>,
+
+    /// Time of the last received frame in the variable frame rate mode, in seconds.
+    last_time: f64,
+
+    /// Black in YUV, for fading the YUV frames.
+    yuv_black: [u8; 3],
+
+    /// Number of video frames sent to the encoder so far.
+    encoded_frames: u64,
+
+    /// Held back frames along with the number of times they should be output.
+    queue: VecDeque<(VideoFrame, usize)>,
+
+    /// Total number of output frames in the queue.
+    queued_frames: u64,
+
+    /// Whether the fade-out was cut short to stay under `MAX_FADE_OUT_MEMORY`.
+    cut_short: bool,
+
+    /// Frames popped from the queue, to be reused.
+    pool: Vec<VideoFrame>,
+
+    /// Frame for fading when a frame should be output multiple times with different gains.
+    fade_frame: VideoFrame,
+
+    /// Whether the unsupported pixel format warning was already given.
+    warned: bool,
+}
+
+/// Fades the audio on its way to the encoder.
+///
+/// For the fade-out, the samples covering the fade-out duration are held back until `finish()`,
+/// when the end of the audio is known.
+pub struct AudioFader {
+    fade: Fade,
+
+    /// Sample rate of the audio.
+    rate: f64,
+
+    /// Number of samples received so far.
+    received_samples: u64,
+
+    /// Held back samples.
+    queue: VecDeque<(f32, f32)>,
+
+    /// Buffer for the samples being faded.
+    buffer: Vec<(f32, f32)>,
+}
+
+impl VideoFader {
+    pub fn new(fade: Fade,
+               frame_duration: f64,
+               vfr_time_base: Option<f64>,
+               color_conversion: ColorConversion)
+               -> Self {
+        Self { fade,
+               frame_duration,
+               vfr_time_base,
+               last_time: 0f64,
+               yuv_black: color_conversion.black(),
+               encoded_frames: 0,
+               queue: VecDeque::new(),
+               queued_frames: 0,
+               cut_short: false,
+               pool: Vec::new(),
+               fade_frame: VideoFrame::empty(),
+               warned: false }
+    }
+
+    /// Fades the frame and sends it to the encoder, or holds it back for the fade-out.
+    ///
+    /// Returns a warning message if the frame pixel format is not supported.
+    pub fn take(&mut self,
+                encoder: &mut Encoder,
+                frame: &mut VideoFrame,
+                times: usize)
+                -> Result<Option<String>> {
+        if self.fade.fade_out <= 0f64 {
+            return self.encode(encoder, frame, times, None);
+        }
+
+        if self.vfr_time_base.is_some() {
+            self.last_time = self.time(frame);
+        }
+
+        let mut queued = self.pool.pop().unwrap_or_else(VideoFrame::empty);
+        copy_frame(frame, &mut queued);
+        self.queue.push_back((queued, times));
+        self.queued_frames += times as u64;
+
+        let mut warning = None;
+
+        let frame_size = (0..frame.planes()).map(|i| frame.data(i).len() as u64)
+                                            .sum::<u64>();
+
+        // Only hold back as many frames as the fade-out needs and as fit into the memory limit.
+        while let Some(&(ref front, front_times)) = self.queue.front() {
+            // Duration of the queued frames following the front one.
+            let rest = if self.vfr_time_base.is_some() {
+                self.last_time - self.time(front)
+            } else {
+                (self.queued_frames - front_times as u64) as f64 * self.frame_duration
+            };
+
+            let too_large = self.queue.len() as u64 * frame_size > MAX_FADE_OUT_MEMORY;
+            if rest < self.fade.fade_out && !too_large {
+                break;
+            }
+
+            if too_large && !self.cut_short {
+                self.cut_short = true;
+                warning = Some(format!("Warning: the fade-out is cut short to keep the held \
+                                        back frames under {} MB.\n",
+                                       MAX_FADE_OUT_MEMORY / 1024 / 1024));
+            }
+
+            let (mut front, front_times) = self.queue.pop_front().unwrap();
+            self.queued_frames -= front_times as u64;
+
+            let result = self.encode(encoder, &mut front, front_times, None);
+            self.pool.push(front);
+            warning = warning.or(result?);
+        }
+
+        Ok(warning)
+    }
+
+    /// Fades out and sends the held back frames to the encoder.
+    ///
+    /// Returns a warning message if the frame pixel format is not supported.
+    pub fn finish(&mut self, encoder: &mut Encoder) -> Result<Option<String>> {
+        let end = if self.vfr_time_base.is_some() {
+            // The frame durations aren't known, so the fade-out ends on the last frame.
+            self.last_time
+        } else {
+            (self.encoded_frames + self.queued_frames) as f64 * self.frame_duration
+        };
+        let mut warning = None;
+
+        while let Some((mut frame, times)) = self.queue.pop_front() {
+            self.queued_frames -= times as u64;
+            warning = warning.or(self.encode(encoder, &mut frame, times, Some(end))?);
+        }
+
+        self.pool.clear();
+
+        Ok(warning)
+    }
+
+    /// Sends the frame to the encoder, fading it if needed. `end` is the end time of the video if
+    /// the fade-out should be applied.
+    fn encode(&mut self,
+              encoder: &mut Encoder,
+              frame: &mut VideoFrame,
+              times: usize,
+              end: Option<f64>)
+              -> Result<Option<String>> {
+        if !self.fade.is_fading_in(self.time(frame)) && end.is_none() {
+            encoder.take(frame, times)
+                   .context("could not encode the frame")?;
+            self.encoded_frames += times as u64;
+            return Ok(None);
+        }
+
+        let mut warning = None;
+
+        // Every output frame has a different gain, so fade and encode them one by one.
+        for _ in 0..times {
+            let time = self.time(frame);
+
+            let mut gain = self.fade.fade_in_gain(time);
+            if let Some(end) = end {
+                let duration = if self.vfr_time_base.is_some() {
+                    0f64
+                } else {
+                    self.frame_duration
+                };
+
+                gain *= self.fade.fade_out_gain(time, duration, end);
+            }
+
+            copy_frame(frame, &mut self.fade_frame);
+
+            if !fade_video_frame(&mut self.fade_frame, gain, self.yuv_black) && !self.warned {
+                self.warned = true;
+                warning = Some(format!("Warning: fading is not supported with the {:?} pixel \
+                                        format.\n",
+                                       frame.format()));
+            }
+
+            encoder.take(&mut self.fade_frame, 1)
+                   .context("could not encode the frame")?;
+            self.encoded_frames += 1;
+        }
+
+        Ok(warning)
+    }
+
+    /// Returns the time of the next output frame, in seconds.
+    #[inline]
+    fn time(&self, frame: &VideoFrame) -> f64 {
+        match self.vfr_time_base {
+            Some(time_base) => frame.pts().unwrap_or(0) as f64 * time_base,
+            None => self.encoded_frames as f64 * self.frame_duration,
+        }
+    }
+}
+
+impl AudioFader {
+    pub fn new(fade: Fade, rate: u32) -> Self {
+        Self { fade,
+               rate: f64::from(rate),
+               received_samples: 0,
+               queue: VecDeque::new(),
+               buffer: Vec::new() }
+    }
+
+    /// Fades the samples and sends them to the encoder, or holds them back for the fade-out.
+    pub fn take(&mut self, encoder: &mut Encoder, samples: &[(f32, f32)]) -> Result<()> {
+        let start = self.received_samples;
+        self.received_samples += samples.len() as u64;
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(samples);
+
+        if self.fade.is_fading_in(start as f64 / self.rate) {
+            for (i, sample) in self.buffer.iter_mut().enumerate() {
+                let time = (start + i as u64) as f64 / self.rate;
+                *sample = fade_sample(*sample, self.fade.fade_in_gain(time));
+            }
+        }
+
+        if self.fade.fade_out <= 0f64 {
+            return encoder.take_audio(&self.buffer);
+        }
+
+        self.queue.extend(self.buffer.drain(..));
+
+        // Only hold back as many samples as the fade-out needs.
+        let keep = (self.fade.fade_out * self.rate).ceil() as usize;
+        if self.queue.len() > keep {
+            let excess = self.queue.len() - keep;
+            self.buffer.extend(self.queue.drain(..excess));
+            encoder.take_audio(&self.buffer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fades out and sends the held back samples to the encoder.
+    pub fn finish(&mut self, encoder: &mut Encoder) -> Result<()> {
+        let end = self.received_samples as f64 / self.rate;
+        let start = self.received_samples - self.queue.len() as u64;
+        let sample_duration = 1f64 / self.rate;
+
+        self.buffer.clear();
+        for (i, sample) in self.queue.drain(..).enumerate() {
+            let time = (start + i as u64) as f64 / self.rate;
+            let gain = self.fade.fade_out_gain(time, sample_duration, end);
+            self.buffer.push(fade_sample(sample, gain));
+        }
+
+        encoder.take_audio(&self.buffer)
+    }
+}
+
+#[inline]
+fn clamp(x: f64) -> f64 {
+    x.max(0f64).min(1f64)
+}
+
+/// Multiplies the frame brightness by the gain, fading it to black. `yuv_black` is black in the
+/// YUV frames.
+///
+/// Returns `false` if the frame pixel format is not supported.
+pub fn fade_video_frame(frame: &mut VideoFrame, gain: f64, yuv_black: [u8; 3]) -> bool {
+    if gain >= 1f64 {
+        return true;
+    }
+
+    let gain = gain as f32;
+
+    match frame.format() {
+        format::Pixel::RGB24 | format::Pixel::RGBA => {
+            let bytes_per_pixel = if frame.format() == format::Pixel::RGB24 {
+                3
+            } else {
+                4
+            };
+            let width = frame.width() as usize;
+            let stride = frame.stride(0);
+            let data = frame.data_mut(0);
+
+            for row in data.chunks_mut(stride) {
+                for pixel in row[..width * bytes_per_pixel].chunks_mut(bytes_per_pixel) {
+                    for value in &mut pixel[..3] {
+                        *value = scale(*value, 0f32, gain);
+                    }
+                }
+            }
+
+            true
+        }
+
+        format::Pixel::YUV420P | format::Pixel::YUV444P => {
+            for (plane, &black) in yuv_black.iter().enumerate() {
+                for value in frame.data_mut(plane) {
+                    *value = scale(*value, f32::from(black), gain);
+                }
+            }
+
+            true
+        }
+
+        _ => false,
+    }
+}
+
+/// Moves the value towards `black` by the gain.
+#[inline]
+fn scale(value: u8, black: f32, gain: f32) -> u8 {
+    (black + (f32::from(value) - black) * gain).round() as u8
+}
+
+/// Multiplies the sample by the gain.
+#[inline]
+pub fn fade_sample((left, right): (f32, f32), gain: f64) -> (f32, f32) {
+    let gain = gain as f32;
+    (left * gain, right * gain)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fade_in_gain_test() {
+        let fade = Fade { fade_in: 1f64,
+                          fade_out: 0f64 };
+
+        assert_eq!(fade.fade_in_gain(0f64), 0f64);
+        assert_eq!(fade.fade_in_gain(0.25), 0.25);
+        assert_eq!(fade.fade_in_gain(2f64), 1f64);
+        assert!(fade.is_fading_in(0.5));
+        assert!(!fade.is_fading_in(1f64));
+    }
+
+    #[test]
+    fn fade_out_gain_test() {
+        let fade = Fade { fade_in: 0f64,
+                          fade_out: 1f64 };
+
+        // The last frame of a 10 second video at 10 FPS.
+        assert_eq!(fade.fade_out_gain(9.9, 0.1, 10f64), 0f64);
+        assert!((fade.fade_out_gain(9.4, 0.1, 10f64) - 0.5).abs() < 1e-9);
+        assert_eq!(fade.fade_out_gain(5f64, 0.1, 10f64), 1f64);
+    }
+
+    #[test]
+    fn fade_out_memory_test() {
+        let fade = Fade { fade_in: 0f64,
+                          fade_out: 1f64 };
+
+        assert_eq!(fade.fade_out_memory(0.1, (100, 10)), 10 * 100 * 10 * 4);
+        assert_eq!(fade.fade_out_memory(0.3, (1, 1)), 4 * 4);
+        assert_eq!(fade.fade_out_memory(1e-18, (u32::max_value(), u32::max_value())),
+                   u64::max_value());
+        assert_eq!(fade.fade_out_memory(1e-300, (1, 1)), u64::max_value());
+    }
+
+    #[test]
+    fn disabled_test() {
+        let fade = Fade { fade_in: 0f64,
+                          fade_out: 0f64 };
+
+        assert_eq!(fade.fade_in_gain(0f64), 1f64);
+        assert_eq!(fade.fade_out_gain(9.9, 0.1, 10f64), 1f64);
+        assert!(!fade.is_fading_in(0f64));
+    }
+
+    #[test]
+    fn fade_sample_test() {
+        assert_eq!(fade_sample((0.5, -0.5), 0.5), (0.25, -0.25));
+        assert_eq!(fade_sample((0.5, -0.5), 0f64), (0f32, 0f32));
+    }
+
+    #[test]
+    fn scale_test() {
+        assert_eq!(scale(235, 16f32, 0f32), 16);
+        assert_eq!(scale(200, 128f32, 0.5), 164);
+        assert_eq!(scale(255, 0f32, 1f32), 255);
+    }
+}
@@ -0,0 +1,206 @@
+use failure::{ensure, format_err, Error, ResultExt};
+use ffmpeg::util::frame;
+use ffmpeg::{self, filter, format, ChannelLayout, Rational};
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+/// A libavfilter graph processing video frames.
+pub struct VideoFilter {
+    graph: filter::Graph,
+    output_frame: frame::Video,
+    resolution: (u32, u32),
+}
+
+/// A libavfilter graph processing audio frames.
+pub struct AudioFilter {
+    graph: filter::Graph,
+    output_frame: frame::Audio,
+}
+
+impl VideoFilter {
+    /// Builds the filter graph from the given description.
+    ///
+    /// The input and output frames are of the given resolution and pixel format. Filters which
+    /// change the resolution are not supported.
+    pub fn new(spec: &str,
+               (width, height): (u32, u32),
+               format: format::Pixel,
+               time_base: Rational)
+               -> Result<Self> {
+        let format_name = format.descriptor()
+                                .ok_or_else(|| format_err!("invalid pixel format"))?
+                                .name();
+
+        let args = format!("video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect=1/1",
+                           width,
+                           height,
+                           format_name,
+                           time_base.numerator(),
+                           time_base.denominator());
+
+        let mut graph = filter::Graph::new();
+        add_filter(&mut graph, "buffer", "in", &args)?;
+        add_filter(&mut graph, "buffersink", "out", "")?;
+        graph.get("out").unwrap().set_pixel_format(format);
+
+        parse_and_validate(&mut graph, spec)?;
+
+        Ok(Self { graph,
+                  output_frame: frame::Video::empty(),
+                  resolution: (width, height) })
+    }
+
+    /// Sends the frame into the filter graph.
+    #[inline]
+    pub fn push(&mut self, frame: &frame::Video) -> Result<()> {
+        self.graph
+            .get("in")
+            .unwrap()
+            .source()
+            .add(frame)
+            .context("could not send the frame into the video filter")?;
+        Ok(())
+    }
+
+    /// Signals the end of the input.
+    #[inline]
+    pub fn flush(&mut self) -> Result<()> {
+        self.graph
+            .get("in")
+            .unwrap()
+            .source()
+            .flush()
+            .context("could not flush the video filter")?;
+        Ok(())
+    }
+
+    /// Returns the next filtered frame, if there is one.
+    pub fn pull(&mut self) -> Result<Option<&mut frame::Video>> {
+        let result = self.graph
+                         .get("out")
+                         .unwrap()
+                         .sink()
+                         .frame(&mut self.output_frame);
+
+        if !check_sink_result(result).context("could not get the frame from the video filter")? {
+            return Ok(None);
+        }
+
+        ensure!((self.output_frame.width(), self.output_frame.height()) == self.resolution,
+                "the video filter must not change the resolution (use cap_output_resolution)");
+
+        Ok(Some(&mut self.output_frame))
+    }
+}
+
+impl AudioFilter {
+    /// Builds the filter graph from the given description.
+    ///
+    /// The input and output frames have the given sample format, channel layout and rate. Output
+    /// frames contain `frame_size` samples, except for the last one.
+    pub fn new(spec: &str,
+               format: format::Sample,
+               channel_layout: ChannelLayout,
+               rate: u32,
+               frame_size: u32)
+               -> Result<Self> {
+        let args = format!("time_base=1/{}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+                           rate,
+                           rate,
+                           format.name(),
+                           channel_layout.bits());
+
+        let mut graph = filter::Graph::new();
+        add_filter(&mut graph, "abuffer", "in", &args)?;
+        add_filter(&mut graph, "abuffersink", "out", "")?;
+
+        {
+            let mut out = graph.get("out").unwrap();
+            out.set_sample_format(format);
+            out.set_channel_layout(channel_layout);
+            out.set_sample_rate(rate);
+        }
+
+        parse_and_validate(&mut graph, spec)?;
+
+        graph.get("out").unwrap().sink().set_frame_size(frame_size);
+
+        Ok(Self { graph,
+                  output_frame: frame::Audio::empty() })
+    }
+
+    /// Sends the frame into the filter graph.
+    #[inline]
+    pub fn push(&mut self, frame: &frame::Audio) -> Result<()> {
+        self.graph
+            .get("in")
+            .unwrap()
+            .source()
+            .add(frame)
+            .context("could not send the frame into the audio filter")?;
+        Ok(())
+    }
+
+    /// Signals the end of the input.
+    #[inline]
+    pub fn flush(&mut self) -> Result<()> {
+        self.graph
+            .get("in")
+            .unwrap()
+            .source()
+            .flush()
+            .context("could not flush the audio filter")?;
+        Ok(())
+    }
+
+    /// Returns the next filtered frame, if there is one.
+    pub fn pull(&mut self) -> Result<Option<&mut frame::Audio>> {
+        let result = self.graph
+                         .get("out")
+                         .unwrap()
+                         .sink()
+                         .frame(&mut self.output_frame);
+
+        if check_sink_result(result).context("could not get the frame from the audio filter")? {
+            Ok(Some(&mut self.output_frame))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Adds a filter with the given name and arguments to the graph.
+fn add_filter(graph: &mut filter::Graph, filter_name: &str, name: &str, args: &str) -> Result<()> {
+    let filter = filter::find(filter_name).ok_or_else(|| {
+                                               format_err!("could not find the {} filter",
+                                                           filter_name)
+                                           })?;
+
+    graph.add(&filter, name, args)
+         .with_context(|_| format!("could not add the {} filter", filter_name))?;
+
+    Ok(())
+}
+
+/// Connects the user filters between the "in" and "out" filters and validates the graph.
+fn parse_and_validate(graph: &mut filter::Graph, spec: &str) -> Result<()> {
+    graph.output("in", 0)
+         .and_then(|p| p.input("out", 0))
+         .and_then(|p| p.parse(spec))
+         .with_context(|_| format!("could not parse the filter graph `{}`", spec))?;
+
+    graph.validate().context("could not configure the filter graph")?;
+
+    Ok(())
+}
+
+/// Returns whether a frame was received, or an error if the filter graph failed.
+fn check_sink_result(result: result::Result<(), ffmpeg::Error>) -> Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(ffmpeg::Error::Eof) => Ok(false),
+        Err(ffmpeg::Error::Other { errno }) if errno == libc::EAGAIN => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
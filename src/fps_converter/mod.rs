@@ -1,19 +1,56 @@
+use ffmpeg::format;
+
+use crate::capture::{self, VideoBuffer};
 use crate::engine::Engine;
-use crate::hooks::hw;
+use crate::hooks::hw::{self, FrameCapture};
 
-mod sampling;
+pub mod registry;
+pub mod sampling;
+pub mod shutter;
 mod simple;
+pub mod timelapse;
+pub mod timescale;
+pub mod vfr;
+mod worker_pool;
+pub use self::registry::FPSConverterInfo;
 pub use self::sampling::SamplingConverter;
+pub use self::shutter::Shutter;
 pub use self::simple::SimpleConverter;
+pub use self::timelapse::TimelapseConverter;
+pub use self::timescale::TimescaleConverter;
+pub use self::vfr::VfrConverter;
+
+/// Captures the current frame for the FPS converter.
+pub type CaptureFn = fn(&mut Engine) -> FrameCapture;
 
 pub trait FPSConverter {
     /// Updates the FPS converter state. The converter may capture one frame using the provided
-    /// closure.
-    fn time_passed<F>(&mut self, engine: &mut Engine, frametime: f64, capture: F)
-        where F: FnOnce(&mut Engine) -> hw::FrameCapture;
+    /// function.
+    fn time_passed(&mut self, engine: &mut Engine, frametime: f64, capture: CaptureFn);
+
+    /// This should be called before an engine restart.
+    #[inline]
+    fn backup_and_free_ocl_data(&mut self, _engine: &mut Engine) {}
 }
 
-pub enum FPSConverters {
-    Simple(SimpleConverter),
-    Sampling(SamplingConverter),
+/// Reads the captured frame into a video buffer at the capture region size.
+fn read_frame(engine: &mut Engine, frame_capture: FrameCapture) -> VideoBuffer {
+    let region = capture::get_capture_parameters(engine).capture_region;
+    let mut buf = capture::get_buffer(engine, region.size());
+
+    match frame_capture {
+        FrameCapture::OpenGL(read_pixels) => {
+            buf.set_format(format::Pixel::RGB24);
+            read_pixels(engine.marker().1, region, buf.as_mut_slice());
+        }
+
+        FrameCapture::OpenCL(ocl_gl_texture) => {
+            hw::read_ocl_image_into_buf(engine,
+                                        ocl_gl_texture.as_ref(),
+                                        ocl_gl_texture.region(),
+                                        &mut buf);
+        }
+    }
+
+    buf
 }
@@ -0,0 +1,191 @@
+use failure::{bail, Error};
+use lazy_static::lazy_static;
+use std::result;
+
+use super::sampling::{cap_sampling_curve, cap_sampling_exposure, cap_sampling_linear,
+                      cap_sampling_phase, cap_sampling_sps};
+use super::timelapse::{cap_timelapse, cap_timelapse_audio, cap_timelapse_blend};
+use super::timescale::{cap_timescale, cap_timescale_audio, cap_timescale_ramp};
+use super::vfr::{cap_vfr, cap_vfr_timecodes};
+use super::*;
+use crate::capture::CaptureParameters;
+use crate::cvar::CVar;
+use crate::utils::format_error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// An FPS converter which can be selected with `cap_fps_converter`.
+pub struct FPSConverterInfo {
+    /// Name used in `cap_fps_converter`.
+    pub name: &'static str,
+
+    /// What the converter does, shown by `cap_fps_converters`.
+    pub description: &'static str,
+
+    /// Console variables which configure this converter.
+    pub cvars: &'static [&'static CVar],
+
+    /// Whether the converter gives every frame its own timestamp instead of outputting constant
+    /// FPS video.
+    pub variable_frame_rate: bool,
+
+    /// Creates the converter for the given video time base and output resolution.
+    pub create: fn(&mut Engine, f64, (u32, u32)) -> Box<dyn FPSConverter>,
+}
+
+/// An FPS converter which changes the time passed to the selected converter. It's enabled by its
+/// own settings rather than selected.
+pub struct FPSConverterWrapperInfo {
+    /// Name shown by `cap_fps_converters`.
+    pub name: &'static str,
+
+    /// What the wrapper does, shown by `cap_fps_converters`.
+    pub description: &'static str,
+
+    /// Console variables which configure this wrapper.
+    pub cvars: &'static [&'static CVar],
+
+    /// Returns whether the wrapper is enabled with the given capture parameters.
+    pub enabled: fn(&CaptureParameters) -> bool,
+
+    /// Wraps the converter according to the given capture parameters.
+    pub wrap: fn(Box<dyn FPSConverter>, &CaptureParameters) -> Box<dyn FPSConverter>,
+}
+
+/// All FPS converters, in the order they are listed by `cap_fps_converters`.
+pub static FPS_CONVERTERS: &[FPSConverterInfo] =
+    &[FPSConverterInfo { name: "simple",
+                         description: "Drops and duplicates the game frames to get constant FPS \
+                                       video.",
+                         cvars: &[],
+                         variable_frame_rate: false,
+                         create: |_, time_base, _| Box::new(SimpleConverter::new(time_base)) },
+      FPSConverterInfo { name: "sampling",
+                         description: "Blends the game frames together, giving motion blur. \
+                                       Use it with cap_sampling_sps set to a multiple of \
+                                       cap_fps.",
+                         cvars: &[&cap_sampling_sps,
+                                  &cap_sampling_exposure,
+                                  &cap_sampling_curve,
+                                  &cap_sampling_phase,
+                                  &cap_sampling_linear],
+                         variable_frame_rate: false,
+                         create: |engine, time_base, video_resolution| {
+                             Box::new(SamplingConverter::new(engine, time_base, video_resolution))
+                         } },
+      FPSConverterInfo { name: "vfr",
+                         description: "Captures every game frame with its own timestamp. \
+                                       cap_fps sets the nominal frame rate.",
+                         cvars: &[&cap_vfr, &cap_vfr_timecodes],
+                         variable_frame_rate: true,
+                         create: |_, _, _| Box::new(VfrConverter::new()) }];
+
+/// All FPS converter wrappers, in the order they are applied.
+pub static FPS_CONVERTER_WRAPPERS: &[FPSConverterWrapperInfo] =
+    &[FPSConverterWrapperInfo { name: "timescale",
+                                description: "Slows down or speeds up the game while keeping \
+                                              the output frame rate.",
+                                cvars: &[&cap_timescale,
+                                         &cap_timescale_ramp,
+                                         &cap_timescale_audio],
+                                enabled: |params| params.timescale.is_some(),
+                                wrap: |inner, _| Box::new(TimescaleConverter::new(inner)) },
+      FPSConverterWrapperInfo { name: "timelapse",
+                                description: "Advances several seconds of game time per second \
+                                              of output.",
+                                cvars: &[&cap_timelapse,
+                                         &cap_timelapse_blend,
+                                         &cap_timelapse_audio],
+                                enabled: |params| params.timelapse.is_some(),
+                                wrap: |inner, params| {
+                                    Box::new(TimelapseConverter::new(inner,
+                                                                     params.timelapse.unwrap()))
+                                } }];
+
+/// Returns the converter with the given name.
+///
+/// `auto` picks `vfr` if `vfr` is set, `sampling` if `sampling` is set and `simple` otherwise.
+pub fn select(name: &str, vfr: bool, sampling: bool) -> Result<&'static FPSConverterInfo> {
+    let name = match name.trim() {
+        "auto" if vfr => "vfr",
+        "auto" if sampling => "sampling",
+        "auto" => "simple",
+        name => name,
+    };
+
+    match FPS_CONVERTERS.iter().find(|info| info.name == name) {
+        Some(info) => Ok(info),
+        None => bail!("unknown FPS converter {}; see cap_fps_converters for the list", name),
+    }
+}
+
+/// Wraps the converter into every enabled wrapper.
+pub fn wrap(mut converter: Box<dyn FPSConverter>,
+            params: &CaptureParameters)
+            -> Box<dyn FPSConverter> {
+    for info in FPS_CONVERTER_WRAPPERS {
+        if (info.enabled)(params) {
+            converter = (info.wrap)(converter, params);
+        }
+    }
+
+    converter
+}
+
+command!(cap_fps_converters, |mut engine| {
+    let mut buf = String::from("FPS converters:\n");
+    buf.push_str("    auto\n");
+    buf.push_str("     - Picks vfr with cap_vfr 1, sampling with cap_sampling_sps or with \
+                  cap_timelapse_blend 1 together with cap_timelapse, simple otherwise.\n");
+
+    for info in FPS_CONVERTERS {
+        push_info(&mut buf, info.name, info.description, info.cvars);
+    }
+
+    buf.push_str("\nWrappers, enabled by their settings on top of the selected converter:\n");
+
+    for info in FPS_CONVERTER_WRAPPERS {
+        push_info(&mut buf, info.name, info.description, info.cvars);
+    }
+
+    match cap_fps_converter.to_string(&mut engine) {
+        Ok(name) => buf.push_str(&format!("Selected: {}\n", name)),
+        Err(ref e) => buf.push_str(&format_error(e)),
+    }
+
+    buf.push_str("\nUsage:\n");
+    buf.push_str("    cap_fps_converter <name>\n");
+    buf.push_str("     - Set the FPS converter used by cap_start.\n");
+
+    engine.con_print(&buf);
+});
+
+cvar!(cap_fps_converter, "auto");
+
+/// Adds the converter or wrapper description to the `cap_fps_converters` output.
+fn push_info(buf: &mut String, name: &str, description: &str, cvars: &[&CVar]) {
+    buf.push_str(&format!("    {}\n", name));
+    buf.push_str(&format!("     - {}\n", description));
+
+    if !cvars.is_empty() {
+        let names = cvars.iter().map(|cvar| cvar.name()).collect::<Vec<_>>();
+        buf.push_str(&format!("     - Settings: {}.\n", names.join(", ")));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn select_test() {
+        assert_eq!(select("auto", false, false).unwrap().name, "simple");
+        assert_eq!(select("auto", false, true).unwrap().name, "sampling");
+        assert_eq!(select("auto", true, false).unwrap().name, "vfr");
+        assert_eq!(select(" sampling ", true, false).unwrap().name, "sampling");
+        assert!(select("vfr", false, false).unwrap().variable_frame_rate);
+
+        assert!(select("", false, false).is_err());
+        assert!(select("blend", false, false).is_err());
+    }
+}
@@ -1,20 +1,40 @@
 use ffmpeg::format;
 use ocl::{self, OclPrm};
+use std::mem;
 
 use super::*;
 use crate::capture;
+use crate::engine::MainThreadMarker;
 use crate::hooks::hw::FrameCapture;
 use crate::utils::MaybeUnavailable;
 
+use super::worker_pool::WorkerPool;
+
 /// Resampling FPS converter which averages input frames for smooth motion.
+///
+/// Every output frame is accumulated in its own buffer. With exposures above 1 the exposure
+/// windows of the neighbouring output frames overlap, so one input frame can be added into several
+/// buffers, which are used as a ring.
 pub struct SamplingConverter {
-    /// Difference, in video frames, between how much time passed in-game and how much video we
-    /// output.
+    /// Difference, in video frames, between how much time passed in-game and the start of the
+    /// oldest output frame which was not output yet.
     remainder: f64,
 
     /// The target time_base.
     time_base: f64,
 
+    /// Computes the input frame weights.
+    shutter: Shutter,
+
+    /// Whether any frames were received yet.
+    started: bool,
+
+    /// Index of the accumulation buffer of the oldest output frame which was not output yet.
+    ring_head: usize,
+
+    /// Whether anything was added into each of the accumulation buffers.
+    ring_used: Vec<bool>,
+
     /// Data with a destructor, wrapped so `SamplingConverter` can be put in a static variable.
     private: SamplingConverterPrivate,
 }
@@ -26,29 +46,56 @@ struct SamplingConverterPrivate {
     /// This is Some(None) if OpenCL is unavailable and None during an engine restart.
     ocl_runtime_data: MaybeUnavailable<OclRuntimeData>,
 
-    /// Pixels from the buffer are stored here when the engine restarts.
-    ocl_backup_buffer: Option<Vec<ocl::prm::Float>>,
+    /// Pixels from the accumulation buffers are stored here when the engine restarts.
+    ocl_backup_buffers: Option<Vec<Vec<ocl::prm::Float>>>,
 
     /// The video resolution.
     video_resolution: (u32, u32),
 
-    /// The OpenGL sampling buffer.
-    gl_sampling_buffer: Vec<f32>,
+    /// Number of accumulation buffers.
+    accumulator_count: usize,
+
+    /// Conversion between the pixel values and the accumulated values.
+    transfer: Transfer,
+
+    /// Threads doing the OpenGL sampling, started on the first OpenGL frame.
+    ///
+    /// This is declared before the buffers so it's dropped, waiting for the jobs, first.
+    pool: Option<WorkerPool>,
+
+    /// The OpenGL accumulation buffers.
+    gl_sampling_buffers: Vec<Vec<f32>>,
+
+    /// The OpenGL read buffers. The pixels are read into one of them while the workers can still
+    /// be adding the other one.
+    gl_read_buffers: [Vec<u8>; 2],
 
-    /// The OpenGL read buffer.
-    gl_read_buffer: Vec<u8>,
+    /// Index of the read buffer holding the last read pixels.
+    gl_read_index: usize,
+}
+
+/// Conversion between the sRGB-encoded pixel values and the values accumulated by the sampling
+/// converter.
+///
+/// The accumulated values range from 0 to 255 and are either the pixel values themselves or their
+/// linear light intensities.
+#[derive(Clone, Copy)]
+pub(super) struct Transfer {
+    /// Whether the frames are blended in linear light.
+    linear: bool,
+
+    /// The accumulated value for every pixel value.
+    decode_table: [f32; 256],
 }
 
 /// Data used at runtime by OpenCL sampling.
 struct OclRuntimeData {
-    /// Buffer images.
-    ocl_buffers: [ocl::Image<ocl::prm::Float>; 2],
-
-    /// Output image.
-    ocl_output_image: ocl::Image<ocl::prm::Float>,
+    /// Accumulation images.
+    ocl_accumulators: Vec<ocl::Image<ocl::prm::Float>>,
 
-    /// Index of the last written to OpenCL buffer.
-    ocl_current_buffer_index: usize,
+    /// Image the kernels write into; swapped with the accumulation image afterwards since an image
+    /// can't be read from and written to by the same kernel.
+    ocl_scratch_image: ocl::Image<ocl::prm::Float>,
 }
 
 impl SamplingConverter {
@@ -56,170 +103,319 @@ impl SamplingConverter {
     pub fn new(engine: &mut Engine, time_base: f64, video_resolution: (u32, u32)) -> Self {
         assert!(time_base > 0f64);
 
+        let (shutter, linear) = {
+            let parameters = capture::get_capture_parameters(engine);
+
+            // The timelapse blend averages all of the skipped frames.
+            let shutter = if parameters.timelapse_blend {
+                Shutter::full_frame()
+            } else {
+                parameters.sampling_shutter.clone()
+            };
+
+            (shutter, parameters.sampling_linear)
+        };
+
+        // An output frame is pending until both its duration and its exposure window pass, and
+        // it receives input frames from the start of its exposure window.
+        let (window_start, window_end) = shutter.window();
+        let accumulator_count = (window_end.max(1f64) - window_start).ceil() as usize + 1;
+
         Self { remainder: 0f64,
                time_base,
-               private: SamplingConverterPrivate::new(engine, video_resolution) }
+               shutter,
+               started: false,
+               ring_head: 0,
+               ring_used: vec![false; accumulator_count],
+               private: SamplingConverterPrivate::new(engine,
+                                                      video_resolution,
+                                                      accumulator_count,
+                                                      Transfer::new(linear)) }
     }
 
-    /// This should be called before an engine restart.
+    /// Returns the accumulation buffer index of the pending output frame with the given index,
+    /// counting from the oldest one.
     #[inline]
-    pub fn backup_and_free_ocl_data(&mut self, engine: &mut Engine) {
-        self.private.backup_and_free_ocl_data(engine);
+    fn slot(&self, index: usize) -> usize {
+        assert!(index < self.ring_used.len());
+
+        (self.ring_head + index) % self.ring_used.len()
     }
-}
 
-impl FPSConverter for SamplingConverter {
-    fn time_passed<F>(&mut self, engine: &mut Engine, frametime: f64, capture: F)
-        where F: FnOnce(&mut Engine) -> FrameCapture
-    {
-        assert!(frametime >= 0.0f64);
+    /// Reads the OpenGL pixels if they weren't read yet.
+    fn read_gl_pixels(&mut self,
+                      engine: &mut Engine,
+                      read_pixels: fn(MainThreadMarker<'_>, capture::Region, &mut [u8]),
+                      pixels_read: &mut bool) {
+        if *pixels_read {
+            return;
+        }
 
-        let frame_capture = capture(engine);
+        *pixels_read = true;
 
-        let old_remainder = self.remainder;
-        self.remainder += frametime / self.time_base;
+        let private: &mut SamplingConverterPrivate = &mut self.private;
+        let pool = private.pool.get_or_insert_with(WorkerPool::new);
 
-        let exposure = capture::get_capture_parameters(engine).sampling_exposure;
+        let (w, h) = private.video_resolution;
+        let len = (w * h * 3) as usize;
+        if private.gl_read_buffers[0].len() != len {
+            pool.wait();
 
-        if self.remainder <= (1f64 - exposure) {
-            // Do nothing.
-        } else if self.remainder < 1f64 {
-            let weight = (self.remainder - old_remainder.max(1f64 - exposure)) * (1f64 / exposure);
+            for buffer in &mut private.gl_read_buffers {
+                buffer.resize(len, 0);
+            }
+            for buffer in &mut private.gl_sampling_buffers {
+                buffer.resize(len, 0f32);
+            }
+        }
 
-            match frame_capture {
-                FrameCapture::OpenGL(read_pixels) => {
-                    let (w, h) = self.private.video_resolution;
-                    self.private.gl_read_buffer.resize((w * h * 3) as usize, 0);
-                    self.private
-                        .gl_sampling_buffer
-                        .resize((w * h * 3) as usize, 0f32);
+        // The running job can only be reading the other read buffer.
+        private.gl_read_index = 1 - private.gl_read_index;
 
-                    read_pixels(engine.marker().1, (w, h), &mut self.private.gl_read_buffer);
+        let region = capture::get_capture_parameters(engine).capture_region;
+        read_pixels(engine.marker().1,
+                    region,
+                    &mut private.gl_read_buffers[private.gl_read_index]);
+    }
 
-                    let private: &mut SamplingConverterPrivate = &mut self.private;
-                    weighted_image_add(&mut private.gl_sampling_buffer,
-                                       &private.gl_read_buffer,
-                                       weight as f32);
+    /// Adds the input frame into the accumulation buffer.
+    fn add(&mut self,
+           engine: &mut Engine,
+           frame_capture: &FrameCapture,
+           pixels_read: &mut bool,
+           slot: usize,
+           weight: f64) {
+        match *frame_capture {
+            FrameCapture::OpenGL(read_pixels) => {
+                self.read_gl_pixels(engine, read_pixels, pixels_read);
+
+                let private: &mut SamplingConverterPrivate = &mut self.private;
+                let pool = private.pool.as_mut().unwrap();
+
+                // The buffers stay untouched until the next pool operation, which waits for this
+                // one, and the pool is dropped before them.
+                unsafe {
+                    pool.weighted_image_add(&mut private.gl_sampling_buffers[slot],
+                                            &private.gl_read_buffers[private.gl_read_index],
+                                            weight as f32,
+                                            private.transfer);
                 }
+            }
 
-                FrameCapture::OpenCL(ocl_gl_texture) => {
-                    let ocl_data = self.private.get_ocl_data(engine).unwrap();
+            FrameCapture::OpenCL(ref ocl_gl_texture) => {
+                let linear = self.private.transfer.linear;
+                let ocl_data = self.private.get_ocl_data(engine).unwrap();
 
-                    ocl_weighted_image_add(engine,
-                                           ocl_gl_texture.as_ref(),
-                                           ocl_data.src_buffer(),
-                                           ocl_data.dst_buffer(),
-                                           weight as f32);
+                ocl_weighted_image_add(engine,
+                                       ocl_gl_texture.as_ref(),
+                                       ocl_gl_texture.region().origin(),
+                                       &ocl_data.ocl_accumulators[slot],
+                                       &ocl_data.ocl_scratch_image,
+                                       weight as f32,
+                                       linear);
 
-                    ocl_data.switch_buffer_index();
+                ocl_data.swap_scratch_image(slot);
+            }
+        }
+    }
+
+    /// Adds the input frame into the accumulation buffer, outputs the result and clears the
+    /// buffer.
+    fn output(&mut self,
+              engine: &mut Engine,
+              frame_capture: &FrameCapture,
+              pixels_read: &mut bool,
+              slot: usize,
+              weight: f64) {
+        let (w, h) = self.private.video_resolution;
+
+        match *frame_capture {
+            FrameCapture::OpenGL(read_pixels) => {
+                self.read_gl_pixels(engine, read_pixels, pixels_read);
+
+                let mut buf = capture::get_buffer(engine, (w, h));
+                buf.set_format(format::Pixel::RGB24);
+
+                let private: &mut SamplingConverterPrivate = &mut self.private;
+                let pool = private.pool.as_mut().unwrap();
+                pool.weighted_image_add_to(&private.gl_sampling_buffers[slot],
+                                           &private.gl_read_buffers[private.gl_read_index],
+                                           buf.as_mut_slice(),
+                                           weight as f32,
+                                           private.transfer);
+
+                // Clear the buffer while the frame is being sent off.
+                unsafe {
+                    pool.fill_with_black(&mut private.gl_sampling_buffers[slot]);
                 }
+
+                capture::capture(engine.marker().1, buf, 1);
             }
+
+            FrameCapture::OpenCL(ref ocl_gl_texture) => {
+                let linear = self.private.transfer.linear;
+                let ocl_data = self.private.get_ocl_data(engine).unwrap();
+
+                ocl_weighted_image_add(engine,
+                                       ocl_gl_texture.as_ref(),
+                                       ocl_gl_texture.region().origin(),
+                                       &ocl_data.ocl_accumulators[slot],
+                                       &ocl_data.ocl_scratch_image,
+                                       weight as f32,
+                                       linear);
+
+                // The accumulation image is cleared right after, so it can hold the sRGB-encoded
+                // frame meanwhile.
+                let result = if linear {
+                    ocl_linear_to_srgb(engine,
+                                       &ocl_data.ocl_scratch_image,
+                                       &ocl_data.ocl_accumulators[slot]);
+                    &ocl_data.ocl_accumulators[slot]
+                } else {
+                    &ocl_data.ocl_scratch_image
+                };
+
+                // Output the frame.
+                let mut buf = capture::get_buffer(engine, (w, h));
+                hw::read_ocl_image_into_buf(engine,
+                                            result,
+                                            capture::Region::whole((w, h)),
+                                            &mut buf);
+                capture::capture(engine.marker().1, buf, 1);
+
+                ocl_fill_with_black(engine, &ocl_data.ocl_accumulators[slot]);
+            }
+        }
+    }
+
+    /// Outputs the input frame as is the given number of times.
+    fn output_input_frame(&mut self,
+                          engine: &mut Engine,
+                          frame_capture: &FrameCapture,
+                          pixels_read: &mut bool,
+                          times: usize) {
+        let (w, h) = self.private.video_resolution;
+
+        match *frame_capture {
+            FrameCapture::OpenGL(read_pixels) => {
+                self.read_gl_pixels(engine, read_pixels, pixels_read);
+
+                let mut buf = capture::get_buffer(engine, (w, h));
+                buf.set_format(format::Pixel::RGB24);
+                buf.as_mut_slice()
+                   .copy_from_slice(&self.private.gl_read_buffers[self.private.gl_read_index]);
+                capture::capture(engine.marker().1, buf, times);
+            }
+
+            FrameCapture::OpenCL(ref ocl_gl_texture) => {
+                let mut buf = capture::get_buffer(engine, (w, h));
+                hw::read_ocl_image_into_buf(engine,
+                                            ocl_gl_texture.as_ref(),
+                                            ocl_gl_texture.region(),
+                                            &mut buf);
+                capture::capture(engine.marker().1, buf, times);
+            }
+        }
+    }
+}
+
+impl FPSConverter for SamplingConverter {
+    fn time_passed(&mut self, engine: &mut Engine, frametime: f64, capture: CaptureFn) {
+        assert!(frametime >= 0.0f64);
+
+        let frame_capture = capture(engine);
+        let mut pixels_read = false;
+
+        let (window_start, window_end) = self.shutter.window();
+        let output_delay = window_end.max(1f64);
+
+        // The time before the start of the capturing is taken to look like the first frame, so
+        // the first output frames don't fade in.
+        let old_remainder = if self.started {
+            self.remainder
         } else {
-            let weight = (1f64 - old_remainder.max(1f64 - exposure)) * (1f64 / exposure);
-
-            match frame_capture {
-                FrameCapture::OpenGL(read_pixels) => {
-                    let (w, h) = self.private.video_resolution;
-                    self.private.gl_read_buffer.resize((w * h * 3) as usize, 0);
-                    self.private
-                        .gl_sampling_buffer
-                        .resize((w * h * 3) as usize, 0f32);
-
-                    read_pixels(engine.marker().1, (w, h), &mut self.private.gl_read_buffer);
-
-                    let mut buf = capture::get_buffer(engine.marker().1, (w, h));
-                    buf.set_format(format::Pixel::RGB24);
-                    weighted_image_add_to(&self.private.gl_sampling_buffer,
-                                          &self.private.gl_read_buffer,
-                                          buf.as_mut_slice(),
-                                          weight as f32);
-                    capture::capture(engine.marker().1, buf, 1);
-
-                    fill_with_black(&mut self.private.gl_sampling_buffer);
-
-                    self.remainder -= 1f64;
-
-                    // Output it more times if needed.
-                    let additional_frames = self.remainder as usize;
-                    if additional_frames > 0 {
-                        let mut buf = capture::get_buffer(engine.marker().1, (w, h));
-                        buf.set_format(format::Pixel::RGB24);
-                        buf.as_mut_slice()
-                           .copy_from_slice(&self.private.gl_read_buffer);
-                        capture::capture(engine.marker().1, buf, additional_frames);
-
-                        self.remainder -= additional_frames as f64;
-                    }
-
-                    // Add the remaining image into the buffer.
-                    if self.remainder > (1f64 - exposure) {
-                        let private: &mut SamplingConverterPrivate = &mut self.private;
-                        weighted_image_add(&mut private.gl_sampling_buffer,
-                                           &private.gl_read_buffer,
-                                           ((self.remainder - (1f64 - exposure))
-                                            * (1f64 / exposure))
-                                           as f32);
-                    }
+            self.started = true;
+            self.remainder.min(window_start)
+        };
+
+        self.remainder += frametime / self.time_base;
+        let new_remainder = self.remainder;
+
+        // Input frames covering whole exposure windows are output as is, and the consecutive
+        // ones are output together.
+        let mut input_frame_times = 0;
+
+        let mut output_frames = 0;
+        let mut frame = 0;
+
+        // Go over the pending output frames which this input frame affects.
+        while frame as f64 + window_start < new_remainder {
+            let frame_start = frame as f64;
+            let weight = self.shutter.weight(old_remainder - frame_start,
+                                             new_remainder - frame_start);
+            let output = new_remainder >= frame_start + output_delay;
+
+            let slot = self.slot(frame - output_frames);
+
+            if output && !self.ring_used[slot] && weight >= 1f64 {
+                input_frame_times += 1;
+            } else {
+                if input_frame_times > 0 {
+                    self.output_input_frame(engine,
+                                            &frame_capture,
+                                            &mut pixels_read,
+                                            input_frame_times);
+                    input_frame_times = 0;
                 }
 
-                FrameCapture::OpenCL(ocl_gl_texture) => {
-                    let ocl_data = self.private.get_ocl_data(engine).unwrap();
-
-                    ocl_weighted_image_add(engine,
-                                           ocl_gl_texture.as_ref(),
-                                           ocl_data.src_buffer(),
-                                           ocl_data.output_image(),
-                                           weight as f32);
-
-                    ocl_fill_with_black(engine, ocl_data.dst_buffer());
-
-                    ocl_data.switch_buffer_index();
-
-                    // Output the frame.
-                    let (w, h) = hw::get_resolution(engine.marker().1);
-                    let mut buf = capture::get_buffer(engine.marker().1, (w, h));
-                    hw::read_ocl_image_into_buf(engine, ocl_data.output_image(), &mut buf);
-                    capture::capture(engine.marker().1, buf, 1);
-
-                    self.remainder -= 1f64;
-
-                    // Output it more times if needed.
-                    let additional_frames = self.remainder as usize;
-                    if additional_frames > 0 {
-                        let mut buf = capture::get_buffer(engine.marker().1, (w, h));
-                        hw::read_ocl_image_into_buf(engine, ocl_gl_texture.as_ref(), &mut buf);
-                        capture::capture(engine.marker().1, buf, additional_frames);
-
-                        self.remainder -= additional_frames as f64;
-                    }
-
-                    // Add the remaining image into the buffer.
-                    if self.remainder > (1f64 - exposure) {
-                        ocl_weighted_image_add(engine,
-                                               ocl_gl_texture.as_ref(),
-                                               ocl_data.src_buffer(),
-                                               ocl_data.dst_buffer(),
-                                               ((self.remainder - (1f64 - exposure))
-                                                * (1f64 / exposure))
-                                               as f32);
-                        ocl_data.switch_buffer_index();
-                    }
+                if output {
+                    self.output(engine, &frame_capture, &mut pixels_read, slot, weight);
+                } else if weight > 0f64 {
+                    self.add(engine, &frame_capture, &mut pixels_read, slot, weight);
+                    self.ring_used[slot] = true;
                 }
             }
+
+            if output {
+                self.ring_used[slot] = false;
+                self.ring_head = (self.ring_head + 1) % self.ring_used.len();
+                self.remainder -= 1f64;
+                output_frames += 1;
+            }
+
+            frame += 1;
         }
+
+        if input_frame_times > 0 {
+            self.output_input_frame(engine, &frame_capture, &mut pixels_read, input_frame_times);
+        }
+    }
+
+    #[inline]
+    fn backup_and_free_ocl_data(&mut self, engine: &mut Engine) {
+        self.private.backup_and_free_ocl_data(engine);
     }
 }
 
 impl SamplingConverterPrivate {
     #[inline]
-    fn new(engine: &mut Engine, video_resolution: (u32, u32)) -> Self {
+    fn new(engine: &mut Engine,
+           video_resolution: (u32, u32),
+           accumulator_count: usize,
+           transfer: Transfer)
+           -> Self {
         Self { ocl_runtime_data:
                    MaybeUnavailable::from_check_result(OclRuntimeData::new(engine,
-                                                                           video_resolution)),
-               ocl_backup_buffer: None,
+                                                                           video_resolution,
+                                                                           accumulator_count)),
+               ocl_backup_buffers: None,
                video_resolution,
-               gl_sampling_buffer: Vec::new(),
-               gl_read_buffer: Vec::new() }
+               accumulator_count,
+               transfer,
+               pool: None,
+               gl_sampling_buffers: vec![Vec::new(); accumulator_count],
+               gl_read_buffers: [Vec::new(), Vec::new()],
+               gl_read_index: 0 }
     }
 
     #[inline]
@@ -232,23 +428,21 @@ impl SamplingConverterPrivate {
     }
 
     /// This should be called before an engine restart.
-    fn backup_and_free_ocl_data(&mut self, engine: &mut Engine) {
+    fn backup_and_free_ocl_data(&mut self, _engine: &mut Engine) {
         let reset = if let MaybeUnavailable::Available(ref ocl_data) = self.ocl_runtime_data {
-            // Copy the src buffer into the output image.
-            ocl_weighted_image_add(engine,
-                                   ocl_data.dst_buffer(),
-                                   ocl_data.src_buffer(),
-                                   ocl_data.output_image(),
-                                   0f32);
-
-            let image = ocl_data.output_image();
-
-            let mut backup_buffer = Vec::with_capacity(image.element_count());
-            backup_buffer.resize(image.element_count(), 0f32.into());
-
-            image.read(&mut backup_buffer).enq().expect("image.read()");
-
-            self.ocl_backup_buffer = Some(backup_buffer);
+            let backup_buffers = ocl_data.ocl_accumulators
+                                         .iter()
+                                         .map(|image| {
+                                             let mut backup_buffer: Vec<ocl::prm::Float> =
+                                                 vec![0f32.into(); image.element_count()];
+                                             image.read(&mut backup_buffer)
+                                                  .enq()
+                                                  .expect("image.read()");
+                                             backup_buffer
+                                         })
+                                         .collect();
+
+            self.ocl_backup_buffers = Some(backup_buffers);
 
             true
         } else {
@@ -266,99 +460,72 @@ impl SamplingConverterPrivate {
             panic!("tried to restore already existing OpenCL data");
         }
 
-        let ocl_data = OclRuntimeData::new(engine, self.video_resolution)
+        let ocl_data = OclRuntimeData::new(engine, self.video_resolution, self.accumulator_count)
             .expect("changing from fullscreen to windowed is not supported");
 
-        let temp_image = {
-            let pro_que = hw::get_pro_que(engine).unwrap();
-            hw::build_ocl_image(pro_que,
-                                ocl::MemFlags::new().read_only().host_write_only(),
-                                ocl::enums::ImageChannelDataType::Float,
-                                self.video_resolution.into()).expect("building an OpenCL image")
-        };
-
-        let backup_buffer = self.ocl_backup_buffer.take().unwrap();
-        temp_image.write(&backup_buffer)
-                  .enq()
-                  .expect("image.write()");
-
-        // Copy the backup buffer into the src buffer.
-        ocl_weighted_image_add(engine,
-                               ocl_data.dst_buffer(),
-                               &temp_image,
-                               ocl_data.src_buffer(),
-                               0f32);
+        let backup_buffers = self.ocl_backup_buffers.take().unwrap();
+        for (image, backup_buffer) in ocl_data.ocl_accumulators.iter().zip(&backup_buffers) {
+            image.write(backup_buffer).enq().expect("image.write()");
+        }
 
         self.ocl_runtime_data = MaybeUnavailable::Available(ocl_data);
     }
 }
 
 impl OclRuntimeData {
-    fn new(engine: &mut Engine, (w, h): (u32, u32)) -> Option<Self> {
-        let mut rv = if let Some(pro_que) = hw::get_pro_que(engine) {
-            Some(Self { ocl_buffers:
-                            [hw::build_ocl_image(pro_que,
-                                                 ocl::MemFlags::new().read_write()
-                                                                     .host_no_access(),
-                                                 ocl::enums::ImageChannelDataType::Float,
-                                                 (w, h).into()).expect("building an OpenCL image"),
-                             hw::build_ocl_image(pro_que,
-                                                 ocl::MemFlags::new().read_write()
-                                                                     .host_no_access(),
-                                                 ocl::enums::ImageChannelDataType::Float,
-                                                 (w, h).into()).expect("building an OpenCL image")],
-                        ocl_output_image:
-                            hw::build_ocl_image(pro_que,
-                                                ocl::MemFlags::new().read_write().host_read_only(),
-                                                ocl::enums::ImageChannelDataType::Float,
-                                                (w, h).into()).expect("building an OpenCL image"),
-                        ocl_current_buffer_index: 0 })
+    fn new(engine: &mut Engine, (w, h): (u32, u32), accumulator_count: usize) -> Option<Self> {
+        let rv = if let Some(pro_que) = hw::get_pro_que(engine) {
+            // The accumulation images and the scratch image swap places, so they all need the
+            // same flags.
+            let build_image = || {
+                hw::build_ocl_image::<ocl::prm::Float>(pro_que,
+                                                       ocl::MemFlags::new().read_write(),
+                                                       ocl::enums::ImageChannelDataType::Float,
+                                                       (w, h).into())
+                    .expect("building an OpenCL image")
+            };
+
+            Some(Self { ocl_accumulators: (0..accumulator_count).map(|_| build_image()).collect(),
+                        ocl_scratch_image: build_image() })
         } else {
             None
         };
 
-        if let Some(ref mut rv) = rv {
-            ocl_fill_with_black(engine, rv.src_buffer());
+        if let Some(ref rv) = rv {
+            for image in &rv.ocl_accumulators {
+                ocl_fill_with_black(engine, image);
+            }
         }
 
         rv
     }
 
+    /// Makes the scratch image the accumulation image in the given slot and vice versa.
     #[inline]
-    fn src_buffer(&self) -> &ocl::Image<ocl::prm::Float> {
-        &self.ocl_buffers[self.ocl_current_buffer_index]
-    }
-
-    #[inline]
-    fn dst_buffer(&self) -> &ocl::Image<ocl::prm::Float> {
-        &self.ocl_buffers[self.ocl_current_buffer_index ^ 1]
-    }
-
-    #[inline]
-    fn output_image(&self) -> &ocl::Image<ocl::prm::Float> {
-        &self.ocl_output_image
-    }
-
-    #[inline]
-    fn switch_buffer_index(&mut self) {
-        self.ocl_current_buffer_index ^= 1;
+    fn swap_scratch_image(&mut self, slot: usize) {
+        mem::swap(&mut self.ocl_accumulators[slot], &mut self.ocl_scratch_image);
     }
 }
 
 #[inline]
 fn ocl_weighted_image_add<T: OclPrm, U: OclPrm, V: OclPrm>(engine: &mut Engine,
                                                            src: &ocl::Image<T>,
+                                                           (src_x, src_y): (u32, u32),
                                                            buf: &ocl::Image<U>,
                                                            dst: &ocl::Image<V>,
-                                                           weight: f32) {
+                                                           weight: f32,
+                                                           linear: bool) {
     let pro_que = hw::get_pro_que(engine).unwrap();
 
     let kernel = pro_que.kernel_builder("weighted_image_add")
-                        .global_work_size(src.dims())
+                        .global_work_size(buf.dims())
                         .arg(src)
+                        .arg(src_x)
+                        .arg(src_y)
                         .arg(buf)
                         .arg(dst)
                         .arg(weight)
+                        .arg(linear as u32)
                         .build()
                         .unwrap();
 
@@ -383,29 +550,134 @@ fn ocl_fill_with_black<T: OclPrm>(engine: &mut Engine, image: &ocl::Image<T>) {
 }
 
 #[inline]
-fn weighted_image_add(buf: &mut [f32], image: &[u8], weight: f32) {
+fn ocl_linear_to_srgb<T: OclPrm, U: OclPrm>(engine: &mut Engine,
+                                            src: &ocl::Image<T>,
+                                            dst: &ocl::Image<U>) {
+    let pro_que = hw::get_pro_que(engine).unwrap();
+
+    let kernel = pro_que.kernel_builder("linear_to_srgb_image")
+                        .global_work_size(src.dims())
+                        .arg(src)
+                        .arg(dst)
+                        .build()
+                        .unwrap();
+
+    unsafe {
+        kernel.enq().expect("sampling kernel enq()");
+    }
+}
+
+impl Transfer {
+    pub(super) fn new(linear: bool) -> Self {
+        let mut decode_table = [0f32; 256];
+        for (i, x) in decode_table.iter_mut().enumerate() {
+            *x = if linear {
+                srgb_to_linear(i as f32 / 255f32) * 255f32
+            } else {
+                i as f32
+            };
+        }
+
+        Self { linear,
+               decode_table }
+    }
+
+    /// Returns the accumulated value of the pixel value.
+    #[inline]
+    fn decode(&self, pixel: u8) -> f32 {
+        self.decode_table[usize::from(pixel)]
+    }
+
+    /// Returns the pixel value of the accumulated value.
+    #[inline]
+    fn encode(&self, x: f32) -> u8 {
+        if self.linear {
+            to_pixel(linear_to_srgb(x / 255f32) * 255f32)
+        } else {
+            to_pixel(x)
+        }
+    }
+}
+
+/// Rounds the value from 0 to 255 into a pixel value.
+#[inline]
+fn to_pixel(x: f32) -> u8 {
+    // The values are never negative, so adding 0.5 and truncating rounds them.
+    (x + 0.5).min(255f32) as u8
+}
+
+/// Converts the sRGB-encoded value into linear light, both from 0 to 1.
+#[inline]
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.040_45 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts the linear light value into sRGB encoding, both from 0 to 1.
+#[inline]
+fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1f32 / 2.4) - 0.055
+    }
+}
+
+// The per-pixel loops are written with iterators so they are vectorized. The linear light loops
+// look up the transfer function for every pixel, which prevents the vectorization, so they are
+// kept separate from the plain loops.
+
+#[inline]
+pub(super) fn weighted_image_add(buf: &mut [f32], image: &[u8], weight: f32, transfer: &Transfer) {
     assert_eq!(buf.len(), image.len());
 
-    for i in 0..buf.len() {
-        buf[i] += f32::from(image[i]) * weight;
+    if transfer.linear {
+        for (x, &pixel) in buf.iter_mut().zip(image) {
+            *x += transfer.decode(pixel) * weight;
+        }
+    } else {
+        for (x, &pixel) in buf.iter_mut().zip(image) {
+            *x += f32::from(pixel) * weight;
+        }
     }
 }
 
 #[inline]
-fn weighted_image_add_to(buf: &[f32], image: &[u8], dst: &mut [u8], weight: f32) {
+pub(super) fn weighted_image_add_to(buf: &[f32],
+                                    image: &[u8],
+                                    dst: &mut [u8],
+                                    weight: f32,
+                                    transfer: &Transfer) {
     assert_eq!(buf.len(), image.len());
     assert_eq!(buf.len(), dst.len());
 
-    for i in 0..buf.len() {
-        dst[i] = (buf[i] + f32::from(image[i]) * weight).round() as u8;
+    if transfer.linear {
+        for ((&x, &pixel), out) in buf.iter().zip(image).zip(dst) {
+            *out = transfer.encode(x + transfer.decode(pixel) * weight);
+        }
+    } else {
+        for ((&x, &pixel), out) in buf.iter().zip(image).zip(dst) {
+            *out = to_pixel(x + f32::from(pixel) * weight);
+        }
     }
 }
 
 #[inline]
-fn fill_with_black(buf: &mut [f32]) {
-    buf.iter_mut().for_each(|x| *x = 0.);
+pub(super) fn fill_with_black(buf: &mut [f32]) {
+    for x in buf {
+        *x = 0f32;
+    }
 }
 
+cvar!(cap_sampling_curve, "box");
+cvar!(cap_sampling_exposure, "0.5");
+cvar!(cap_sampling_linear, "0");
+cvar!(cap_sampling_phase, "trailing");
+cvar!(cap_sampling_sps, "");
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -424,7 +696,7 @@ mod test {
         let mut buf = [1f32, 2f32, 3f32, 4f32, 5f32];
         let image = [10, 20, 30, 40, 50];
 
-        weighted_image_add(&mut buf, &image, 0.5f32);
+        weighted_image_add(&mut buf, &image, 0.5f32, &Transfer::new(false));
 
         assert_eq!(buf, [6f32, 12f32, 18f32, 24f32, 30f32]);
     }
@@ -435,7 +707,7 @@ mod test {
         let mut buf = [1f32, 2f32, 3f32, 4f32, 5f32];
         let image = [10, 20, 30, 40, 50, 60];
 
-        weighted_image_add(&mut buf, &image, 0.5f32);
+        weighted_image_add(&mut buf, &image, 0.5f32, &Transfer::new(false));
     }
 
     #[test]
@@ -444,7 +716,7 @@ mod test {
         let image = [10, 20, 30, 40, 50];
         let mut dst = [5, 4, 3, 2, 1];
 
-        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32);
+        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32, &Transfer::new(false));
 
         assert_eq!(dst, [6, 12, 18, 24, 30]);
     }
@@ -456,7 +728,7 @@ mod test {
         let image = [10, 20, 30, 40, 50, 60];
         let mut dst = [5, 4, 3, 2, 1];
 
-        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32);
+        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32, &Transfer::new(false));
     }
 
     #[test]
@@ -466,6 +738,44 @@ mod test {
         let image = [10, 20, 30, 40, 50];
         let mut dst = [5, 4, 3, 2, 1, 0];
 
-        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32);
+        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32, &Transfer::new(false));
+    }
+
+    #[test]
+    fn srgb_test() {
+        // Reference values from the sRGB specification.
+        assert!((srgb_to_linear(0.5) - 0.214_041).abs() < 1e-5);
+        assert!((srgb_to_linear(0.04) - 0.003_095_98).abs() < 1e-7);
+        assert!((linear_to_srgb(0.214_041) - 0.5).abs() < 1e-5);
+        assert!((linear_to_srgb(0.002) - 0.025_84).abs() < 1e-7);
+        assert_eq!(srgb_to_linear(1f32), 1f32);
+        assert_eq!(linear_to_srgb(0f32), 0f32);
+    }
+
+    #[test]
+    fn transfer_round_trip_test() {
+        for &linear in &[false, true] {
+            let transfer = Transfer::new(linear);
+
+            for pixel in 0..=255 {
+                assert_eq!(transfer.encode(transfer.decode(pixel)), pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_image_add_linear_test() {
+        let transfer = Transfer::new(true);
+
+        let mut buf = [0f32; 3];
+        let image = [0, 255, 128];
+        weighted_image_add(&mut buf, &image, 0.5f32, &transfer);
+
+        // Half of the white is 0.5 in linear light, encoded as 188 in sRGB rather than 128.
+        let image = [255, 0, 128];
+        let mut dst = [0; 3];
+        weighted_image_add_to(&buf, &image, &mut dst, 0.5f32, &transfer);
+
+        assert_eq!(dst, [188, 188, 128]);
     }
 }
@@ -0,0 +1,318 @@
+use failure::{bail, ensure, format_err, Error};
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Number of segments in the cumulative weight table.
+const TABLE_SEGMENTS: usize = 1024;
+
+/// Shape of the shutter opening over the exposure window.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutterCurve {
+    /// Every moment of the exposure has the same weight.
+    Box,
+
+    /// The weight rises linearly up to the middle of the exposure and falls back down.
+    Triangle,
+
+    /// Gaussian weight centered on the middle of the exposure, with the window edges at three
+    /// standard deviations.
+    Gaussian,
+
+    /// Weights spread evenly over the exposure and linearly interpolated in between.
+    Custom(Vec<f64>),
+}
+
+/// Placement of the exposure window within the output frame duration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutterPhase {
+    /// The exposure starts at the beginning of the frame duration.
+    Leading,
+
+    /// The exposure is centered within the frame duration.
+    Centered,
+
+    /// The exposure ends at the end of the frame duration.
+    Trailing,
+}
+
+/// Computes the weights of the input frames for the sampling FPS converter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shutter {
+    /// Start of the exposure window, in output frames.
+    window_start: f64,
+
+    /// Exposure duration, in output frames.
+    exposure: f64,
+
+    /// Normalized integral of the curve at evenly spaced points over the exposure window.
+    cumulative: Vec<f64>,
+}
+
+impl ShutterCurve {
+    /// Returns the unnormalized weight at the given position in the exposure window, from 0 to 1.
+    fn value(&self, x: f64) -> f64 {
+        match *self {
+            ShutterCurve::Box => 1f64,
+            ShutterCurve::Triangle => 1f64 - (2f64 * x - 1f64).abs(),
+            ShutterCurve::Gaussian => {
+                let z = (x - 0.5) * 6f64;
+                (-z * z / 2f64).exp()
+            }
+            ShutterCurve::Custom(ref values) => {
+                let position = x * (values.len() - 1) as f64;
+                let index = (position.floor() as usize).min(values.len() - 2);
+                let t = position - index as f64;
+
+                values[index] * (1f64 - t) + values[index + 1] * t
+            }
+        }
+    }
+}
+
+impl Shutter {
+    /// Creates the shutter. `exposure` is the fraction of the frame duration during which the
+    /// shutter is open.
+    pub fn new(exposure: f64, curve: &ShutterCurve, phase: ShutterPhase) -> Self {
+        assert!(exposure > 0f64);
+
+        let window_start = match phase {
+            ShutterPhase::Leading => 0f64,
+            ShutterPhase::Centered => (1f64 - exposure) / 2f64,
+            ShutterPhase::Trailing => 1f64 - exposure,
+        };
+
+        // Integrate the curve with the trapezoidal rule.
+        let mut cumulative = Vec::with_capacity(TABLE_SEGMENTS + 1);
+        cumulative.push(0f64);
+
+        let step = 1f64 / TABLE_SEGMENTS as f64;
+        let mut previous = curve.value(0f64);
+        for i in 1..=TABLE_SEGMENTS {
+            let value = curve.value(i as f64 * step);
+            let sum = cumulative[i - 1] + (previous + value) / 2f64 * step;
+            cumulative.push(sum);
+            previous = value;
+        }
+
+        let total = cumulative[TABLE_SEGMENTS];
+        for x in &mut cumulative {
+            *x /= total;
+        }
+
+        Self { window_start,
+               exposure,
+               cumulative }
+    }
+
+    /// Creates the shutter which is open over the whole frame duration with every moment having the
+    /// same weight, so the input frames are weighted by their duration alone.
+    #[inline]
+    pub fn full_frame() -> Self {
+        Self::new(1f64, &ShutterCurve::Box, ShutterPhase::Leading)
+    }
+
+    /// Returns the exposure duration, in output frames.
+    #[inline]
+    pub fn exposure(&self) -> f64 {
+        self.exposure
+    }
+
+    /// Returns the start and the end of the exposure window, in output frames relative to the
+    /// start of the output frame. With exposures above 1 the window extends into the neighbouring
+    /// output frames.
+    #[inline]
+    pub fn window(&self) -> (f64, f64) {
+        (self.window_start, self.window_start + self.exposure)
+    }
+
+    /// Returns the weight of an input frame spanning from `start` to `end`, in output frames
+    /// relative to the start of the current output frame.
+    ///
+    /// The weights of the input frames covering a whole output frame add up to 1.
+    pub fn weight(&self, start: f64, end: f64) -> f64 {
+        let start = (start - self.window_start) / self.exposure;
+        let end = (end - self.window_start) / self.exposure;
+
+        if end <= start {
+            return 0f64;
+        }
+
+        self.integral(end) - self.integral(start)
+    }
+
+    /// Returns the normalized integral of the curve from the start of the exposure window to `x`.
+    fn integral(&self, x: f64) -> f64 {
+        if x <= 0f64 {
+            return 0f64;
+        }
+
+        if x >= 1f64 {
+            return 1f64;
+        }
+
+        let position = x * TABLE_SEGMENTS as f64;
+        let index = position.floor() as usize;
+        let t = position - index as f64;
+
+        self.cumulative[index] * (1f64 - t) + self.cumulative[index + 1] * t
+    }
+}
+
+/// Parses the given string into a `ShutterCurve`.
+pub fn parse_curve(string: &str) -> Result<ShutterCurve> {
+    let mut words = string.split_whitespace();
+
+    let curve = match words.next() {
+        Some("box") => ShutterCurve::Box,
+        Some("triangle") => ShutterCurve::Triangle,
+        Some("gaussian") => ShutterCurve::Gaussian,
+        Some("custom") => {
+            let values = words.by_ref()
+                              .map(|x| {
+                                  x.parse::<f64>()
+                                   .map_err(|_| format_err!("invalid curve value: {}", x))
+                              })
+                              .collect::<Result<Vec<_>>>()?;
+
+            ensure!(values.len() >= 2, "a custom curve needs at least two values");
+            ensure!(values.iter().all(|&x| x >= 0f64),
+                    "the curve values cannot be negative");
+            ensure!(values.iter().any(|&x| x > 0f64),
+                    "at least one curve value must be positive");
+
+            ShutterCurve::Custom(values)
+        }
+        _ => bail!("expected box, triangle, gaussian or custom followed by the curve values"),
+    };
+
+    ensure!(words.next().is_none(), "unexpected curve values");
+
+    Ok(curve)
+}
+
+/// Parses the given string into a `ShutterPhase`.
+pub fn parse_phase(string: &str) -> Result<ShutterPhase> {
+    match string {
+        "leading" => Ok(ShutterPhase::Leading),
+        "centered" => Ok(ShutterPhase::Centered),
+        "trailing" => Ok(ShutterPhase::Trailing),
+        _ => bail!("expected leading, centered or trailing"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn box_test() {
+        let shutter = Shutter::new(0.5, &ShutterCurve::Box, ShutterPhase::Trailing);
+
+        assert_eq!(shutter.weight(0f64, 0.5), 0f64);
+        assert_close(shutter.weight(0.25, 0.75), 0.5);
+        assert_close(shutter.weight(0.5, 1f64), 1f64);
+    }
+
+    #[test]
+    fn full_frame_test() {
+        let shutter = Shutter::full_frame();
+        assert_eq!(shutter.window(), (0f64, 1f64));
+
+        // Four input frames per output frame are weighted equally.
+        for i in 0..4 {
+            assert_close(shutter.weight(f64::from(i) / 4f64, f64::from(i + 1) / 4f64), 0.25);
+        }
+    }
+
+    #[test]
+    fn phase_test() {
+        let leading = Shutter::new(0.5, &ShutterCurve::Box, ShutterPhase::Leading);
+        assert_close(leading.weight(0f64, 0.25), 0.5);
+        assert_eq!(leading.weight(0.5, 1f64), 0f64);
+
+        let centered = Shutter::new(0.5, &ShutterCurve::Box, ShutterPhase::Centered);
+        assert_close(centered.weight(0f64, 0.5), 0.5);
+        assert_close(centered.weight(0.5, 1f64), 0.5);
+    }
+
+    #[test]
+    fn long_exposure_test() {
+        let shutter = Shutter::new(2f64, &ShutterCurve::Box, ShutterPhase::Trailing);
+        assert_eq!(shutter.window(), (-1f64, 1f64));
+        assert_close(shutter.weight(-1f64, 0f64), 0.5);
+        assert_close(shutter.weight(0f64, 0.5), 0.25);
+
+        let shutter = Shutter::new(3f64, &ShutterCurve::Box, ShutterPhase::Centered);
+        assert_eq!(shutter.window(), (-1f64, 2f64));
+        assert_close(shutter.weight(1f64, 3f64), 1f64 / 3f64);
+    }
+
+    #[test]
+    fn triangle_test() {
+        let shutter = Shutter::new(1f64, &ShutterCurve::Triangle, ShutterPhase::Trailing);
+
+        assert_close(shutter.weight(0f64, 0.5), 0.5);
+        assert_close(shutter.weight(0f64, 0.25), 0.125);
+        assert_close(shutter.weight(0.25, 0.75), 0.75);
+    }
+
+    #[test]
+    fn gaussian_test() {
+        let shutter = Shutter::new(1f64, &ShutterCurve::Gaussian, ShutterPhase::Trailing);
+
+        assert_close(shutter.weight(0f64, 0.5), 0.5);
+
+        // The middle third holds most of the weight.
+        assert!(shutter.weight(1f64 / 3f64, 2f64 / 3f64) > 0.68);
+    }
+
+    #[test]
+    fn custom_test() {
+        let shutter = Shutter::new(1f64,
+                                   &ShutterCurve::Custom(vec![1f64, 1f64, 0f64, 0f64]),
+                                   ShutterPhase::Trailing);
+
+        assert_close(shutter.weight(2f64 / 3f64, 1f64), 0f64);
+        assert_close(shutter.weight(0f64, 1f64 / 3f64), 2f64 / 3f64);
+    }
+
+    #[test]
+    fn weights_add_up_test() {
+        let curves = [ShutterCurve::Box,
+                      ShutterCurve::Triangle,
+                      ShutterCurve::Gaussian,
+                      ShutterCurve::Custom(vec![0f64, 2f64, 1f64])];
+
+        for curve in &curves {
+            let shutter = Shutter::new(0.7, curve, ShutterPhase::Centered);
+
+            let sum: f64 = (0..7).map(|i| shutter.weight(f64::from(i) / 7f64,
+                                                        f64::from(i + 1) / 7f64))
+                                 .sum();
+            assert_close(sum, 1f64);
+        }
+    }
+
+    #[test]
+    fn parse_curve_test() {
+        assert_eq!(parse_curve("gaussian").unwrap(), ShutterCurve::Gaussian);
+        assert_eq!(parse_curve("custom 0 1 0.5").unwrap(),
+                   ShutterCurve::Custom(vec![0f64, 1f64, 0.5]));
+        assert!(parse_curve("custom 1").is_err());
+        assert!(parse_curve("custom 0 0").is_err());
+        assert!(parse_curve("custom 1 -1").is_err());
+        assert!(parse_curve("box 1").is_err());
+        assert!(parse_curve("").is_err());
+    }
+
+    #[test]
+    fn parse_phase_test() {
+        assert_eq!(parse_phase("centered").unwrap(), ShutterPhase::Centered);
+        assert!(parse_phase("middle").is_err());
+    }
+}
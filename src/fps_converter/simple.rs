@@ -1,8 +1,5 @@
-use ffmpeg::format;
-
 use super::*;
 use crate::capture;
-use crate::hooks::hw::FrameCapture;
 
 /// Simple FPS converter which drops and duplicates frames to get constant FPS output.
 pub struct SimpleConverter {
@@ -25,9 +22,7 @@ impl SimpleConverter {
 }
 
 impl FPSConverter for SimpleConverter {
-    fn time_passed<F>(&mut self, engine: &mut Engine, frametime: f64, capture: F)
-        where F: FnOnce(&mut Engine) -> FrameCapture
-    {
+    fn time_passed(&mut self, engine: &mut Engine, frametime: f64, capture: CaptureFn) {
         assert!(frametime >= 0.0f64);
 
         self.remainder += frametime / self.time_base;
@@ -39,20 +34,7 @@ impl FPSConverter for SimpleConverter {
 
         if frames > 0 {
             let frame_capture = capture(engine);
-
-            let (w, h) = hw::get_resolution(engine.marker().1);
-            let mut buf = capture::get_buffer(engine.marker().1, (w, h));
-
-            match frame_capture {
-                FrameCapture::OpenGL(read_pixels) => {
-                    buf.set_format(format::Pixel::RGB24);
-                    read_pixels(engine.marker().1, (w, h), buf.as_mut_slice());
-                }
-
-                FrameCapture::OpenCL(ocl_gl_texture) => {
-                    hw::read_ocl_image_into_buf(engine, ocl_gl_texture.as_ref(), &mut buf);
-                }
-            }
+            let buf = read_frame(engine, frame_capture);
 
             capture::capture(engine.marker().1, buf, frames);
         }
@@ -0,0 +1,38 @@
+use super::*;
+
+/// FPS converter which advances several seconds of game time per second of output.
+///
+/// The game runs at the regular forced frametime and the inner converter receives the frametime
+/// divided by the factor, so it drops the skipped frames or, if it's sampling, blends them.
+pub struct TimelapseConverter {
+    inner: Box<dyn FPSConverter>,
+
+    /// Seconds of game time per second of output.
+    factor: f64,
+}
+
+impl TimelapseConverter {
+    #[inline]
+    pub fn new(inner: Box<dyn FPSConverter>, factor: f64) -> Self {
+        assert!(factor >= 1f64);
+
+        Self { inner, factor }
+    }
+}
+
+impl FPSConverter for TimelapseConverter {
+    fn time_passed(&mut self, engine: &mut Engine, frametime: f64, capture: CaptureFn) {
+        assert!(frametime >= 0.0f64);
+
+        self.inner.time_passed(engine, frametime / self.factor, capture);
+    }
+
+    #[inline]
+    fn backup_and_free_ocl_data(&mut self, engine: &mut Engine) {
+        self.inner.backup_and_free_ocl_data(engine);
+    }
+}
+
+cvar!(cap_timelapse, "1");
+cvar!(cap_timelapse_audio, "silence");
+cvar!(cap_timelapse_blend, "0");
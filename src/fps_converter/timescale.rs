@@ -0,0 +1,35 @@
+use super::*;
+
+/// FPS converter which maps the scaled game time back to the output time.
+///
+/// The game runs at the forced frametime multiplied by the current scale, so dividing the
+/// frametime by the scale gives the output time the frame takes up. The frames themselves are
+/// handled by the inner converter.
+pub struct TimescaleConverter {
+    inner: Box<dyn FPSConverter>,
+}
+
+impl TimescaleConverter {
+    #[inline]
+    pub fn new(inner: Box<dyn FPSConverter>) -> Self {
+        Self { inner }
+    }
+}
+
+impl FPSConverter for TimescaleConverter {
+    fn time_passed(&mut self, engine: &mut Engine, frametime: f64, capture: CaptureFn) {
+        assert!(frametime >= 0.0f64);
+
+        let scale = engine.data().timescale;
+        self.inner.time_passed(engine, frametime / scale, capture);
+    }
+
+    #[inline]
+    fn backup_and_free_ocl_data(&mut self, engine: &mut Engine) {
+        self.inner.backup_and_free_ocl_data(engine);
+    }
+}
+
+cvar!(cap_timescale, "1");
+cvar!(cap_timescale_audio, "pitch");
+cvar!(cap_timescale_ramp, "");
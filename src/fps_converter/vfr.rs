@@ -0,0 +1,55 @@
+use super::*;
+use crate::capture;
+use crate::encode::VFR_TIME_BASE;
+
+/// Variable frame rate converter which captures every game frame with its timestamp.
+pub struct VfrConverter {
+    /// How much time passed in-game since the first frame, in seconds.
+    time: f64,
+
+    /// Timestamp of the last captured frame, in `VFR_TIME_BASE` units.
+    last_pts: Option<i64>,
+}
+
+impl VfrConverter {
+    #[inline]
+    pub fn new() -> Self {
+        Self { time: 0f64,
+               last_pts: None }
+    }
+}
+
+impl FPSConverter for VfrConverter {
+    fn time_passed(&mut self, engine: &mut Engine, frametime: f64, capture: CaptureFn) {
+        assert!(frametime >= 0.0f64);
+
+        // The first frame starts the video.
+        if self.last_pts.is_some() {
+            self.time += frametime;
+        }
+
+        let pts = to_pts(self.time);
+
+        // Frames can't share a timestamp, so the ones taking no time are dropped.
+        if self.last_pts.map_or(false, |last_pts| pts <= last_pts) {
+            return;
+        }
+
+        self.last_pts = Some(pts);
+
+        let frame_capture = capture(engine);
+        let mut buf = read_frame(engine, frame_capture);
+        buf.set_pts(pts);
+
+        capture::capture(engine.marker().1, buf, 1);
+    }
+}
+
+/// Converts the time in seconds into `VFR_TIME_BASE` units.
+#[inline]
+fn to_pts(time: f64) -> i64 {
+    (time / f64::from(VFR_TIME_BASE)).round() as i64
+}
+
+cvar!(cap_vfr, "0");
+cvar!(cap_vfr_timecodes, "");
@@ -0,0 +1,220 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::sampling::{fill_with_black, weighted_image_add, weighted_image_add_to, Transfer};
+
+/// Highest number of worker threads.
+const MAX_THREADS: usize = 16;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A pointer which can be sent to a worker thread.
+///
+/// The pool user makes sure the data outlives the job and is not accessed concurrently. Pointers
+/// made from shared references must only be turned back into shared slices with `slice()`.
+#[derive(Clone, Copy)]
+struct SendPtr<T>(*mut T);
+
+unsafe impl<T> Send for SendPtr<T> {}
+
+/// Worker threads running the per-pixel sampling operations over bands of the image in
+/// parallel.
+///
+/// Operations only start the work; the game thread can go on while the workers run, and has to
+/// call `wait()` before touching the buffers again. Starting an operation waits for the previous
+/// one.
+pub struct WorkerPool {
+    senders: Vec<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    done_receiver: Receiver<()>,
+
+    /// Number of jobs sent to the workers and not finished yet.
+    pending: usize,
+}
+
+impl WorkerPool {
+    /// Starts one worker thread per processor.
+    pub fn new() -> Self {
+        let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        let count = (count.max(1) as usize).min(MAX_THREADS);
+
+        let (done_sender, done_receiver) = channel();
+        let mut senders = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let (sender, receiver) = channel::<Job>();
+            let done_sender = done_sender.clone();
+
+            threads.push(thread::spawn(move || {
+                                           while let Ok(job) = receiver.recv() {
+                                               job();
+                                               done_sender.send(()).unwrap();
+                                           }
+                                       }));
+            senders.push(sender);
+        }
+
+        Self { senders,
+               threads,
+               done_receiver,
+               pending: 0 }
+    }
+
+    /// Waits for the running operation to finish.
+    pub fn wait(&mut self) {
+        while self.pending > 0 {
+            self.done_receiver.recv().unwrap();
+            self.pending -= 1;
+        }
+    }
+
+    /// Starts adding the image multiplied by the weight into the buffer.
+    ///
+    /// # Safety
+    ///
+    /// `buf` and `image` must stay alive and unchanged, and `buf` must not be accessed, until
+    /// `wait()` returns.
+    pub(super) unsafe fn weighted_image_add(&mut self,
+                                            buf: &mut [f32],
+                                            image: &[u8],
+                                            weight: f32,
+                                            transfer: Transfer) {
+        assert_eq!(buf.len(), image.len());
+
+        let buf_ptr = SendPtr(buf.as_mut_ptr());
+        let image_ptr = SendPtr(image.as_ptr() as *mut u8);
+
+        self.run(buf.len(), move |start, len| {
+                     let buf = slice_mut(buf_ptr, start, len);
+                     let image = slice(image_ptr, start, len);
+                     weighted_image_add(buf, image, weight, &transfer);
+                 });
+    }
+
+    /// Adds the image multiplied by the weight to the buffer, writing the result into `dst`.
+    pub(super) fn weighted_image_add_to(&mut self,
+                                        buf: &[f32],
+                                        image: &[u8],
+                                        dst: &mut [u8],
+                                        weight: f32,
+                                        transfer: Transfer) {
+        assert_eq!(buf.len(), image.len());
+        assert_eq!(buf.len(), dst.len());
+
+        let buf_ptr = SendPtr(buf.as_ptr() as *mut f32);
+        let image_ptr = SendPtr(image.as_ptr() as *mut u8);
+        let dst_ptr = SendPtr(dst.as_mut_ptr());
+
+        // Wait right away since the borrows end with this function.
+        unsafe {
+            self.run(buf.len(), move |start, len| {
+                         let buf = slice(buf_ptr, start, len);
+                         let image = slice(image_ptr, start, len);
+                         let dst = slice_mut(dst_ptr, start, len);
+                         weighted_image_add_to(buf, image, dst, weight, &transfer);
+                     });
+        }
+
+        self.wait();
+    }
+
+    /// Starts filling the buffer with zeros.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay alive and must not be accessed until `wait()` returns.
+    pub unsafe fn fill_with_black(&mut self, buf: &mut [f32]) {
+        let buf_ptr = SendPtr(buf.as_mut_ptr());
+
+        self.run(buf.len(), move |start, len| {
+                     fill_with_black(slice_mut(buf_ptr, start, len));
+                 });
+    }
+
+    /// Splits `0..len` into a band per worker and starts running `f(start, len)` for every band.
+    unsafe fn run<F>(&mut self, len: usize, f: F)
+        where F: Fn(usize, usize) + Send + Copy + 'static
+    {
+        self.wait();
+
+        for ((start, band_len), sender) in bands(len, self.senders.len()).zip(&self.senders) {
+            sender.send(Box::new(move || f(start, band_len))).unwrap();
+            self.pending += 1;
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // The jobs can reference buffers dropped right after the pool.
+        self.wait();
+
+        // Closing the channels stops the workers.
+        self.senders.clear();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Returns a shared slice of the data behind the pointer, for the inputs of the operations.
+#[inline]
+unsafe fn slice<'a, T>(ptr: SendPtr<T>, start: usize, len: usize) -> &'a [T] {
+    ::std::slice::from_raw_parts(ptr.0.add(start), len)
+}
+
+/// Returns a mutable slice of the data behind the pointer, for the outputs of the operations.
+#[inline]
+unsafe fn slice_mut<'a, T>(ptr: SendPtr<T>, start: usize, len: usize) -> &'a mut [T] {
+    ::std::slice::from_raw_parts_mut(ptr.0.add(start), len)
+}
+
+/// Splits `0..len` into at most `count` non-empty bands of nearly equal length, returning their
+/// starts and lengths.
+fn bands(len: usize, count: usize) -> impl Iterator<Item = (usize, usize)> {
+    let band_len = (len + count - 1) / count;
+
+    (0..count).map(move |i| i * band_len)
+              .take_while(move |&start| start < len)
+              .map(move |start| (start, band_len.min(len - start)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bands_test() {
+        assert_eq!(bands(10, 3).collect::<Vec<_>>(), vec![(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(bands(2, 4).collect::<Vec<_>>(), vec![(0, 1), (1, 1)]);
+        assert_eq!(bands(0, 4).count(), 0);
+    }
+
+    #[test]
+    fn weighted_image_add_test() {
+        let mut pool = WorkerPool::new();
+
+        let mut buf = vec![1f32; 1001];
+        let image = (0..1001).map(|x| (x % 256) as u8).collect::<Vec<_>>();
+
+        unsafe {
+            pool.weighted_image_add(&mut buf, &image, 0.5, Transfer::new(false));
+        }
+        pool.wait();
+
+        for (i, &x) in buf.iter().enumerate() {
+            assert_eq!(x, 1f32 + (i % 256) as f32 * 0.5);
+        }
+
+        let mut dst = vec![0u8; 1001];
+        pool.weighted_image_add_to(&buf, &image, &mut dst, 0.5, Transfer::new(false));
+        assert_eq!(dst[100], 101);
+
+        unsafe {
+            pool.fill_with_black(&mut buf);
+        }
+        pool.wait();
+        assert!(buf.iter().all(|&x| x == 0f32));
+    }
+}
@@ -0,0 +1,530 @@
+use failure::{bail, err_msg, Error, ResultExt};
+use ffmpeg::frame::Video as VideoFrame;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// The first line of a hash log.
+const HEADER: &str = "# hl-capture hash log v1";
+
+/// Streaming implementation of the 64-bit xxHash.
+pub struct XxHash64 {
+    seed: u64,
+    total_len: u64,
+    v: [u64; 4],
+    buffer: [u8; 32],
+    buffer_len: usize,
+}
+
+/// Kind of the hashed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Video,
+    Audio,
+}
+
+/// A single hash log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    stream: Stream,
+    index: u64,
+    pts: i64,
+    hash: u64,
+}
+
+/// Reference log being compared against.
+struct Reference {
+    video: VecDeque<Entry>,
+    audio: VecDeque<Entry>,
+
+    /// Whether a divergence was already reported for the video and for the audio.
+    video_diverged: bool,
+    audio_diverged: bool,
+}
+
+/// Writes hashes of the output frames and audio chunks to a file and compares them against a
+/// reference log.
+///
+/// The video frames are hashed as they arrive at the capture thread, cropped with `cap_crop`. When
+/// the frames are processed with OpenCL they are also already scaled to `cap_output_resolution`,
+/// watermarked and converted into the encoder pixel format at that point. Otherwise they are
+/// still RGB, and the watermark and the scaling come after the hashing. The input display, the
+/// text overlay, the fades and `cap_video_filter` always come after the hashing. So the logs can
+/// only be compared when they were captured with the same crop, output resolution, pixel format
+/// and watermark, with or without OpenCL in both cases.
+///
+/// The audio is hashed as captured, before the time stretching, the offset, the loudness
+/// normalization, the limiter, the fades and `cap_audio_filter`.
+pub struct HashLog {
+    writer: Option<BufWriter<File>>,
+    reference: Option<Reference>,
+
+    video_index: u64,
+    video_pts: i64,
+    audio_index: u64,
+    audio_pts: i64,
+}
+
+#[inline]
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+       .rotate_left(31)
+       .wrapping_mul(PRIME64_1)
+}
+
+#[inline]
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val)).wrapping_mul(PRIME64_1)
+                         .wrapping_add(PRIME64_4)
+}
+
+#[inline]
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+#[inline]
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+impl XxHash64 {
+    #[inline]
+    pub fn new(seed: u64) -> Self {
+        Self { seed,
+               total_len: 0,
+               v: [seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                   seed.wrapping_add(PRIME64_2),
+                   seed,
+                   seed.wrapping_sub(PRIME64_1)],
+               buffer: [0; 32],
+               buffer_len: 0 }
+    }
+
+    /// Processes one 32-byte stripe.
+    #[inline]
+    fn process_stripe(v: &mut [u64; 4], stripe: &[u8]) {
+        for (i, v) in v.iter_mut().enumerate() {
+            *v = round(*v, read_u64(&stripe[i * 8..]));
+        }
+    }
+
+    /// Adds the bytes to the hash.
+    pub fn write(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+
+        if self.buffer_len > 0 {
+            let to_copy = (32 - self.buffer_len).min(bytes.len());
+            self.buffer[self.buffer_len..self.buffer_len + to_copy]
+                .copy_from_slice(&bytes[..to_copy]);
+            self.buffer_len += to_copy;
+            bytes = &bytes[to_copy..];
+
+            if self.buffer_len < 32 {
+                return;
+            }
+
+            Self::process_stripe(&mut self.v, &self.buffer);
+            self.buffer_len = 0;
+        }
+
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in &mut stripes {
+            Self::process_stripe(&mut self.v, stripe);
+        }
+
+        let remainder = stripes.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffer_len = remainder.len();
+    }
+
+    /// Returns the hash of all bytes written so far.
+    pub fn finish(&self) -> u64 {
+        let mut hash = if self.total_len >= 32 {
+            let [v1, v2, v3, v4] = self.v;
+
+            let mut hash = v1.rotate_left(1)
+                             .wrapping_add(v2.rotate_left(7))
+                             .wrapping_add(v3.rotate_left(12))
+                             .wrapping_add(v4.rotate_left(18));
+            hash = merge_round(hash, v1);
+            hash = merge_round(hash, v2);
+            hash = merge_round(hash, v3);
+            merge_round(hash, v4)
+        } else {
+            self.seed.wrapping_add(PRIME64_5)
+        };
+
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut tail = &self.buffer[..self.buffer_len];
+
+        while tail.len() >= 8 {
+            hash ^= round(0, read_u64(tail));
+            hash = hash.rotate_left(27)
+                       .wrapping_mul(PRIME64_1)
+                       .wrapping_add(PRIME64_4);
+            tail = &tail[8..];
+        }
+
+        if tail.len() >= 4 {
+            hash ^= u64::from(read_u32(tail)).wrapping_mul(PRIME64_1);
+            hash = hash.rotate_left(23)
+                       .wrapping_mul(PRIME64_2)
+                       .wrapping_add(PRIME64_3);
+            tail = &tail[4..];
+        }
+
+        for &byte in tail {
+            hash ^= u64::from(byte).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^= hash >> 32;
+
+        hash
+    }
+}
+
+impl fmt::Display for Stream {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Video => write!(f, "video"),
+            Stream::Audio => write!(f, "audio"),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,
+               "{} {} {} {:016x}",
+               self.stream, self.index, self.pts, self.hash)
+    }
+}
+
+impl Entry {
+    /// Parses a hash log line.
+    fn parse(line: &str) -> Result<Self> {
+        let mut split = line.split_whitespace();
+
+        let stream = match split.next() {
+            Some("video") => Stream::Video,
+            Some("audio") => Stream::Audio,
+            _ => bail!("unknown stream type"),
+        };
+
+        let index = split.next()
+                         .and_then(|s| s.parse().ok())
+                         .ok_or_else(|| err_msg("invalid index"))?;
+        let pts = split.next()
+                       .and_then(|s| s.parse().ok())
+                       .ok_or_else(|| err_msg("invalid pts"))?;
+        let hash = split.next()
+                        .and_then(|s| u64::from_str_radix(s, 16).ok())
+                        .ok_or_else(|| err_msg("invalid hash"))?;
+
+        Ok(Self { stream,
+                  index,
+                  pts,
+                  hash })
+    }
+}
+
+impl Reference {
+    fn open(filename: &str) -> Result<Self> {
+        let file = File::open(filename).context("could not open the reference hash log")?;
+
+        let mut video = VecDeque::new();
+        let mut audio = VecDeque::new();
+
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("could not read the reference hash log")?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = Entry::parse(line).with_context(|_| {
+                                               format!("invalid line {} in the reference hash log",
+                                                       number + 1)
+                                           })?;
+
+            match entry.stream {
+                Stream::Video => video.push_back(entry),
+                Stream::Audio => audio.push_back(entry),
+            }
+        }
+
+        Ok(Self { video,
+                  audio,
+                  video_diverged: false,
+                  audio_diverged: false })
+    }
+
+    /// Compares the entry against the reference, returning a message for the first divergence.
+    fn compare(&mut self, entry: &Entry) -> Option<String> {
+        let (queue, diverged) = match entry.stream {
+            Stream::Video => (&mut self.video, &mut self.video_diverged),
+            Stream::Audio => (&mut self.audio, &mut self.audio_diverged),
+        };
+
+        let reference = queue.pop_front();
+
+        if *diverged {
+            return None;
+        }
+
+        let kind = match entry.stream {
+            Stream::Video => "frame",
+            Stream::Audio => "chunk",
+        };
+
+        match reference {
+            Some(reference) if reference == *entry => None,
+            Some(reference) => {
+                *diverged = true;
+                Some(format!("Hash log: {} {} {} (pts {}) diverges from the reference \
+                              ({} {} with pts {}).\n",
+                             entry.stream,
+                             kind,
+                             entry.index,
+                             entry.pts,
+                             kind,
+                             reference.index,
+                             reference.pts))
+            }
+            None => {
+                *diverged = true;
+                Some(format!("Hash log: {} {} {} (pts {}) is past the end of the reference.\n",
+                             entry.stream, kind, entry.index, entry.pts))
+            }
+        }
+    }
+}
+
+impl HashLog {
+    /// Opens the log and reference files.
+    ///
+    /// Returns `None` if both file names are empty.
+    pub fn new(log_filename: &str, reference_filename: &str) -> Result<Option<Self>> {
+        if log_filename.is_empty() && reference_filename.is_empty() {
+            return Ok(None);
+        }
+
+        let reference = if reference_filename.is_empty() {
+            None
+        } else {
+            Some(Reference::open(reference_filename)?)
+        };
+
+        let writer = if log_filename.is_empty() {
+            None
+        } else {
+            let mut writer = BufWriter::new(File::create(log_filename).context("could not \
+                                                                                create the \
+                                                                                hash log")?);
+            writeln!(writer, "{}", HEADER).context("could not write to the hash log")?;
+            Some(writer)
+        };
+
+        Ok(Some(Self { writer,
+                       reference,
+                       video_index: 0,
+                       video_pts: 0,
+                       audio_index: 0,
+                       audio_pts: 0 }))
+    }
+
+    /// Writes and compares one entry.
+    fn add(&mut self, entry: Entry) -> Result<Option<String>> {
+        if let Some(ref mut writer) = self.writer {
+            writeln!(writer, "{}", entry).context("could not write to the hash log")?;
+        }
+
+        Ok(self.reference.as_mut().and_then(|r| r.compare(&entry)))
+    }
+
+    /// Hashes the video frame which is output the given number of times.
+    ///
+    /// This should be the frame as it arrived at the capture thread, see the `HashLog`
+    /// documentation. `pts` is the frame timestamp in `VFR_TIME_BASE` units for the variable frame
+    /// rate capture, otherwise the logged timestamps count the output frames.
+    ///
+    /// Returns a message if this frame diverges from the reference.
+    pub fn video_frame(&mut self,
+                       frame: &VideoFrame,
+                       times: usize,
+                       pts: Option<i64>)
+                       -> Result<Option<String>> {
+        let hash = hash_frame(frame);
+        let mut message = None;
+
+        if let Some(pts) = pts {
+            self.video_pts = pts;
+        }
+
+        for _ in 0..times {
+            let entry = Entry { stream: Stream::Video,
+                                index: self.video_index,
+                                pts: self.video_pts,
+                                hash };
+            self.video_index += 1;
+            self.video_pts += 1;
+
+            message = message.or(self.add(entry)?);
+        }
+
+        Ok(message)
+    }
+
+    /// Hashes the chunk of 16-bit stereo audio.
+    ///
+    /// Returns a message if this chunk diverges from the reference.
+    pub fn audio_chunk(&mut self, samples: &[(i16, i16)]) -> Result<Option<String>> {
+        let mut hasher = XxHash64::new(0);
+        for &(l, r) in samples {
+            hasher.write(&l.to_le_bytes());
+            hasher.write(&r.to_le_bytes());
+        }
+
+        self.add_audio_chunk(hasher.finish(), samples.len())
+    }
+
+    /// Hashes the chunk of float stereo audio.
+    ///
+    /// Returns a message if this chunk diverges from the reference.
+    pub fn audio_chunk_float(&mut self, samples: &[(f32, f32)]) -> Result<Option<String>> {
+        let mut hasher = XxHash64::new(0);
+        for &(l, r) in samples {
+            hasher.write(&l.to_bits().to_le_bytes());
+            hasher.write(&r.to_bits().to_le_bytes());
+        }
+
+        self.add_audio_chunk(hasher.finish(), samples.len())
+    }
+
+    fn add_audio_chunk(&mut self, hash: u64, samples: usize) -> Result<Option<String>> {
+        let entry = Entry { stream: Stream::Audio,
+                            index: self.audio_index,
+                            pts: self.audio_pts,
+                            hash };
+        self.audio_index += 1;
+        self.audio_pts += samples as i64;
+
+        self.add(entry)
+    }
+
+    /// Flushes the log and checks that the whole reference was matched.
+    ///
+    /// Returns a message describing the comparison result.
+    pub fn finish(&mut self) -> Result<Option<String>> {
+        if let Some(ref mut writer) = self.writer {
+            writer.flush().context("could not write to the hash log")?;
+        }
+
+        Ok(self.reference.as_ref().map(|r| {
+            if r.video_diverged || r.audio_diverged {
+                "Hash log: the capture diverged from the reference.\n".to_string()
+            } else if !r.video.is_empty() || !r.audio.is_empty() {
+                format!("Hash log: the capture matched the reference, but ended {} video \
+                         frames and {} audio chunks early.\n",
+                        r.video.len(),
+                        r.audio.len())
+            } else {
+                "Hash log: the capture matched the reference.\n".to_string()
+            }
+        }))
+    }
+}
+
+/// Hashes the visible pixels of the frame, ignoring the stride padding.
+fn hash_frame(frame: &VideoFrame) -> u64 {
+    let mut hasher = XxHash64::new(0);
+
+    let components_per_plane = if frame.planes() == 1 {
+        frame.format().descriptor().unwrap().nb_components()
+    } else {
+        1
+    } as usize;
+
+    for i in 0..frame.planes() {
+        let stride = frame.stride(i);
+        let length = frame.plane_width(i) as usize * components_per_plane;
+        let data = frame.data(i);
+
+        for y in 0..frame.plane_height(i) as usize {
+            hasher.write(&data[y * stride..y * stride + length]);
+        }
+    }
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn xxh64(bytes: &[u8]) -> u64 {
+        let mut hasher = XxHash64::new(0);
+        hasher.write(bytes);
+        hasher.finish()
+    }
+
+    #[test]
+    fn xxh64_short_test() {
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"a"), 0xD24E_C4F1_A98C_6E5B);
+        assert_eq!(xxh64(b"abc"), 0x44BC_2CF5_AD77_0999);
+    }
+
+    #[test]
+    fn xxh64_streaming_test() {
+        let data = (0..1000u32).map(|x| (x * 7 + 3) as u8).collect::<Vec<_>>();
+
+        let mut hasher = XxHash64::new(0);
+        for chunk in data.chunks(13) {
+            hasher.write(chunk);
+        }
+
+        assert_eq!(hasher.finish(), xxh64(&data));
+    }
+
+    #[test]
+    fn entry_roundtrip_test() {
+        let entry = Entry { stream: Stream::Audio,
+                            index: 12,
+                            pts: 4410,
+                            hash: 0x0123_4567_89AB_CDEF };
+
+        assert_eq!(Entry::parse(&entry.to_string()).unwrap(), entry);
+    }
+
+    #[test]
+    fn entry_parse_invalid_test() {
+        assert!(Entry::parse("subtitle 0 0 0").is_err());
+        assert!(Entry::parse("video 0 0").is_err());
+        assert!(Entry::parse("video 0 0 xyz").is_err());
+    }
+}
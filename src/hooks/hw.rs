@@ -17,14 +17,16 @@ use std::result;
 use std::slice;
 
 use crate::capture::{self, GameThreadEvent};
+use crate::color::{ColorRange, ColorSpace};
 use crate::command;
 use crate::cvar;
 use crate::dl;
-use crate::encode;
+use crate::encode::{self, ScalingAlgorithm};
 use crate::engine::{Engine, MainThreadMarker};
 use crate::fps_converter::*;
 use crate::sdl;
 use crate::utils::MaybeUnavailable;
+use crate::watermark::Watermark;
 
 use crate::utils::format_error;
 
@@ -51,6 +53,7 @@ struct Functions {
     Con_Printf: unsafe extern "C" fn(*const c_char),
     Con_ToggleConsole_f: unsafe extern "C" fn(),
     Cvar_RegisterVariable: unsafe extern "C" fn(*mut cvar::cvar_t),
+    Cvar_Set: unsafe extern "C" fn(*const c_char, *const c_char),
     GL_SetMode: unsafe extern "C" fn(c_int,
                                      *mut c_void,
                                      *mut c_void,
@@ -69,7 +72,9 @@ struct Functions {
 
 /// Pointers to all used hw variables.
 struct Pointers {
+    cl_enginefuncs: *mut cl_enginefunc_t,
     cls: *mut client_static_t,
+    com_gamedir: *mut c_char,
     game: *mut *mut CGame,
     host_frametime: *mut c_double,
     paintbuffer: *mut portable_samplepair_t, // [1026]
@@ -93,10 +98,13 @@ pub enum SoundCaptureMode {
 /// Wrapper for the OpenCL image created from an OpenGL texture.
 pub struct OclGlTexture {
     image: ocl::Image<u8>,
+
+    /// The part of the image to capture, in the image coordinates.
+    region: capture::Region,
 }
 
 pub enum FrameCapture {
-    OpenGL(fn(MainThreadMarker<'_>, (u32, u32), &mut [u8])),
+    OpenGL(fn(MainThreadMarker<'_>, capture::Region, &mut [u8])),
     OpenCL(OclGlTexture),
 }
 
@@ -104,7 +112,8 @@ impl OclGlTexture {
     fn new(_: MainThreadMarker<'_>,
            texture: GLuint,
            queue: ocl::Queue,
-           dims: ocl::SpatialDims)
+           dims: ocl::SpatialDims,
+           region: capture::Region)
            -> Self {
         unsafe {
             gl::Finish();
@@ -129,7 +138,14 @@ impl OclGlTexture {
 
         image.cmd().gl_acquire().enq().expect("gl_acquire()");
 
-        Self { image }
+        Self { image,
+               region }
+    }
+
+    /// Returns the part of the image to capture.
+    #[inline]
+    pub fn region(&self) -> capture::Region {
+        self.region
     }
 }
 
@@ -164,6 +180,15 @@ struct FBO_Container_t {
     Tex: GLuint,
 }
 
+/// The beginning of the engine functions table given to the client dll.
+#[repr(C)]
+struct cl_enginefunc_t {
+    stuff: [*mut c_void; 54],
+    GetClientTime: unsafe extern "C" fn() -> c_float,
+    stuff_2: [*mut c_void; 19],
+    pfnGetLevelName: unsafe extern "C" fn() -> *const c_char,
+}
+
 #[repr(C)]
 struct client_static_t {
     stuff: [u8; 0x4060],
@@ -232,13 +257,15 @@ pub unsafe extern "C" fn RunListenServer(instance: *mut c_void,
                                     launcherFactory,
                                     filesystemFactory);
 
-    if let Some(FPSConverters::Sampling(mut sampling_conv)) = engine.data_mut().fps_converter.take()
-    {
-        sampling_conv.backup_and_free_ocl_data(&mut engine);
-        engine.data_mut().fps_converter = Some(FPSConverters::Sampling(sampling_conv));
+    if let Some(mut fps_converter) = engine.data_mut().fps_converter.take() {
+        fps_converter.backup_and_free_ocl_data(&mut engine);
+        engine.data_mut().fps_converter = Some(fps_converter);
     }
 
     engine.data_mut().ocl_yuv_buffers.reset();
+    engine.data_mut().ocl_scaled_image = None;
+    engine.data_mut().ocl_watermark = None;
+    engine.data_mut().ocl_watermarked_image = None;
     engine.data_mut().pro_que.reset();
 
     // Since hw.so is getting unloaded, reset all pointers.
@@ -297,7 +324,7 @@ pub unsafe extern "C" fn GL_SetMode(mainwindow: c_int,
 /// Calculates the frame time and limits the FPS.
 #[no_mangle]
 pub unsafe extern "C" fn Host_FilterTime(time: c_float) -> c_int {
-    let engine = Engine::new();
+    let mut engine = Engine::new();
 
     let old_realtime = *ptr!(realtime);
 
@@ -306,8 +333,20 @@ pub unsafe extern "C" fn Host_FilterTime(time: c_float) -> c_int {
     // TODO: this will NOT set the frametime on the first frame of capture / demo playback and WILL
     // set the frametime on the first frame of not capturing. This needs to be fixed somehow.
     if capture::is_capturing() && (*ptr!(cls)).demoplayback != 0 {
-        let params = capture::get_capture_parameters(&engine);
-        let frametime = params.sampling_time_base.unwrap_or(params.time_base).into();
+        let (mut frametime, scale) = {
+            let params = capture::get_capture_parameters(&engine);
+            let frametime: f64 = params.sampling_time_base.unwrap_or(params.time_base).into();
+            let scale = params.timescale
+                              .as_ref()
+                              .map(|t| t.scale_at(get_client_time(engine.marker().1)));
+            (frametime, scale)
+        };
+
+        // The game time is scaled while the converter still sees the unscaled frametime.
+        if let Some(scale) = scale {
+            engine.data_mut().timescale = scale;
+            frametime *= scale;
+        }
 
         *ptr!(host_frametime) = frametime;
         *ptr!(realtime) = old_realtime + frametime;
@@ -321,6 +360,23 @@ pub unsafe extern "C" fn Host_FilterTime(time: c_float) -> c_int {
 #[no_mangle]
 pub unsafe extern "C" fn Key_Event(key: c_int, down: c_int) {
     let mut engine = Engine::new();
+
+    let is_down = down != 0;
+    engine.data_mut().input_state.key_event(key, is_down);
+
+    if capture::is_capturing() {
+        let time = engine.data().capture_time;
+        let result = engine.data_mut()
+                           .input_log
+                           .as_mut()
+                           .map(|log| log.key_event(time, key, is_down));
+
+        if let Some(Err(e)) = result {
+            engine.data_mut().input_log = None;
+            con_print(&format_error(&e.context("disabling the input log").into()));
+        }
+    }
+
     engine.data_mut().inside_key_event = true;
     real!(Key_Event)(key, down);
     engine.data_mut().inside_key_event = false;
@@ -356,7 +412,7 @@ pub unsafe extern "C" fn Memory_Init(buf: *mut c_void, size: c_int) {
 pub unsafe extern "C" fn S_PaintChannels(endtime: c_int) {
     let mut engine = Engine::new();
 
-    if !capture::is_capturing() {
+    if !capture::is_capturing() || !capture::get_capture_parameters(&engine).audio {
         engine.data_mut().capture_sound = false;
         real!(S_PaintChannels)(endtime);
         return;
@@ -377,9 +433,15 @@ pub unsafe extern "C" fn S_PaintChannels(endtime: c_int) {
 
         engine.data_mut().sound_remainder = samples - samples_rounded;
 
+        // The timelapse passes several seconds of game time per second of output without
+        // scaling the game time itself.
+        let timescale = capture::get_capture_parameters(&engine).timelapse
+                                                                .unwrap_or(engine.data().timescale);
+
         AUDIO_BUFFER.with(|b| {
                         let mut buf = capture::get_audio_buffer(engine.marker().1);
-                        buf.data_mut().clear();
+                        buf.clear();
+                        buf.set_timescale(timescale);
                         *b.borrow_mut() = Some(buf);
                     });
 
@@ -400,25 +462,49 @@ pub unsafe extern "C" fn S_TransferStereo16(end: c_int) {
     if engine.data().capture_sound {
         AUDIO_BUFFER.with(|b| {
                         let mut buf = b.borrow_mut();
-                        let buf = buf.as_mut().unwrap().data_mut();
+                        let buf = buf.as_mut().unwrap();
 
                         let paintedtime = *ptr!(paintedtime);
                         let paintbuffer = slice::from_raw_parts_mut(ptr!(paintbuffer), 1026);
+                        let samples = paintbuffer.iter().take((end - paintedtime) as usize * 2);
 
                         let engine = Engine::new();
-                        let volume =
-                            (capture::get_capture_parameters(&engine).volume * 256f32) as i32;
+                        let parameters = capture::get_capture_parameters(&engine);
+
+                        if parameters.audio_float {
+                            // Keep the values above full scale, applying the volume as a float
+                            // gain.
+                            let gain = parameters.volume / 32768f32;
+                            let buf = buf.float_data_mut();
+
+                            for sample in samples {
+                                buf.push((sample.left as f32 * gain, sample.right as f32 * gain));
+                            }
+
+                            return;
+                        }
+
+                        let volume = (parameters.volume * 256f32) as i32;
+                        let mut clipped = 0;
+
+                        for sample in samples {
+                            let l = (sample.left * volume) >> 8;
+                            let r = (sample.right * volume) >> 8;
+
+                            for &value in &[l, r] {
+                                if value < -32768 || value > 32767 {
+                                    clipped += 1;
+                                }
+                            }
 
-                        for sample in paintbuffer.iter().take((end - paintedtime) as usize * 2) {
                             // Clamping as done in Snd_WriteLinearBlastStereo16().
-                            let l16 = cmp::min(32767, cmp::max(-32768, (sample.left * volume) >> 8))
-                                      as i16;
-                            let r16 = cmp::min(32767,
-                                               cmp::max(-32768, (sample.right * volume) >> 8))
-                                      as i16;
+                            let l16 = cmp::min(32767, cmp::max(-32768, l)) as i16;
+                            let r16 = cmp::min(32767, cmp::max(-32768, r)) as i16;
 
-                            buf.push((l16, r16));
+                            buf.data_mut().push((l16, r16));
                         }
+
+                        buf.add_clipped(clipped);
                     });
     }
 
@@ -441,7 +527,10 @@ pub unsafe extern "C" fn Sys_VID_FlipScreen() {
     }
 
     // If the encoding just started, wait for the pixel format.
-    while capture::is_capturing() && engine.data().encoder_pixel_format.is_none() {
+    while capture::is_capturing()
+          && capture::get_capture_parameters(&engine).video
+          && engine.data().encoder_pixel_format.is_none()
+    {
         match capture::get_event_block(engine.marker().1) {
             GameThreadEvent::Message(msg) => con_print(&msg),
             GameThreadEvent::EncoderPixelFormat(fmt) => {
@@ -454,17 +543,16 @@ pub unsafe extern "C" fn Sys_VID_FlipScreen() {
         // Always capture sound.
         engine.data_mut().capture_sound = true;
 
-        match engine.data_mut().fps_converter.take().unwrap() {
-            FPSConverters::Simple(mut simple_conv) => {
-                simple_conv.time_passed(&mut engine, *ptr!(host_frametime), capture_frame);
-                engine.data_mut().fps_converter = Some(FPSConverters::Simple(simple_conv));
-            }
+        engine.data_mut().capture_time += *ptr!(host_frametime);
 
-            FPSConverters::Sampling(mut sampling_conv) => {
-                sampling_conv.time_passed(&mut engine, *ptr!(host_frametime), capture_frame);
-                engine.data_mut().fps_converter = Some(FPSConverters::Sampling(sampling_conv));
-            }
+        // There's no FPS converter when the video is disabled.
+        if let Some(mut fps_converter) = engine.data_mut().fps_converter.take() {
+            fps_converter.time_passed(&mut engine, *ptr!(host_frametime), capture_frame);
+            engine.data_mut().fps_converter = Some(fps_converter);
         }
+    } else {
+        // The capture thread stops the capturing on errors, leaving the input log open.
+        capture::finish_input_log(&mut engine);
     }
 
     real!(Sys_VID_FlipScreen)();
@@ -502,6 +590,7 @@ fn refresh_pointers(_: MainThreadMarker<'_>) -> Result<()> {
                                      Con_Printf: find!(hw, "Con_Printf"),
                                      Con_ToggleConsole_f: find!(hw, "Con_ToggleConsole_f"),
                                      Cvar_RegisterVariable: find!(hw, "Cvar_RegisterVariable"),
+                                     Cvar_Set: find!(hw, "Cvar_Set"),
                                      GL_SetMode: find!(hw, "GL_SetMode"),
                                      Host_FilterTime: find!(hw, "Host_FilterTime"),
                                      Key_Event: find!(hw, "Key_Event"),
@@ -515,7 +604,9 @@ fn refresh_pointers(_: MainThreadMarker<'_>) -> Result<()> {
         ),
                                      VideoMode_IsWindowed: find!(hw, "VideoMode_IsWindowed"), });
 
-        POINTERS = Some(Pointers { cls: find!(hw, "cls"),
+        POINTERS = Some(Pointers { cl_enginefuncs: find!(hw, "cl_enginefuncs"),
+                                   cls: find!(hw, "cls"),
+                                   com_gamedir: find!(hw, "com_gamedir"),
                                    game: find!(hw, "game"),
                                    host_frametime: find!(hw, "host_frametime"),
                                    paintbuffer: find!(hw, "paintbuffer"),
@@ -573,6 +664,15 @@ pub unsafe fn register_variable(cvar: &mut cvar::cvar_t) {
     real!(Cvar_RegisterVariable)(cvar);
 }
 
+/// Sets the value of a console variable.
+///
+/// # Safety
+/// Unsafe because this function should only be called from the main game thread.
+#[inline]
+pub unsafe fn cvar_set(name: &CStr, value: &CStr) {
+    real!(Cvar_Set)(name.as_ptr(), value.as_ptr());
+}
+
 /// Prints the given string to the game console.
 ///
 /// `string` must not contain null bytes.
@@ -607,6 +707,46 @@ pub unsafe fn cmd_argv(index: u32) -> String {
     CStr::from_ptr(arg).to_string_lossy().into_owned()
 }
 
+/// Returns the game (mod) directory.
+pub fn get_game_dir(_: MainThreadMarker<'_>) -> String {
+    unsafe { CStr::from_ptr(ptr!(com_gamedir)) }.to_string_lossy()
+                                                .into_owned()
+}
+
+/// Returns the current client time, which is the demo time during demo playback.
+pub fn get_client_time(_: MainThreadMarker<'_>) -> f64 {
+    f64::from(unsafe { ((*ptr!(cl_enginefuncs)).GetClientTime)() })
+}
+
+/// Returns the current map name without the path and the extension.
+pub fn get_map_name(_: MainThreadMarker<'_>) -> String {
+    let level_name = unsafe { CStr::from_ptr(((*ptr!(cl_enginefuncs)).pfnGetLevelName)()) };
+    let level_name = level_name.to_string_lossy();
+
+    // The level name looks like "maps/c1a0.bsp".
+    level_name.rsplit('/')
+              .next()
+              .unwrap_or("")
+              .trim_end_matches(".bsp")
+              .to_owned()
+}
+
+/// Returns the sample rate, the number of channels and the bits per sample of the engine sound
+/// output, or `None` if the sound is not initialized.
+pub fn get_sound_output(_: MainThreadMarker<'_>) -> Option<(u32, u32, u32)> {
+    let shm = unsafe { *ptr!(shm) };
+    if shm.is_null() {
+        return None;
+    }
+
+    let shm = unsafe { &*shm };
+    if shm.speed <= 0 {
+        return None;
+    }
+
+    Some((shm.speed as u32, shm.channels.max(0) as u32, shm.samplebits.max(0) as u32))
+}
+
 /// Returns the current game resolution.
 pub fn get_resolution(_: MainThreadMarker<'_>) -> (u32, u32) {
     let mut width;
@@ -662,7 +802,9 @@ pub fn get_pro_que(engine: &mut Engine) -> Option<&mut ocl::ProQue> {
                                                                ocl::Program::builder();
                                                            builder
                             .src(include_str!("../../cl_src/color_conversion.cl"))
-                            .src(include_str!("../../cl_src/sampling.cl"));
+                            .src(include_str!("../../cl_src/sampling.cl"))
+                            .src(include_str!("../../cl_src/scaling.cl"))
+                            .src(include_str!("../../cl_src/watermark.cl"));
                                                            builder
                                                        })
                                                        .build()
@@ -767,6 +909,12 @@ fn get_yuv_buffers_and_pro_que(
 fn capture_frame(engine: &mut Engine) -> FrameCapture {
     let (engine, marker) = engine.marker_mut();
 
+    let (w, h) = get_resolution(marker);
+
+    // OpenGL textures have the origin in the bottom left corner.
+    let region = capture::get_capture_parameters(engine).capture_region
+                                                        .flip_vertically(h);
+
     let texture = unsafe { *ptr!(s_BackBufferFBO) }.Tex;
     let pro_que = if texture != 0 {
         get_pro_que(engine)
@@ -775,12 +923,11 @@ fn capture_frame(engine: &mut Engine) -> FrameCapture {
     };
 
     if let Some(pro_que) = pro_que {
-        let (w, h) = get_resolution(marker);
-
         FrameCapture::OpenCL(OclGlTexture::new(marker,
                                                texture,
                                                pro_que.queue().clone(),
-                                               (w, h).into()))
+                                               (w, h).into(),
+                                               region))
     } else {
         FrameCapture::OpenGL(read_pixels)
     }
@@ -789,17 +936,176 @@ fn capture_frame(engine: &mut Engine) -> FrameCapture {
 /// Gets the OpenCL RGB->YUV color conversion function name.
 fn ocl_color_conversion_func_name(target: format::Pixel) -> Option<&'static str> {
     match target {
-        format::Pixel::YUV420P => Some("rgb_to_yuv420_601_limited"),
-        format::Pixel::YUV444P => Some("rgb_to_yuv444_601_limited"),
+        format::Pixel::YUV420P => Some("rgb_to_yuv420"),
+        format::Pixel::YUV444P => Some("rgb_to_yuv444"),
         _ => None,
     }
 }
 
-/// Reads the given `ocl::Image` into the buffer.
+/// Gets the OpenCL scaling filter index.
+fn ocl_scaling_filter(algorithm: ScalingAlgorithm) -> u32 {
+    match algorithm {
+        ScalingAlgorithm::Bilinear => 0,
+        ScalingAlgorithm::Bicubic => 1,
+        ScalingAlgorithm::Lanczos => 2,
+        ScalingAlgorithm::Area => 3,
+    }
+}
+
+/// Takes the OpenCL image for the scaled frame out of the engine data, building a new one if
+/// needed.
+fn take_scaled_image(engine: &mut Engine, dims: (u32, u32)) -> ocl::Image<ocl::prm::Float> {
+    match engine.data_mut().ocl_scaled_image.take() {
+        Some(image) if *image.dims() == dims.into() => image,
+        _ => {
+            let pro_que = get_pro_que(engine).unwrap();
+            build_ocl_image(pro_que,
+                            ocl::MemFlags::new().read_write().host_no_access(),
+                            ocl::enums::ImageChannelDataType::Float,
+                            dims.into()).expect("building an OpenCL image")
+        }
+    }
+}
+
+/// Reads the given region of the `ocl::Image` into the buffer.
+///
+/// If the output resolution differs from the region size, the region is scaled on the GPU and
+/// the buffer resolution is changed to the output resolution.
 pub fn read_ocl_image_into_buf<T: ocl::OclPrm>(engine: &mut Engine,
                                                image: &ocl::Image<T>,
+                                               region: capture::Region,
                                                buf: &mut capture::VideoBuffer) {
+    let (output_resolution, scaling_algorithm) = {
+        let parameters = capture::get_capture_parameters(engine);
+        (parameters.output_resolution, parameters.scaling_algorithm)
+    };
+
+    if region.size() != output_resolution {
+        let scaled_image = take_scaled_image(engine, output_resolution);
+
+        {
+            let pro_que = get_pro_que(engine).unwrap();
+
+            let kernel = pro_que.kernel_builder("scale_image")
+                                .global_work_size(output_resolution)
+                                .arg(image)
+                                .arg(region.x)
+                                .arg(region.y)
+                                .arg(region.width)
+                                .arg(region.height)
+                                .arg(ocl_scaling_filter(scaling_algorithm))
+                                .arg(&scaled_image)
+                                .build()
+                                .unwrap();
+
+            unsafe {
+                kernel.enq().expect("kernel.enq()");
+            }
+        }
+
+        buf.set_resolution(output_resolution.0, output_resolution.1);
+        watermark_ocl_image_into_buf(engine,
+                                     &scaled_image,
+                                     capture::Region::whole(output_resolution),
+                                     buf);
+
+        engine.data_mut().ocl_scaled_image = Some(scaled_image);
+        return;
+    }
+
+    watermark_ocl_image_into_buf(engine, image, region, buf);
+}
+
+/// Uploads the watermark into an OpenCL image, reusing the one from the engine data if present.
+fn take_watermark_image(engine: &mut Engine, watermark: &Watermark) -> ocl::Image<u8> {
+    match engine.data_mut().ocl_watermark.take() {
+        Some(image) => image,
+        None => {
+            let pro_que = get_pro_que(engine).unwrap();
+            let image = build_ocl_image(pro_que,
+                                        ocl::MemFlags::new().read_only().host_write_only(),
+                                        ocl::enums::ImageChannelDataType::UnormInt8,
+                                        watermark.size().into());
+            let image = image.expect("building an OpenCL image");
+            image.write(watermark.data())
+                 .enq()
+                 .expect("watermark image.write()");
+            image
+        }
+    }
+}
+
+/// Takes the OpenCL image for the watermarked frame out of the engine data, building a new one
+/// if needed.
+fn take_watermarked_image(engine: &mut Engine, dims: (u32, u32)) -> ocl::Image<ocl::prm::Float> {
+    match engine.data_mut().ocl_watermarked_image.take() {
+        Some(image) if *image.dims() == dims.into() => image,
+        _ => {
+            let pro_que = get_pro_que(engine).unwrap();
+            build_ocl_image(pro_que,
+                            ocl::MemFlags::new().read_write().host_no_access(),
+                            ocl::enums::ImageChannelDataType::Float,
+                            dims.into()).expect("building an OpenCL image")
+        }
+    }
+}
+
+/// Blends the watermark onto the given region of the `ocl::Image` if it is enabled, then reads
+/// the result into the buffer.
+fn watermark_ocl_image_into_buf<T: ocl::OclPrm>(engine: &mut Engine,
+                                                image: &ocl::Image<T>,
+                                                region: capture::Region,
+                                                buf: &mut capture::VideoBuffer) {
+    let watermark = match engine.data().watermark {
+        Some(ref watermark) => watermark.clone(),
+        None => {
+            convert_ocl_image_into_buf(engine, image, region, buf);
+            return;
+        }
+    };
+
+    let watermark_image = take_watermark_image(engine, &watermark);
+    let watermarked_image = take_watermarked_image(engine, region.size());
+
+    {
+        let pro_que = get_pro_que(engine).unwrap();
+        let (watermark_x, watermark_y) = watermark.origin(region.size());
+
+        let kernel = pro_que.kernel_builder("blend_watermark")
+                            .global_work_size(region.size())
+                            .arg(image)
+                            .arg(region.x)
+                            .arg(region.y)
+                            .arg(&watermark_image)
+                            .arg(watermark_x)
+                            .arg(watermark_y)
+                            .arg(&watermarked_image)
+                            .build()
+                            .unwrap();
+
+        unsafe {
+            kernel.enq().expect("kernel.enq()");
+        }
+    }
+
+    buf.set_watermarked();
+    convert_ocl_image_into_buf(engine,
+                               &watermarked_image,
+                               capture::Region::whole(region.size()),
+                               buf);
+
+    engine.data_mut().ocl_watermark = Some(watermark_image);
+    engine.data_mut().ocl_watermarked_image = Some(watermarked_image);
+}
+
+/// Converts the given region of the `ocl::Image` into the encoder pixel format and reads it into
+/// the buffer.
+fn convert_ocl_image_into_buf<T: ocl::OclPrm>(engine: &mut Engine,
+                                              image: &ocl::Image<T>,
+                                              region: capture::Region,
+                                              buf: &mut capture::VideoBuffer) {
     let encoder_pixel_format = engine.data().encoder_pixel_format.unwrap();
+    let color_conversion = capture::get_capture_parameters(engine).color_conversion;
 
     if let Some(func_name) = ocl_color_conversion_func_name(encoder_pixel_format) {
         buf.set_format(encoder_pixel_format);
@@ -812,14 +1118,18 @@ pub fn read_ocl_image_into_buf<T: ocl::OclPrm>(engine: &mut Engine,
                                          frame.data(2).len()))
         {
             let kernel = pro_que.kernel_builder(func_name)
-                                .global_work_size(image.dims())
+                                .global_work_size(region.size())
                                 .arg(image)
+                                .arg(region.x)
+                                .arg(region.y)
                                 .arg(frame.stride(0))
                                 .arg(frame.stride(1))
                                 .arg(frame.stride(2))
                                 .arg(Y_buf)
                                 .arg(U_buf)
                                 .arg(V_buf)
+                                .arg((color_conversion.space == ColorSpace::BT709) as u32)
+                                .arg((color_conversion.range == ColorRange::Full) as u32)
                                 .build()
                                 .unwrap();
 
@@ -843,8 +1153,10 @@ pub fn read_ocl_image_into_buf<T: ocl::OclPrm>(engine: &mut Engine,
         build_ocl_buffer(pro_que, buf.as_mut_slice().len()).expect("OpenCL buffer build");
 
     let kernel = pro_que.kernel_builder("rgba_to_uint8_rgba_buffer")
-                        .global_work_size(image.dims())
+                        .global_work_size(region.size())
                         .arg(image)
+                        .arg(region.x)
+                        .arg(region.y)
                         .arg(&ocl_buffer)
                         .build()
                         .unwrap();
@@ -858,17 +1170,20 @@ pub fn read_ocl_image_into_buf<T: ocl::OclPrm>(engine: &mut Engine,
               .expect("buffer.read()");
 }
 
-/// Reads pixels into the buffer.
-fn read_pixels(_: MainThreadMarker<'_>, (w, h): (u32, u32), buf: &mut [u8]) {
+/// Reads pixels from the given region of the screen into the buffer.
+fn read_pixels(marker: MainThreadMarker<'_>, region: capture::Region, buf: &mut [u8]) {
+    // OpenGL has the origin in the bottom left corner.
+    let region = region.flip_vertically(get_resolution(marker).1);
+
     unsafe {
         // Our buffer expects 1-byte alignment.
         gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
 
         // Get the pixels!
-        gl::ReadPixels(0,
-                       0,
-                       w as GLsizei,
-                       h as GLsizei,
+        gl::ReadPixels(region.x as GLint,
+                       region.y as GLint,
+                       region.width as GLsizei,
+                       region.height as GLsizei,
                        gl::RGB,
                        gl::UNSIGNED_BYTE,
                        buf.as_mut_ptr() as _);
@@ -0,0 +1,195 @@
+use failure::{Error, ResultExt};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The first line of an input log.
+const HEADER: &str = "# hl-capture input log v1: <time> <key> <down|up>";
+
+/// Engine key numbers, from keydefs.h.
+const K_SPACE: i32 = 32;
+const K_CTRL: i32 = 133;
+const K_SHIFT: i32 = 134;
+const K_MOUSE1: i32 = 241;
+const K_MOUSE2: i32 = 242;
+const K_MOUSE3: i32 = 243;
+
+/// Keys shown on the input display.
+///
+/// These are the keys from the default Half-Life bindings; custom bindings are not taken into
+/// account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Forward,
+    Left,
+    Back,
+    Right,
+    Jump,
+    Duck,
+    Attack,
+    Attack2,
+}
+
+/// Pressed state of the displayed keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputState {
+    pressed: u8,
+}
+
+/// Writes the key state changes during capturing to a file.
+pub struct InputLog {
+    writer: BufWriter<File>,
+}
+
+impl Key {
+    /// All displayed keys.
+    pub const ALL: [Key; 8] = [Key::Forward,
+                               Key::Left,
+                               Key::Back,
+                               Key::Right,
+                               Key::Jump,
+                               Key::Duck,
+                               Key::Attack,
+                               Key::Attack2];
+
+    /// Returns the displayed key for the given engine key number.
+    pub fn from_engine_key(key: i32) -> Option<Self> {
+        match key {
+            k if k == i32::from(b'w') => Some(Key::Forward),
+            k if k == i32::from(b'a') => Some(Key::Left),
+            k if k == i32::from(b's') => Some(Key::Back),
+            k if k == i32::from(b'd') => Some(Key::Right),
+            K_SPACE => Some(Key::Jump),
+            K_CTRL => Some(Key::Duck),
+            K_MOUSE1 => Some(Key::Attack),
+            K_MOUSE2 => Some(Key::Attack2),
+            _ => None,
+        }
+    }
+
+    /// Returns the label shown on the input display.
+    pub fn label(self) -> &'static str {
+        match self {
+            Key::Forward => "W",
+            Key::Left => "A",
+            Key::Back => "S",
+            Key::Right => "D",
+            Key::Jump => "JUMP",
+            Key::Duck => "DUCK",
+            Key::Attack => "M1",
+            Key::Attack2 => "M2",
+        }
+    }
+
+    #[inline]
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl InputState {
+    /// Returns the state with no keys pressed.
+    #[inline]
+    pub const fn new() -> Self {
+        Self { pressed: 0 }
+    }
+
+    /// Updates the state with an engine key event.
+    #[inline]
+    pub fn key_event(&mut self, key: i32, down: bool) {
+        if let Some(key) = Key::from_engine_key(key) {
+            if down {
+                self.pressed |= key.bit();
+            } else {
+                self.pressed &= !key.bit();
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_pressed(self, key: Key) -> bool {
+        self.pressed & key.bit() != 0
+    }
+}
+
+impl InputLog {
+    /// Creates the log file, overwriting an existing one.
+    pub fn new(filename: &str) -> Result<Self> {
+        let file = File::create(filename).with_context(|_| {
+                                             format!("could not create the input log {}",
+                                                     filename)
+                                         })?;
+
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}", HEADER).context("could not write to the input log")?;
+
+        Ok(Self { writer })
+    }
+
+    /// Writes a key state change. `time` is the capture time in seconds.
+    pub fn key_event(&mut self, time: f64, key: i32, down: bool) -> Result<()> {
+        writeln!(self.writer, "{}", format_event(time, key, down))
+            .context("could not write to the input log")?;
+        Ok(())
+    }
+
+    /// Flushes the log.
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .context("could not write to the input log")?;
+        Ok(())
+    }
+}
+
+/// Returns a readable name of the engine key number.
+fn key_name(key: i32) -> String {
+    match key {
+        K_SPACE => "SPACE".to_string(),
+        K_CTRL => "CTRL".to_string(),
+        K_SHIFT => "SHIFT".to_string(),
+        K_MOUSE1 => "MOUSE1".to_string(),
+        K_MOUSE2 => "MOUSE2".to_string(),
+        K_MOUSE3 => "MOUSE3".to_string(),
+        33..=126 => (key as u8 as char).to_string(),
+        _ => format!("#{}", key),
+    }
+}
+
+/// Formats an input log line.
+fn format_event(time: f64, key: i32, down: bool) -> String {
+    format!("{:.6} {} {}",
+            time,
+            key_name(key),
+            if down { "down" } else { "up" })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn input_state_test() {
+        let mut state = InputState::default();
+
+        state.key_event(i32::from(b'w'), true);
+        state.key_event(K_SPACE, true);
+        state.key_event(i32::from(b'q'), true);
+        assert!(state.is_pressed(Key::Forward));
+        assert!(state.is_pressed(Key::Jump));
+        assert!(!state.is_pressed(Key::Duck));
+
+        state.key_event(i32::from(b'w'), false);
+        assert!(!state.is_pressed(Key::Forward));
+        assert!(state.is_pressed(Key::Jump));
+    }
+
+    #[test]
+    fn format_event_test() {
+        assert_eq!(format_event(1.5, i32::from(b'w'), true), "1.500000 w down");
+        assert_eq!(format_event(2f64, K_MOUSE1, false), "2.000000 MOUSE1 up");
+        assert_eq!(format_event(0f64, 200, true), "0.000000 #200 down");
+    }
+}
@@ -1,18 +1,32 @@
 #[macro_use]
 mod macros;
+mod audio;
+mod av_sync;
 mod capture;
+mod color;
 mod command;
 mod cvar;
+mod disk_space;
 mod dl;
 mod encode;
 mod engine;
+mod fade;
+mod filter_graph;
 mod fps_converter;
+mod hash_log;
 mod hooks {
     pub mod hw;
 }
+mod input;
+mod loudness;
+mod overlay;
+mod presets;
 // mod profiler;
 mod sdl;
+mod timecodes;
+mod timescale;
 mod utils;
+mod watermark;
 
 #[link(name = "GL", kind = "dylib")]
 extern "C" {}
@@ -0,0 +1,304 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Duration of the gating block sub-divisions, in seconds. Gating blocks are 400 ms long and
+/// overlap by 75%, so a new block starts every 100 ms.
+const STEP_DURATION: f64 = 0.1;
+
+/// Number of steps in a gating block.
+const STEPS_PER_BLOCK: usize = 4;
+
+/// Absolute gating threshold, in LUFS.
+const ABSOLUTE_GATE: f64 = -70f64;
+
+/// Relative gating threshold, in LU below the absolutely gated loudness.
+const RELATIVE_GATE: f64 = -10f64;
+
+/// Width of the loudness histogram bins, in LU.
+const HISTOGRAM_STEP: f64 = 0.1;
+
+/// Number of the loudness histogram bins. They cover the loudness from the absolute gate up to
+/// +30 LUFS, louder blocks go into the last bin.
+const HISTOGRAM_BINS: usize = 1000;
+
+/// A second-order IIR filter in the direct form I.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+
+    /// The last two inputs.
+    x: [f64; 2],
+
+    /// The last two outputs.
+    y: [f64; 2],
+}
+
+/// The K-weighting filter: the high shelf pre-filter followed by the RLB high-pass filter.
+#[derive(Debug, Clone, Copy)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+/// Measures the integrated loudness as described in ITU-R BS.1770-4 and EBU R128.
+pub struct LoudnessMeter {
+    /// The K-weighting filters, one per channel.
+    filters: [KWeighting; 2],
+
+    /// Number of samples in a step.
+    step_length: usize,
+
+    /// Number of samples in the current step.
+    step_position: usize,
+
+    /// Sum of the squared K-weighted sample values of both channels in the current step.
+    step_sum: f64,
+
+    /// Sums of the last steps.
+    steps: VecDeque<f64>,
+
+    /// Number of the gating blocks above the absolute gate in every loudness histogram bin.
+    histogram_counts: Vec<u64>,
+
+    /// Sum of the mean squares of the gating blocks in every loudness histogram bin.
+    histogram_sums: Vec<f64>,
+}
+
+impl Biquad {
+    #[inline]
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self { b,
+               a,
+               x: [0f64; 2],
+               y: [0f64; 2] }
+    }
+
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+                - self.a[1] * self.y[0]
+                - self.a[2] * self.y[1];
+
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+impl KWeighting {
+    /// Creates the filter for the given sample rate.
+    ///
+    /// The BS.1770 coefficients are given for 48 kHz only, so they are derived from the analog
+    /// filter parameters the same way libebur128 does it.
+    fn new(rate: f64) -> Self {
+        let f0 = 1681.974_450_955_533;
+        let gain = 3.999_843_853_973_347;
+        let q = 0.707_175_236_955_419_6;
+
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20f64);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1f64 + k / q + k * k;
+
+        let shelf = Biquad::new([(vh + vb * k / q + k * k) / a0,
+                                 2f64 * (k * k - vh) / a0,
+                                 (vh - vb * k / q + k * k) / a0],
+                                [1f64,
+                                 2f64 * (k * k - 1f64) / a0,
+                                 (1f64 - k / q + k * k) / a0]);
+
+        let f0 = 38.135_470_876_024_44;
+        let q = 0.500_327_037_323_877_3;
+
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1f64 + k / q + k * k;
+
+        let high_pass = Biquad::new([1f64, -2f64, 1f64],
+                                    [1f64,
+                                     2f64 * (k * k - 1f64) / a0,
+                                     (1f64 - k / q + k * k) / a0]);
+
+        Self { shelf, high_pass }
+    }
+
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32) -> Self {
+        let filter = KWeighting::new(f64::from(rate));
+
+        Self { filters: [filter; 2],
+               step_length: ((f64::from(rate) * STEP_DURATION).round() as usize).max(1),
+               step_position: 0,
+               step_sum: 0f64,
+               steps: VecDeque::with_capacity(STEPS_PER_BLOCK),
+               histogram_counts: vec![0; HISTOGRAM_BINS],
+               histogram_sums: vec![0f64; HISTOGRAM_BINS] }
+    }
+
+    /// Adds stereo samples to the measurement.
+    ///
+    /// Returns `true` if a gating block was completed, changing the integrated loudness.
+    pub fn add(&mut self, samples: &[(f32, f32)]) -> bool {
+        let mut new_block = false;
+
+        for &(left, right) in samples {
+            let left = self.filters[0].process(f64::from(left));
+            let right = self.filters[1].process(f64::from(right));
+
+            // Both channels have the weight of 1.
+            self.step_sum += left * left + right * right;
+            self.step_position += 1;
+
+            if self.step_position == self.step_length {
+                self.finish_step();
+                new_block |= self.steps.len() == STEPS_PER_BLOCK;
+            }
+        }
+
+        new_block
+    }
+
+    fn finish_step(&mut self) {
+        if self.steps.len() == STEPS_PER_BLOCK {
+            self.steps.pop_front();
+        }
+
+        self.steps.push_back(self.step_sum);
+        self.step_sum = 0f64;
+        self.step_position = 0;
+
+        if self.steps.len() == STEPS_PER_BLOCK {
+            let sum: f64 = self.steps.iter().sum();
+            self.add_block(sum / (STEPS_PER_BLOCK * self.step_length) as f64);
+        }
+    }
+
+    fn add_block(&mut self, mean_square: f64) {
+        let loudness = loudness(mean_square);
+        if loudness <= ABSOLUTE_GATE {
+            return;
+        }
+
+        let bin = histogram_bin(loudness);
+        self.histogram_counts[bin] += 1;
+        self.histogram_sums[bin] += mean_square;
+    }
+
+    /// Returns the gated integrated loudness so far, in LUFS, or `None` if nothing was loud
+    /// enough to measure.
+    ///
+    /// The blocks are kept in a loudness histogram like in libebur128, so this takes the same time
+    /// no matter how long the measurement is. The relative gate is applied with the precision of
+    /// one histogram bin.
+    pub fn integrated(&self) -> Option<f64> {
+        let absolute_gated = gated_mean(&self.histogram_counts, &self.histogram_sums)?;
+        let relative_gate = loudness(absolute_gated) + RELATIVE_GATE;
+
+        let start = if relative_gate > ABSOLUTE_GATE {
+            histogram_bin(relative_gate)
+        } else {
+            0
+        };
+
+        gated_mean(&self.histogram_counts[start..], &self.histogram_sums[start..]).map(loudness)
+    }
+}
+
+/// Returns the histogram bin of the block with the given loudness above the absolute gate.
+#[inline]
+fn histogram_bin(loudness: f64) -> usize {
+    (((loudness - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize).min(HISTOGRAM_BINS - 1)
+}
+
+/// Returns the mean of the block mean squares in the histogram bins.
+fn gated_mean(counts: &[u64], sums: &[f64]) -> Option<f64> {
+    let count: u64 = counts.iter().sum();
+    let sum: f64 = sums.iter().sum();
+
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f64)
+    }
+}
+
+/// Converts the mean square into loudness, in LUFS.
+#[inline]
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10f64 * mean_square.log10()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(rate: u32, frequency: f64, level_db: f64, duration: f64) -> Vec<(f32, f32)> {
+        let amplitude = 10f64.powf(level_db / 20f64);
+        let count = (f64::from(rate) * duration) as usize;
+
+        (0..count).map(|i| {
+                      let value = amplitude
+                                  * (2f64 * PI * frequency * i as f64 / f64::from(rate)).sin();
+                      (value as f32, value as f32)
+                  })
+                  .collect()
+    }
+
+    #[test]
+    fn sine_loudness_test() {
+        // EBU Tech 3341: a stereo 1 kHz sine at -23 dBFS measures -23 LUFS.
+        for &rate in &[22050, 44100, 48000] {
+            let mut meter = LoudnessMeter::new(rate);
+            meter.add(&sine(rate, 1000f64, -23f64, 5f64));
+
+            let loudness = meter.integrated().unwrap();
+            assert!((loudness + 23f64).abs() < 0.1, "{} Hz: {}", rate, loudness);
+        }
+    }
+
+    #[test]
+    fn gating_test() {
+        let rate = 48000;
+        let mut meter = LoudnessMeter::new(rate);
+
+        // Silence and quiet parts are gated out.
+        meter.add(&sine(rate, 1000f64, -23f64, 5f64));
+        meter.add(&vec![(0f32, 0f32); rate as usize * 5]);
+        meter.add(&sine(rate, 1000f64, -50f64, 5f64));
+
+        let loudness = meter.integrated().unwrap();
+        assert!((loudness + 23f64).abs() < 0.2, "{}", loudness);
+    }
+
+    #[test]
+    fn silence_test() {
+        let mut meter = LoudnessMeter::new(48000);
+        meter.add(&vec![(0f32, 0f32); 48000]);
+        assert_eq!(meter.integrated(), None);
+    }
+
+    #[test]
+    fn blocks_test() {
+        let mut meter = LoudnessMeter::new(1000);
+
+        // The first block completes after 400 ms, then one every 100 ms.
+        assert!(!meter.add(&vec![(0.5, 0.5); 399]));
+        assert!(meter.add(&[(0.5, 0.5)]));
+        meter.add(&vec![(0.5, 0.5); 200]);
+        assert_eq!(meter.histogram_counts.iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn histogram_bin_test() {
+        assert_eq!(histogram_bin(-69.95), 0);
+        assert_eq!(histogram_bin(-22.95), 470);
+        assert_eq!(histogram_bin(100f64), HISTOGRAM_BINS - 1);
+    }
+}
@@ -0,0 +1,153 @@
+use ffmpeg::frame::Video as VideoFrame;
+
+use super::{draw_mask, origin, render_mask, MAX_SIZE};
+use crate::color::ColorConversion;
+use crate::input::{InputState, Key};
+
+/// Input display settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputDisplaySettings {
+    /// Position of the top left corner of the display, in pixels. Negative values are counted
+    /// from the right and the bottom edges of the frame.
+    pub position: (i32, i32),
+
+    /// Character height, in pixels.
+    pub size: u32,
+
+    /// Color of the pressed keys, RGBA. Released keys are drawn with a quarter of the alpha.
+    pub color: [u8; 4],
+}
+
+/// Draws the pressed keys onto video frames.
+pub struct InputDisplay {
+    settings: InputDisplaySettings,
+
+    /// How the key colors are converted for the YUV frames.
+    color_conversion: ColorConversion,
+
+    /// Rendered key labels, in the `Key::ALL` order.
+    masks: Vec<(Vec<u8>, (u32, u32))>,
+
+    /// Whether the unsupported pixel format warning was already given.
+    warned: bool,
+}
+
+/// Width of the display, in characters.
+const WIDTH: u32 = 9;
+
+/// Height of the display, in rows.
+const HEIGHT: u32 = 4;
+
+/// Returns the position of the key label on the display, in characters and rows.
+fn cell(key: Key) -> (u32, u32) {
+    match key {
+        Key::Forward => (4, 0),
+        Key::Left => (2, 1),
+        Key::Back => (4, 1),
+        Key::Right => (6, 1),
+        Key::Jump => (0, 2),
+        Key::Duck => (5, 2),
+        Key::Attack => (2, 3),
+        Key::Attack2 => (5, 3),
+    }
+}
+
+impl InputDisplay {
+    pub fn new(mut settings: InputDisplaySettings, color_conversion: ColorConversion) -> Self {
+        settings.size = settings.size.max(1).min(MAX_SIZE);
+
+        let masks = Key::ALL.iter()
+                            .map(|key| {
+                                // The labels are short and the size is bounded.
+                                render_mask(key.label(), settings.size).unwrap()
+                            })
+                            .collect();
+
+        Self { settings,
+               color_conversion,
+               masks,
+               warned: false }
+    }
+
+    /// Returns the height of a row, in pixels.
+    #[inline]
+    fn row_height(&self) -> u32 {
+        self.settings.size + self.settings.size / 4
+    }
+
+    /// Returns the size of the whole display, in pixels.
+    #[inline]
+    fn size(&self) -> (u32, u32) {
+        // The shadow sticks out to the right and to the bottom of the labels by the same amount
+        // for every mask.
+        let (_, mask_height) = self.masks[0].1;
+        let shadow_offset = mask_height - self.settings.size;
+
+        (WIDTH * self.settings.size + shadow_offset,
+         (HEIGHT - 1) * self.row_height() + mask_height)
+    }
+
+    /// Draws the input display onto the frame.
+    ///
+    /// Returns a warning message if the frame pixel format is not supported.
+    pub fn draw(&mut self, frame: &mut VideoFrame, input: InputState) -> Option<String> {
+        let (origin_x, origin_y) = origin(self.settings.position,
+                                          self.size(),
+                                          (frame.width(), frame.height()));
+
+        let mut released_color = self.settings.color;
+        released_color[3] /= 4;
+
+        for (&key, (mask, mask_size)) in Key::ALL.iter().zip(&self.masks) {
+            let (column, row) = cell(key);
+            let key_origin = (origin_x + i64::from(column * self.settings.size),
+                              origin_y + i64::from(row * self.row_height()));
+
+            let color = if input.is_pressed(key) {
+                self.settings.color
+            } else {
+                released_color
+            };
+
+            if !draw_mask(frame, mask, *mask_size, key_origin, color, self.color_conversion) {
+                if !self.warned {
+                    self.warned = true;
+                    return Some(format!("Warning: the input display is not supported with the \
+                                         {:?} pixel format.\n",
+                                        frame.format()));
+                }
+
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::{ColorRange, ColorSpace};
+
+    #[test]
+    fn cells_fit_test() {
+        for &key in &Key::ALL {
+            let (column, row) = cell(key);
+            assert!(column + key.label().len() as u32 <= WIDTH);
+            assert!(row < HEIGHT);
+        }
+    }
+
+    #[test]
+    fn size_test() {
+        let color_conversion = ColorConversion { space: ColorSpace::BT601,
+                                                 range: ColorRange::Limited };
+        let display = InputDisplay::new(InputDisplaySettings { position: (0, 0),
+                                                               size: 8,
+                                                               color: [255; 4] },
+                                        color_conversion);
+
+        assert_eq!(display.size(), (9 * 8 + 1, 3 * 10 + 9));
+    }
+}
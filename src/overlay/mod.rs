@@ -0,0 +1,443 @@
+use failure::{ensure, Error, ResultExt};
+use ffmpeg::format;
+use ffmpeg::frame::Video as VideoFrame;
+use std::result;
+
+use crate::color::ColorConversion;
+use crate::input::InputState;
+
+mod font;
+mod input;
+pub use self::input::{InputDisplay, InputDisplaySettings};
+
+type Result<T> = result::Result<T, Error>;
+
+/// Mask values.
+const MASK_EMPTY: u8 = 0;
+const MASK_SHADOW: u8 = 1;
+const MASK_TEXT: u8 = 2;
+
+/// The largest accepted character height, in pixels.
+pub const MAX_SIZE: u32 = 1024;
+
+/// The game state at the moment the frame was captured.
+#[derive(Debug, Clone, Default)]
+pub struct FrameInfo {
+    /// Name of the current map, without the path and the extension.
+    pub map: String,
+
+    /// Current client time, which is the demo time during demo playback.
+    pub time: f64,
+
+    /// Pressed keys, used by the input display.
+    pub input: InputState,
+}
+
+/// Overlay settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlaySettings {
+    /// Text template with `%map%`, `%time%` and `%frame%` placeholders.
+    pub text: String,
+
+    /// Position of the top left corner of the text, in pixels. Negative values are counted from
+    /// the right and the bottom edges of the frame to the right and the bottom edges of the text.
+    pub position: (i32, i32),
+
+    /// Character height, in pixels.
+    pub size: u32,
+
+    /// Text color, RGBA.
+    pub color: [u8; 4],
+}
+
+/// Draws text onto video frames.
+pub struct Overlay {
+    settings: OverlaySettings,
+
+    /// How the text color is converted for the YUV frames.
+    color_conversion: ColorConversion,
+
+    /// The text the mask was rendered for.
+    mask_text: String,
+
+    /// The rendered text, one `MASK_*` value per pixel.
+    mask: Vec<u8>,
+
+    /// The mask dimensions.
+    mask_size: (u32, u32),
+
+    /// Whether the unsupported pixel format warning was already given.
+    warned: bool,
+}
+
+impl Overlay {
+    #[inline]
+    pub fn new(settings: OverlaySettings, color_conversion: ColorConversion) -> Self {
+        Self { settings,
+               color_conversion,
+               mask_text: String::new(),
+               mask: Vec::new(),
+               mask_size: (0, 0),
+               warned: false }
+    }
+
+    /// Returns whether the text depends on the output frame number, which means that every
+    /// output frame needs to be drawn separately.
+    #[inline]
+    pub fn uses_frame_number(&self) -> bool {
+        self.settings.text.contains("%frame%")
+    }
+
+    /// Draws the overlay onto the frame.
+    ///
+    /// Returns a warning message if the frame pixel format is not supported.
+    pub fn draw(&mut self,
+                frame: &mut VideoFrame,
+                info: &FrameInfo,
+                frame_number: u64)
+                -> Option<String> {
+        let text = format_text(&self.settings.text, info, frame_number);
+        if text != self.mask_text {
+            // Text too long to render is not drawn at all.
+            let (mask, mask_size) = render_mask(&text, self.settings.size).unwrap_or_default();
+            self.mask = mask;
+            self.mask_size = mask_size;
+            self.mask_text = text;
+        }
+
+        let origin = origin(self.settings.position,
+                            self.mask_size,
+                            (frame.width(), frame.height()));
+
+        if !draw_mask(frame,
+                      &self.mask,
+                      self.mask_size,
+                      origin,
+                      self.settings.color,
+                      self.color_conversion)
+           && !self.warned
+        {
+            self.warned = true;
+            return Some(format!("Warning: the overlay is not supported with the {:?} pixel \
+                                 format.\n",
+                                frame.format()));
+        }
+
+        None
+    }
+}
+
+/// Returns the position of a mask of the given size on the frame.
+///
+/// Negative position values are counted from the right and the bottom edges of the frame to the
+/// right and the bottom edges of the mask.
+fn origin((x, y): (i32, i32), (mask_width, mask_height): (u32, u32), (width, height): (u32, u32))
+          -> (i64, i64) {
+    let x = if x >= 0 {
+        i64::from(x)
+    } else {
+        i64::from(width) + i64::from(x) + 1 - i64::from(mask_width)
+    };
+    let y = if y >= 0 {
+        i64::from(y)
+    } else {
+        i64::from(height) + i64::from(y) + 1 - i64::from(mask_height)
+    };
+
+    (x, y)
+}
+
+/// Calls `f` with frame coordinates and the value of every visible mask pixel.
+fn for_each_pixel<F>(mask: &[u8],
+                     (mask_width, mask_height): (u32, u32),
+                     (origin_x, origin_y): (i64, i64),
+                     (width, height): (u32, u32),
+                     mut f: F)
+    where F: FnMut(usize, usize, u8)
+{
+    for my in 0..mask_height {
+        let y = origin_y + i64::from(my);
+        if y < 0 || y >= i64::from(height) {
+            continue;
+        }
+
+        for mx in 0..mask_width {
+            let x = origin_x + i64::from(mx);
+            if x < 0 || x >= i64::from(width) {
+                continue;
+            }
+
+            let value = mask[my as usize * mask_width as usize + mx as usize];
+            if value != MASK_EMPTY {
+                f(x as usize, y as usize, value);
+            }
+        }
+    }
+}
+
+/// Draws the mask onto the frame at the given position, with the text in the given color and
+/// the shadow in black. The colors are converted with `color_conversion` for the YUV frames.
+///
+/// Returns `false` if the frame pixel format is not supported.
+fn draw_mask(frame: &mut VideoFrame,
+             mask: &[u8],
+             mask_size: (u32, u32),
+             origin: (i64, i64),
+             color: [u8; 4],
+             color_conversion: ColorConversion)
+             -> bool {
+    match frame.format() {
+        format::Pixel::RGB24 | format::Pixel::RGBA => {
+            draw_packed(frame, mask, mask_size, origin, color);
+            true
+        }
+        format::Pixel::YUV420P | format::Pixel::YUV444P => {
+            draw_planar(frame, mask, mask_size, origin, color, color_conversion);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Draws the mask onto RGB24 or RGBA frames.
+fn draw_packed(frame: &mut VideoFrame,
+               mask: &[u8],
+               mask_size: (u32, u32),
+               origin: (i64, i64),
+               color: [u8; 4]) {
+    let size = (frame.width(), frame.height());
+    let bytes_per_pixel = if frame.format() == format::Pixel::RGB24 {
+        3
+    } else {
+        4
+    };
+    let stride = frame.stride(0);
+    let alpha = color[3];
+    let data = frame.data_mut(0);
+
+    for_each_pixel(mask, mask_size, origin, size, |x, y, value| {
+        let base = y * stride + x * bytes_per_pixel;
+
+        for i in 0..3 {
+            let src = if value == MASK_TEXT { color[i] } else { 0 };
+            data[base + i] = blend(data[base + i], src, alpha);
+        }
+    });
+}
+
+/// Draws the mask onto YUV420P or YUV444P frames.
+fn draw_planar(frame: &mut VideoFrame,
+               mask: &[u8],
+               mask_size: (u32, u32),
+               origin: (i64, i64),
+               color: [u8; 4],
+               color_conversion: ColorConversion) {
+    let size = (frame.width(), frame.height());
+    let descriptor = frame.format().descriptor().unwrap();
+    let (chroma_w, chroma_h) = (descriptor.log2_chroma_w(), descriptor.log2_chroma_h());
+    let chroma_mask_x = (1 << chroma_w) - 1;
+    let chroma_mask_y = (1 << chroma_h) - 1;
+
+    let alpha = color[3];
+    let text = color_conversion.rgb_to_yuv(color);
+    let shadow = color_conversion.black();
+
+    for plane in 0..3 {
+        let stride = frame.stride(plane);
+        let data = frame.data_mut(plane);
+
+        for_each_pixel(mask, mask_size, origin, size, |x, y, value| {
+            let index = if plane == 0 {
+                y * stride + x
+            } else {
+                // Only draw the top left pixel of every chroma block.
+                if x & chroma_mask_x != 0 || y & chroma_mask_y != 0 {
+                    return;
+                }
+
+                (y >> chroma_h) * stride + (x >> chroma_w)
+            };
+
+            let src = if value == MASK_TEXT {
+                text[plane]
+            } else {
+                shadow[plane]
+            };
+            data[index] = blend(data[index], src, alpha);
+        });
+    }
+}
+
+/// Substitutes the placeholders in the overlay text template.
+pub fn format_text(template: &str, info: &FrameInfo, frame_number: u64) -> String {
+    template.replace("%map%", &info.map)
+            .replace("%time%", &format_time(info.time))
+            .replace("%frame%", &frame_number.to_string())
+}
+
+/// Formats the time in seconds as `M:SS.mmm`, or `H:MM:SS.mmm` if it's over an hour.
+pub fn format_time(time: f64) -> String {
+    let millis = (time.max(0f64) * 1000f64).round() as u64;
+
+    let hours = millis / 3_600_000;
+    let minutes = millis / 60_000 % 60;
+    let seconds = millis / 1000 % 60;
+    let millis = millis % 1000;
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}.{:03}", hours, minutes, seconds, millis)
+    } else {
+        format!("{}:{:02}.{:03}", minutes, seconds, millis)
+    }
+}
+
+/// Parses the given string into an overlay position.
+///
+/// The string should be `<x> <y>`.
+pub fn parse_position(string: &str) -> Result<(i32, i32)> {
+    let values = string.split_whitespace()
+                       .map(str::parse::<i32>)
+                       .collect::<result::Result<Vec<_>, _>>()
+                       .context("could not convert the values to integers")?;
+    ensure!(values.len() == 2, "expected two values: <x> <y>");
+
+    Ok((values[0], values[1]))
+}
+
+/// Parses the given string into an RGBA color.
+///
+/// The string should be `<r> <g> <b>` or `<r> <g> <b> <a>` with values from 0 to 255.
+pub fn parse_color(string: &str) -> Result<[u8; 4]> {
+    let values = string.split_whitespace()
+                       .map(str::parse::<u8>)
+                       .collect::<result::Result<Vec<_>, _>>()
+                       .context("could not convert the values to integers from 0 to 255")?;
+    ensure!(values.len() == 3 || values.len() == 4,
+            "expected three or four values: <r> <g> <b> [a]");
+
+    Ok([values[0],
+        values[1],
+        values[2],
+        values.get(3).cloned().unwrap_or(255)])
+}
+
+/// Renders the text with a drop shadow into a mask.
+///
+/// Returns `None` if the mask dimensions overflow.
+fn render_mask(text: &str, size: u32) -> Option<(Vec<u8>, (u32, u32))> {
+    let size = size.max(1);
+    let shadow_offset = (size / font::GLYPH_SIZE).max(1);
+
+    let char_count = text.chars().count();
+    let width = char_count.checked_mul(size as usize)?
+                          .checked_add(shadow_offset as usize)?;
+    let height = size as usize + shadow_offset as usize;
+
+    // The mask size is handed out as u32.
+    if width > u32::max_value() as usize || height > u32::max_value() as usize {
+        return None;
+    }
+
+    let mut mask = vec![MASK_EMPTY; width.checked_mul(height)?];
+
+    let size = size as usize;
+    let shadow_offset = shadow_offset as usize;
+    let glyph_size = font::GLYPH_SIZE as usize;
+
+    for (i, c) in text.chars().enumerate() {
+        let glyph = font::glyph(c);
+        let left = i * size;
+
+        for y in 0..size {
+            for x in 0..size {
+                // Nearest-neighbor scaling of the glyph.
+                if !font::is_set(glyph,
+                                 (x * glyph_size / size) as u32,
+                                 (y * glyph_size / size) as u32)
+                {
+                    continue;
+                }
+
+                let index = y * width + left + x;
+                mask[index] = MASK_TEXT;
+
+                let shadow_index = (y + shadow_offset) * width + left + x + shadow_offset;
+                if mask[shadow_index] == MASK_EMPTY {
+                    mask[shadow_index] = MASK_SHADOW;
+                }
+            }
+        }
+    }
+
+    Some((mask, (width as u32, height as u32)))
+}
+
+/// Blends the source value over the destination value.
+#[inline]
+fn blend(dst: u8, src: u8, alpha: u8) -> u8 {
+    let alpha = u32::from(alpha);
+    ((u32::from(dst) * (255 - alpha) + u32::from(src) * alpha + 127) / 255) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_time_test() {
+        assert_eq!(format_time(0f64), "0:00.000");
+        assert_eq!(format_time(-1f64), "0:00.000");
+        assert_eq!(format_time(61.5), "1:01.500");
+        assert_eq!(format_time(3723.0004), "1:02:03.000");
+    }
+
+    #[test]
+    fn format_text_test() {
+        let info = FrameInfo { map: "c1a0".to_string(),
+                               time: 12.25,
+                               input: InputState::default() };
+
+        assert_eq!(format_text("%map% %time% #%frame%", &info, 42),
+                   "c1a0 0:12.250 #42");
+        assert_eq!(format_text("label", &info, 42), "label");
+    }
+
+    #[test]
+    fn parse_color_test() {
+        assert_eq!(parse_color("255 128 0").unwrap(), [255, 128, 0, 255]);
+        assert_eq!(parse_color("0 0 0 64").unwrap(), [0, 0, 0, 64]);
+
+        assert!(parse_color("0 0").is_err());
+        assert!(parse_color("256 0 0").is_err());
+        assert!(parse_color("0 0 0 0 0").is_err());
+    }
+
+    #[test]
+    fn parse_position_test() {
+        assert_eq!(parse_position("16 16").unwrap(), (16, 16));
+        assert_eq!(parse_position("-16 16").unwrap(), (-16, 16));
+
+        assert!(parse_position("16").is_err());
+        assert!(parse_position("a b").is_err());
+    }
+
+    #[test]
+    fn render_mask_test() {
+        let (mask, (width, height)) = render_mask("|", 8).unwrap();
+        assert_eq!((width, height), (9, 9));
+
+        // The top pixel of '|' and its shadow.
+        assert_eq!(mask[3], MASK_TEXT);
+        assert_eq!(mask[(width + 4) as usize], MASK_TEXT);
+        assert_eq!(mask[(width * 3 + 4) as usize], MASK_SHADOW);
+
+        assert!(render_mask(&"|".repeat(1 << 22), MAX_SIZE).is_none());
+    }
+
+    #[test]
+    fn blend_test() {
+        assert_eq!(blend(0, 255, 255), 255);
+        assert_eq!(blend(100, 255, 0), 100);
+        assert_eq!(blend(0, 255, 128), 128);
+    }
+}
@@ -0,0 +1,420 @@
+use failure::{bail, ensure, format_err, Error, ResultExt};
+use lazy_static::lazy_static;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::result;
+
+use crate::cvar::{self, CVar};
+use crate::encode;
+use crate::engine::Engine;
+use crate::hooks::hw;
+use crate::utils::format_error;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Name of the file, inside the mod directory, where the user presets are stored.
+const PRESETS_FILENAME: &str = "hl-capture-presets.ini";
+
+/// Preset keys which are not console variables.
+const VIDEO_ENCODER_KEY: &str = "cap_video_encoder";
+const AUDIO_ENCODER_KEY: &str = "cap_audio_encoder";
+
+/// Console variables which describe the environment rather than the output, so they are not
+/// saved into presets.
+const NOT_SAVED: &[&str] = &["cap_allow_tabbing_out_in_demos",
+                             "cap_hash_compare",
+                             "cap_hash_log",
+                             "cap_input_log",
+                             "cap_min_free_mb",
+                             "cap_playdemostop"];
+
+/// Presets shipped with hl-capture.
+const BUILTIN_PRESETS: &[(&str, &[(&str, &str)])] =
+    &[("youtube_1080p60",
+       &[("cap_video_encoder", "libx264"),
+         ("cap_audio_encoder", "aac"),
+         ("cap_filename", "capture.mp4"),
+         ("cap_muxer_settings", "movflags=+faststart"),
+         ("cap_fps", "60"),
+         ("cap_pixel_format", "yuv420p"),
+         ("cap_crf", "18"),
+         ("cap_video_bitrate", "0"),
+         ("cap_x264_preset", "slow"),
+         ("cap_audio_bitrate", "320")]),
+      ("lossless_ffv1",
+       &[("cap_video_encoder", "ffv1"),
+         ("cap_audio_encoder", "flac"),
+         ("cap_filename", "capture.mkv"),
+         ("cap_muxer_settings", ""),
+         ("cap_pixel_format", "bgr0"),
+         ("cap_video_encoder_settings", "level=3 slices=16 slicecrc=1")]),
+      ("preview",
+       &[("cap_video_encoder", "libx264"),
+         ("cap_audio_encoder", "aac"),
+         ("cap_filename", "preview.mp4"),
+         ("cap_muxer_settings", "movflags=+faststart"),
+         ("cap_fps", "30"),
+         ("cap_pixel_format", "yuv420p"),
+         ("cap_crf", "28"),
+         ("cap_video_bitrate", "0"),
+         ("cap_x264_preset", "ultrafast"),
+         ("cap_audio_bitrate", "128")])];
+
+/// A named set of capture settings.
+#[derive(Debug, Clone, PartialEq)]
+struct Preset {
+    name: String,
+    values: Vec<(String, String)>,
+}
+
+impl Preset {
+    /// Returns the built-in preset with the given name.
+    fn builtin(name: &str) -> Option<Self> {
+        BUILTIN_PRESETS.iter()
+                       .find(|&&(n, _)| n == name)
+                       .map(|&(name, values)| {
+                           Self { name: name.to_string(),
+                                  values: values.iter()
+                                                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                                                .collect() }
+                       })
+    }
+}
+
+/// Checks that the preset name can be stored in the presets file.
+fn validate_name(name: &str) -> Result<()> {
+    ensure!(!name.is_empty(), "the preset name cannot be empty");
+    ensure!(name.chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.'),
+            "the preset name can only contain letters, digits, '_', '-' and '.'");
+    Ok(())
+}
+
+/// Parses the contents of a presets file.
+///
+/// The format is INI-like: every preset starts with a `[name]` line followed by `key = value`
+/// lines. Empty lines and lines starting with `#` or `;` are ignored.
+fn parse_presets(contents: &str) -> Result<Vec<Preset>> {
+    let mut presets: Vec<Preset> = Vec::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            ensure!(line.ends_with(']'), "line {}: expected ']'", number + 1);
+
+            let name = line[1..line.len() - 1].trim();
+            validate_name(name).with_context(|_| format!("line {}", number + 1))?;
+            ensure!(presets.iter().all(|p| p.name != name),
+                    "line {}: duplicate preset '{}'",
+                    number + 1,
+                    name);
+
+            presets.push(Preset { name: name.to_string(),
+                                  values: Vec::new() });
+            continue;
+        }
+
+        let mut split = line.splitn(2, '=');
+        let key = split.next().unwrap().trim();
+        let value = split.next()
+                         .ok_or_else(|| format_err!("line {}: expected 'key = value'", number + 1))?
+                         .trim();
+        ensure!(!key.is_empty(), "line {}: empty key", number + 1);
+
+        match presets.last_mut() {
+            Some(preset) => preset.values.push((key.to_string(), value.to_string())),
+            None => bail!("line {}: value outside of a preset", number + 1),
+        }
+    }
+
+    Ok(presets)
+}
+
+/// Formats the presets as the contents of a presets file.
+fn write_presets(presets: &[Preset]) -> String {
+    let mut buf = String::from("# hl-capture presets.\n\
+                                # Every preset starts with a [name] line followed by \
+                                `cvar = value` lines.\n");
+
+    for preset in presets {
+        buf.push_str(&format!("\n[{}]\n", preset.name));
+
+        for &(ref key, ref value) in &preset.values {
+            buf.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+
+    buf
+}
+
+/// Returns the path to the user presets file.
+fn presets_path(engine: &Engine) -> PathBuf {
+    PathBuf::from(hw::get_game_dir(engine.marker().1)).join(PRESETS_FILENAME)
+}
+
+/// Reads the user presets. A missing file is treated as no presets.
+fn load_user_presets(engine: &Engine) -> Result<Vec<Preset>> {
+    let path = presets_path(engine);
+
+    let contents = match fs::read_to_string(&path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result.with_context(|_| format!("could not read {}", path.display()))?,
+    };
+
+    Ok(parse_presets(&contents).with_context(|_| format!("could not parse {}", path.display()))?)
+}
+
+/// Writes the user presets.
+fn save_user_presets(engine: &Engine, presets: &[Preset]) -> Result<()> {
+    let path = presets_path(engine);
+
+    File::create(&path).and_then(|mut f| f.write_all(write_presets(presets).as_bytes()))
+                       .with_context(|_| format!("could not write {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Finds the preset by name, preferring user presets over the built-in ones.
+fn find_preset(engine: &Engine, name: &str) -> Result<Preset> {
+    load_user_presets(engine)?.into_iter()
+                              .find(|p| p.name == name)
+                              .or_else(|| Preset::builtin(name))
+                              .ok_or_else(|| format_err!("preset '{}' does not exist", name))
+}
+
+/// Returns the console variable with the given name.
+fn find_cvar(name: &str) -> Option<&'static CVar> {
+    cvar::CVARS.iter().cloned().find(|c| c.name() == name)
+}
+
+/// Collects the current capture settings.
+fn current_values(engine: &mut Engine) -> Result<Vec<(String, String)>> {
+    let mut values = Vec::new();
+
+    if let Some(name) = encode::video_encoder_name() {
+        values.push((VIDEO_ENCODER_KEY.to_string(), name));
+    }
+    if let Some(name) = encode::audio_encoder_name() {
+        values.push((AUDIO_ENCODER_KEY.to_string(), name));
+    }
+
+    let mut cvars = cvar::CVARS.iter()
+                               .filter(|c| !NOT_SAVED.contains(&c.name()))
+                               .collect::<Vec<_>>();
+    cvars.sort_by_key(|c| c.name());
+
+    for cvar in cvars {
+        let value = cvar.to_string(engine)
+                        .with_context(|_| format!("could not get the value of {}", cvar.name()))?;
+        values.push((cvar.name().to_string(), value));
+    }
+
+    Ok(values)
+}
+
+/// Applies the preset values.
+///
+/// Unknown keys are reported but don't prevent the rest of the preset from being applied.
+fn apply_preset(engine: &mut Engine, preset: &Preset) -> Result<()> {
+    let mut errors = String::new();
+
+    for &(ref key, ref value) in &preset.values {
+        let result = match key.as_str() {
+            VIDEO_ENCODER_KEY => encode::set_video_encoder(value),
+            AUDIO_ENCODER_KEY => encode::set_audio_encoder(value),
+            _ => match find_cvar(key) {
+                Some(cvar) => cvar.set(engine, value),
+                None => Err(format_err!("unknown setting '{}'", key)),
+            },
+        };
+
+        if let Err(ref e) = result {
+            errors.push_str(&format_error(e));
+        }
+    }
+
+    ensure!(errors.is_empty(),
+            "some settings from preset '{}' could not be applied:\n{}",
+            preset.name,
+            errors);
+
+    Ok(())
+}
+
+command!(cap_preset_save, |mut engine| {
+    let mut args = engine.args();
+
+    if args.len() != 2 {
+        engine.con_print("Usage:\n    cap_preset_save <name>\n     - Save the current capture \
+                          settings into a preset.\n");
+        return;
+    }
+
+    let name = args.nth(1).unwrap();
+
+    let result = validate_name(&name).and_then(|_| load_user_presets(&engine))
+                                     .and_then(|mut presets| {
+                                         let preset = Preset { name: name.clone(),
+                                                               values:
+                                                                   current_values(&mut engine)? };
+
+                                         match presets.iter_mut().find(|p| p.name == name) {
+                                             Some(p) => *p = preset,
+                                             None => presets.push(preset),
+                                         }
+
+                                         save_user_presets(&engine, &presets)
+                                     });
+
+    match result {
+        Ok(()) => engine.con_print(&format!("Saved preset '{}' to {}.\n",
+                                            name,
+                                            presets_path(&engine).display())),
+        Err(ref e) => engine.con_print(&format_error(e)),
+    }
+});
+
+command!(cap_preset_load, |mut engine| {
+    let mut args = engine.args();
+
+    if args.len() != 2 {
+        engine.con_print("Usage:\n    cap_preset_load <name>\n     - Load the capture settings \
+                          from a preset.\n");
+        return;
+    }
+
+    let name = args.nth(1).unwrap();
+
+    let result = find_preset(&engine, &name).and_then(|p| apply_preset(&mut engine, &p));
+
+    match result {
+        Ok(()) => engine.con_print(&format!("Loaded preset '{}'.\n", name)),
+        Err(ref e) => engine.con_print(&format_error(e)),
+    }
+});
+
+command!(cap_preset_delete, |engine| {
+    let mut args = engine.args();
+
+    if args.len() != 2 {
+        engine.con_print("Usage:\n    cap_preset_delete <name>\n     - Delete a saved \
+                          preset.\n");
+        return;
+    }
+
+    let name = args.nth(1).unwrap();
+
+    let result = load_user_presets(&engine).and_then(|mut presets| {
+                                               let len = presets.len();
+                                               presets.retain(|p| p.name != name);
+
+                                               if presets.len() == len {
+                                                   if Preset::builtin(&name).is_some() {
+                                                       bail!("built-in presets cannot be deleted");
+                                                   }
+
+                                                   bail!("preset '{}' does not exist", name);
+                                               }
+
+                                               save_user_presets(&engine, &presets)
+                                           });
+
+    match result {
+        Ok(()) => engine.con_print(&format!("Deleted preset '{}'.\n", name)),
+        Err(ref e) => engine.con_print(&format_error(e)),
+    }
+});
+
+command!(cap_preset_list, |engine| {
+    let user_presets = match load_user_presets(&engine) {
+        Ok(p) => p,
+        Err(ref e) => {
+            engine.con_print(&format_error(e));
+            Vec::new()
+        }
+    };
+
+    let mut buf = String::from("Built-in presets:\n");
+
+    for &(name, _) in BUILTIN_PRESETS {
+        buf.push_str(&format!("    {}", name));
+
+        if user_presets.iter().any(|p| p.name == name) {
+            buf.push_str(" (overridden)");
+        }
+
+        buf.push('\n');
+    }
+
+    buf.push_str(&format!("User presets ({}):\n", presets_path(&engine).display()));
+
+    if user_presets.is_empty() {
+        buf.push_str("    none\n");
+    }
+
+    for preset in &user_presets {
+        buf.push_str(&format!("    {}\n", preset.name));
+    }
+
+    engine.con_print(&buf);
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_presets_test() {
+        let presets = parse_presets("# comment\n\
+                                     [first]\n\
+                                     cap_fps = 30000 1001\n\
+                                     cap_muxer_settings =\n\
+                                     \n\
+                                     ; another comment\n\
+                                     [second]\n\
+                                     cap_crf=0\n").unwrap();
+
+        assert_eq!(presets,
+                   vec![Preset { name: "first".to_string(),
+                                 values: vec![("cap_fps".to_string(), "30000 1001".to_string()),
+                                              ("cap_muxer_settings".to_string(),
+                                               String::new())] },
+                        Preset { name: "second".to_string(),
+                                 values: vec![("cap_crf".to_string(), "0".to_string())] }]);
+    }
+
+    #[test]
+    fn parse_presets_invalid_test() {
+        assert!(parse_presets("cap_fps = 60\n").is_err());
+        assert!(parse_presets("[first\ncap_fps = 60\n").is_err());
+        assert!(parse_presets("[first]\ncap_fps 60\n").is_err());
+        assert!(parse_presets("[first]\n[first]\n").is_err());
+        assert!(parse_presets("[with space]\n").is_err());
+    }
+
+    #[test]
+    fn write_presets_roundtrip_test() {
+        let presets = vec![Preset { name: "a".to_string(),
+                                    values: vec![("cap_fps".to_string(), "60".to_string()),
+                                                 ("cap_pixel_format".to_string(),
+                                                  String::new())] },
+                           Preset { name: "b".to_string(),
+                                    values: Vec::new() }];
+
+        assert_eq!(parse_presets(&write_presets(&presets)).unwrap(), presets);
+    }
+
+    #[test]
+    fn builtin_presets_valid_test() {
+        for &(name, _) in BUILTIN_PRESETS {
+            assert!(validate_name(name).is_ok());
+            assert!(Preset::builtin(name).is_some());
+        }
+    }
+}
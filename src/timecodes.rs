@@ -0,0 +1,77 @@
+use failure::{Error, ResultExt};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+/// The first line of a timecode file.
+const HEADER: &str = "# timecode format v2";
+
+/// Writes the video frame timestamps in the timecode v2 format understood by mkvmerge and
+/// similar tools: a header line followed by one timestamp in milliseconds per frame.
+pub struct TimecodeWriter<W: Write> {
+    writer: W,
+
+    /// Duration of one timestamp unit, in seconds.
+    tick: f64,
+}
+
+impl TimecodeWriter<BufWriter<File>> {
+    /// Creates the timecode file, or returns `None` if the filename is empty.
+    pub fn create(filename: &str, tick: f64) -> Result<Option<Self>> {
+        if filename.is_empty() {
+            return Ok(None);
+        }
+
+        let file = File::create(filename).context("could not create the timecode file")?;
+        Ok(Some(Self::new(BufWriter::new(file), tick)?))
+    }
+}
+
+impl<W: Write> TimecodeWriter<W> {
+    pub fn new(mut writer: W, tick: f64) -> Result<Self> {
+        assert!(tick > 0f64);
+
+        writeln!(writer, "{}", HEADER).context("could not write to the timecode file")?;
+
+        Ok(Self { writer, tick })
+    }
+
+    /// Writes the timestamp of the next frame, in `tick` units.
+    pub fn write(&mut self, pts: i64) -> Result<()> {
+        writeln!(self.writer, "{:.6}", pts as f64 * self.tick * 1000f64)
+            .context("could not write to the timecode file")?;
+
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .context("could not write to the timecode file")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timecode_writer_test() {
+        let mut buf = Vec::new();
+
+        {
+            let mut writer = TimecodeWriter::new(&mut buf, 1f64 / 90000f64).unwrap();
+            writer.write(0).unwrap();
+            writer.write(1500).unwrap();
+            writer.write(3001).unwrap();
+            writer.finish().unwrap();
+        }
+
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "# timecode format v2\n0.000000\n16.666667\n33.344444\n");
+    }
+}
@@ -0,0 +1,481 @@
+use failure::{bail, ensure, format_err, Error};
+use std::f64::consts::PI;
+use std::result;
+
+type Result<T> = result::Result<T, Error>;
+
+/// Duration of the WSOLA frames, in seconds.
+const WSOLA_FRAME_DURATION: f64 = 0.04;
+
+/// How far the WSOLA frames can be moved to line up with the previous frame, in seconds.
+const WSOLA_TOLERANCE: f64 = 0.01;
+
+/// Speed of the game time relative to the output time, either constant or changing with the demo
+/// time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timescale {
+    /// Demo times and the scales at them, sorted by the time. The scale is interpolated linearly
+    /// between the keyframes and stays constant outside of them.
+    keyframes: Vec<(f64, f64)>,
+}
+
+/// What happens to the audio when the game time is scaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioStretchMode {
+    /// The audio is replaced with silence of the output duration.
+    Mute,
+
+    /// The audio is resampled, so slow motion lowers the pitch.
+    Pitch,
+
+    /// The audio is time-stretched keeping the pitch.
+    Preserve,
+}
+
+/// What happens to the audio in the timelapse mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelapseAudio {
+    /// The audio is replaced with silence of the output duration.
+    Silence,
+
+    /// The output has no audio.
+    Drop,
+}
+
+/// Stretches the captured audio to the output duration.
+pub struct AudioStretcher {
+    mode: AudioStretchMode,
+
+    /// Fractional output sample count carried over to the next chunk, for `Mute`.
+    remainder: f64,
+
+    /// Resampling position relative to the start of the next chunk, for `Pitch`. -1 is the last
+    /// sample of the previous chunk.
+    position: f64,
+
+    /// The last sample of the previous chunk, for `Pitch`.
+    last: (f32, f32),
+
+    /// Time-stretching state, for `Preserve`.
+    wsola: Wsola,
+}
+
+/// Waveform similarity overlap-add time stretching.
+struct Wsola {
+    /// Number of samples in a frame.
+    frame_length: usize,
+
+    /// Maximal frame offset when looking for the best match.
+    tolerance: usize,
+
+    /// The frame window.
+    window: Vec<f32>,
+
+    /// Input samples not used up yet.
+    input: Vec<(f32, f32)>,
+
+    /// Nominal start of the next frame in `input`.
+    position: f64,
+
+    /// Continuation of the last frame in `input`, which the next frame should look like.
+    natural: Option<usize>,
+
+    /// Second half of the last windowed frame, to be added to the next one.
+    tail: Vec<(f32, f32)>,
+}
+
+impl Timescale {
+    /// Creates a constant timescale.
+    #[inline]
+    pub fn constant(scale: f64) -> Self {
+        assert!(scale > 0f64);
+
+        Self { keyframes: vec![(0f64, scale)] }
+    }
+
+    /// Returns the scale at the given demo time.
+    pub fn scale_at(&self, time: f64) -> f64 {
+        let index = self.keyframes.iter().position(|&(t, _)| t > time);
+
+        match index {
+            Some(0) => self.keyframes[0].1,
+            None => self.keyframes.last().unwrap().1,
+            Some(i) => {
+                let (t0, s0) = self.keyframes[i - 1];
+                let (t1, s1) = self.keyframes[i];
+                s0 + (s1 - s0) * (time - t0) / (t1 - t0)
+            }
+        }
+    }
+}
+
+/// Parses the scale and the ramp strings into a `Timescale`, returning `None` if the time isn't
+/// scaled.
+///
+/// The ramp is a list of demo time and scale pairs.
+pub fn parse_timescale(scale: &str, ramp: &str) -> Result<Option<Timescale>> {
+    let scale = scale.trim()
+                     .parse::<f64>()
+                     .map_err(|_| format_err!("invalid scale: {}", scale))?;
+    ensure!(scale > 0f64, "the scale must be positive");
+
+    let values = ramp.split_whitespace()
+                     .map(|x| {
+                         x.parse::<f64>()
+                          .map_err(|_| format_err!("invalid ramp value: {}", x))
+                     })
+                     .collect::<Result<Vec<_>>>()?;
+
+    if values.is_empty() {
+        return Ok(if scale == 1f64 {
+                      None
+                  } else {
+                      Some(Timescale::constant(scale))
+                  });
+    }
+
+    ensure!(scale == 1f64, "the scale and the ramp cannot be used together");
+    ensure!(values.len() % 2 == 0,
+            "the ramp should consist of demo time and scale pairs");
+
+    let keyframes = values.chunks(2)
+                          .map(|pair| (pair[0], pair[1]))
+                          .collect::<Vec<_>>();
+
+    ensure!(keyframes.iter().all(|&(_, s)| s > 0f64),
+            "the ramp scales must be positive");
+    ensure!(keyframes.windows(2).all(|w| w[0].0 < w[1].0),
+            "the ramp times must be increasing");
+
+    Ok(Some(Timescale { keyframes }))
+}
+
+/// Parses the timelapse factor, returning `None` if the time isn't sped up.
+pub fn parse_timelapse(string: &str) -> Result<Option<f64>> {
+    let factor = string.trim()
+                       .parse::<f64>()
+                       .map_err(|_| format_err!("invalid factor: {}", string))?;
+    ensure!(factor >= 1f64, "the factor must be at least 1");
+
+    Ok(if factor == 1f64 { None } else { Some(factor) })
+}
+
+/// Parses the given string into a `TimelapseAudio`.
+pub fn parse_timelapse_audio(string: &str) -> Result<TimelapseAudio> {
+    match string.trim() {
+        "silence" => Ok(TimelapseAudio::Silence),
+        "drop" => Ok(TimelapseAudio::Drop),
+        _ => bail!("allowed values are silence and drop"),
+    }
+}
+
+/// Parses the given string into an `AudioStretchMode`.
+pub fn parse_audio_stretch_mode(string: &str) -> Result<AudioStretchMode> {
+    match string.trim() {
+        "mute" => Ok(AudioStretchMode::Mute),
+        "pitch" => Ok(AudioStretchMode::Pitch),
+        "preserve" => Ok(AudioStretchMode::Preserve),
+        _ => bail!("allowed values are mute, pitch and preserve"),
+    }
+}
+
+impl AudioStretcher {
+    pub fn new(mode: AudioStretchMode, rate: u32) -> Self {
+        Self { mode,
+               remainder: 0f64,
+               position: 0f64,
+               last: (0f32, 0f32),
+               wsola: Wsola::new(rate) }
+    }
+
+    /// Replaces the samples captured while the game time was scaled by `scale` with their
+    /// stretched version.
+    pub fn process(&mut self, samples: &mut Vec<(f32, f32)>, scale: f64) {
+        assert!(scale > 0f64);
+
+        match self.mode {
+            AudioStretchMode::Mute => {
+                let count = samples.len() as f64 / scale + self.remainder;
+                self.remainder = count.fract();
+
+                samples.clear();
+                samples.resize(count as usize, (0f32, 0f32));
+            }
+
+            AudioStretchMode::Pitch => {
+                let input = samples.split_off(0);
+                self.resample(&input, scale, samples);
+            }
+
+            AudioStretchMode::Preserve => {
+                let input = samples.split_off(0);
+                self.wsola.process(&input, scale, samples);
+            }
+        }
+    }
+
+    /// Resamples the input with linear interpolation, taking `scale` input samples per output
+    /// sample.
+    fn resample(&mut self, input: &[(f32, f32)], scale: f64, output: &mut Vec<(f32, f32)>) {
+        if input.is_empty() {
+            return;
+        }
+
+        let last = self.last;
+        let sample = |i: isize| {
+            if i < 0 {
+                last
+            } else {
+                input[i as usize]
+            }
+        };
+
+        while self.position < (input.len() - 1) as f64 {
+            let index = self.position.floor();
+            let t = (self.position - index) as f32;
+            let (a, b) = (sample(index as isize), sample(index as isize + 1));
+
+            output.push((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t));
+            self.position += scale;
+        }
+
+        self.position -= input.len() as f64;
+        self.last = *input.last().unwrap();
+    }
+}
+
+impl Wsola {
+    fn new(rate: u32) -> Self {
+        let frame_length = ((f64::from(rate) * WSOLA_FRAME_DURATION) as usize / 2 * 2).max(2);
+        let tolerance = (f64::from(rate) * WSOLA_TOLERANCE) as usize;
+
+        // The periodic Hann window adds up to 1 with 50% overlap.
+        let window = (0..frame_length).map(|i| {
+                                          let x = i as f64 / frame_length as f64;
+                                          (0.5 - 0.5 * (2f64 * PI * x).cos()) as f32
+                                      })
+                                      .collect();
+
+        Self { frame_length,
+               tolerance,
+               window,
+               input: Vec::new(),
+               // Start far enough in for the search to the left.
+               position: tolerance as f64,
+               natural: None,
+               tail: vec![(0f32, 0f32); frame_length / 2] }
+    }
+
+    fn process(&mut self, input: &[(f32, f32)], scale: f64, output: &mut Vec<(f32, f32)>) {
+        self.input.extend_from_slice(input);
+
+        let hop = self.frame_length / 2;
+
+        loop {
+            let nominal = self.position.round() as usize;
+
+            // Every candidate frame and the natural continuation need to be in the input.
+            let end = nominal + self.tolerance + self.frame_length;
+            let natural_end = self.natural.map_or(0, |n| n + self.frame_length);
+            if end.max(natural_end) > self.input.len() {
+                break;
+            }
+
+            let start = match self.natural {
+                Some(natural) => self.best_match(nominal, natural),
+                None => nominal,
+            };
+
+            // Overlap-add the first half and keep the second half for the next frame.
+            for i in 0..hop {
+                let (l, r) = self.input[start + i];
+                let w = self.window[i];
+                let (tail_l, tail_r) = self.tail[i];
+                output.push((tail_l + l * w, tail_r + r * w));
+
+                let (l, r) = self.input[start + hop + i];
+                let w = self.window[hop + i];
+                self.tail[i] = (l * w, r * w);
+            }
+
+            self.natural = Some(start + hop);
+            self.position += hop as f64 * scale;
+        }
+
+        // Drop the samples which won't be needed anymore.
+        let mut lowest = (self.position.round() as usize).saturating_sub(self.tolerance);
+        if let Some(natural) = self.natural {
+            lowest = lowest.min(natural);
+        }
+
+        self.input.drain(..lowest);
+        self.position -= lowest as f64;
+        self.natural = self.natural.map(|n| n - lowest);
+    }
+
+    /// Returns the frame start around `nominal` most similar to the frame at `natural`.
+    fn best_match(&self, nominal: usize, natural: usize) -> usize {
+        let mono = |(l, r): (f32, f32)| l + r;
+        let target = &self.input[natural..natural + self.frame_length];
+
+        let mut best = (nominal, std::f32::MIN);
+        for start in nominal - self.tolerance..=nominal + self.tolerance {
+            let candidate = &self.input[start..start + self.frame_length];
+
+            let (correlation, energy) =
+                candidate.iter()
+                         .zip(target)
+                         .fold((0f32, 0f32), |(correlation, energy), (&a, &b)| {
+                             (correlation + mono(a) * mono(b), energy + mono(a) * mono(a))
+                         });
+
+            // Normalize by the candidate energy so louder candidates don't win by default.
+            let similarity = correlation / (energy + 1e-9).sqrt();
+            if similarity > best.1 {
+                best = (start, similarity);
+            }
+        }
+
+        best.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(rate: u32, frequency: f64, count: usize) -> Vec<(f32, f32)> {
+        (0..count).map(|i| {
+                      let x = (2f64 * PI * frequency * i as f64 / f64::from(rate)).sin() as f32;
+                      (x, x)
+                  })
+                  .collect()
+    }
+
+    /// Returns the number of times the left channel goes from negative to non-negative.
+    fn rising_zero_crossings(samples: &[(f32, f32)]) -> usize {
+        samples.windows(2)
+               .filter(|w| w[0].0 < 0f32 && w[1].0 >= 0f32)
+               .count()
+    }
+
+    #[test]
+    fn parse_timescale_test() {
+        assert_eq!(parse_timescale("1", "").unwrap(), None);
+        assert_eq!(parse_timescale("0.25", "").unwrap(),
+                   Some(Timescale::constant(0.25)));
+        assert_eq!(parse_timescale("1", "10 1 12 0.5").unwrap(),
+                   Some(Timescale { keyframes: vec![(10f64, 1f64), (12f64, 0.5)] }));
+
+        assert!(parse_timescale("0", "").is_err());
+        assert!(parse_timescale("0.5", "10 1").is_err());
+        assert!(parse_timescale("1", "10 1 12").is_err());
+        assert!(parse_timescale("1", "10 1 5 0.5").is_err());
+        assert!(parse_timescale("1", "10 -1").is_err());
+    }
+
+    #[test]
+    fn parse_timelapse_test() {
+        assert_eq!(parse_timelapse("1").unwrap(), None);
+        assert_eq!(parse_timelapse(" 60 ").unwrap(), Some(60f64));
+        assert_eq!(parse_timelapse("2.5").unwrap(), Some(2.5));
+
+        assert!(parse_timelapse("0.5").is_err());
+        assert!(parse_timelapse("").is_err());
+        assert!(parse_timelapse("fast").is_err());
+    }
+
+    #[test]
+    fn scale_at_test() {
+        let timescale = parse_timescale("1", "10 1 12 0.5 20 0.5").unwrap().unwrap();
+
+        assert_eq!(timescale.scale_at(0f64), 1f64);
+        assert_eq!(timescale.scale_at(11f64), 0.75);
+        assert_eq!(timescale.scale_at(15f64), 0.5);
+        assert_eq!(timescale.scale_at(30f64), 0.5);
+    }
+
+    #[test]
+    fn mute_test() {
+        let mut stretcher = AudioStretcher::new(AudioStretchMode::Mute, 1000);
+
+        let mut samples = vec![(1f32, 1f32); 3];
+        stretcher.process(&mut samples, 0.4);
+        assert_eq!(samples, vec![(0f32, 0f32); 7]);
+
+        // The fractional half sample is carried over.
+        let mut samples = vec![(1f32, 1f32); 3];
+        stretcher.process(&mut samples, 0.4);
+        assert_eq!(samples.len(), 8);
+    }
+
+    #[test]
+    fn pitch_test() {
+        let mut stretcher = AudioStretcher::new(AudioStretchMode::Pitch, 1000);
+
+        let mut samples = vec![(0f32, 0f32), (1f32, 2f32)];
+        stretcher.process(&mut samples, 0.5);
+        assert_eq!(samples, vec![(0f32, 0f32), (0.5, 1f32)]);
+
+        // Interpolates across the chunk boundary.
+        let mut samples = vec![(2f32, 4f32)];
+        stretcher.process(&mut samples, 0.5);
+        assert_eq!(samples, vec![(1f32, 2f32), (1.5, 3f32)]);
+    }
+
+    #[test]
+    fn pitch_lowers_frequency_test() {
+        let rate = 48000;
+        let mut stretcher = AudioStretcher::new(AudioStretchMode::Pitch, rate);
+
+        let mut samples = sine(rate, 400f64, rate as usize);
+        stretcher.process(&mut samples, 0.5);
+
+        // Two seconds of 200 Hz, minus the last interpolated interval waiting for the next chunk.
+        assert!((samples.len() as isize - 2 * rate as isize).abs() <= 2);
+        assert!((rising_zero_crossings(&samples) as isize - 400).abs() <= 1);
+    }
+
+    #[test]
+    fn preserve_test() {
+        let rate = 48000;
+        let mut stretcher = AudioStretcher::new(AudioStretchMode::Preserve, rate);
+
+        // Feed in chunks of one video frame.
+        let input = sine(rate, 400f64, rate as usize);
+        let mut output = Vec::new();
+        for chunk in input.chunks(800) {
+            let mut samples = chunk.to_vec();
+            stretcher.process(&mut samples, 0.25);
+            output.extend(samples);
+        }
+
+        // Four seconds minus the frames still waiting for input, still at 400 Hz.
+        let wsola = &stretcher.wsola;
+        let latency = (wsola.frame_length * 3 / 2 + 2 * wsola.tolerance) * 4;
+        assert!(output.len() <= 4 * rate as usize);
+        assert!(output.len() + latency >= 4 * rate as usize);
+
+        let steady = &output[rate as usize / 2..rate as usize / 2 + rate as usize];
+        assert!((rising_zero_crossings(steady) as isize - 400).abs() <= 4);
+    }
+
+    #[test]
+    fn preserve_identity_test() {
+        let rate = 1000;
+        let mut stretcher = AudioStretcher::new(AudioStretchMode::Preserve, rate);
+
+        let input = sine(rate, 30f64, 1000);
+        let mut output = input.clone();
+        stretcher.process(&mut output, 1f64);
+
+        // With a scale of 1 the frames line up exactly, giving back the input shifted by the
+        // initial position after the first half frame fading in.
+        let hop = stretcher.wsola.frame_length / 2;
+        let delay = stretcher.wsola.tolerance;
+        assert!(output.len() > hop);
+        for (a, b) in output[hop..].iter().zip(&input[hop + delay..]) {
+            assert!((a.0 - b.0).abs() < 1e-5);
+        }
+    }
+}
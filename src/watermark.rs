@@ -0,0 +1,333 @@
+use failure::{bail, ensure, format_err, Error, ResultExt};
+use ffmpeg::frame::Video as VideoFrame;
+use ffmpeg::software::scaling;
+use ffmpeg::{format, media, Packet};
+use lazy_static::lazy_static;
+use std::result;
+use std::sync::{Arc, Mutex};
+
+use crate::utils::format_error;
+
+type Result<T> = result::Result<T, Error>;
+
+lazy_static! {
+    static ref SETTINGS: Mutex<Option<WatermarkSettings>> = Mutex::new(None);
+}
+
+/// Watermark settings set with `cap_watermark`.
+#[derive(Debug, Clone, PartialEq)]
+struct WatermarkSettings {
+    /// Path to the image.
+    filename: String,
+
+    /// Position of the top left corner of the image on the output frame, in pixels. Negative
+    /// values are counted from the right and the bottom edges of the frame to the right and the
+    /// bottom edges of the image.
+    position: (i32, i32),
+
+    /// Opacity from 0 to 1.
+    opacity: f32,
+
+    /// Image scale factor.
+    scale: f32,
+}
+
+/// A watermark image ready for blending.
+pub struct Watermark {
+    /// RGBA pixels, top to bottom, with the opacity applied to the alpha channel.
+    data: Vec<u8>,
+
+    width: u32,
+    height: u32,
+
+    /// Position on the output frame, see `WatermarkSettings::position`.
+    position: (i32, i32),
+}
+
+impl Watermark {
+    /// Returns the RGBA pixels.
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    #[inline]
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Returns the position of the top left corner of the watermark on a frame of the given size.
+    pub fn origin(&self, (width, height): (u32, u32)) -> (i32, i32) {
+        let (x, y) = self.position;
+
+        let x = if x >= 0 {
+            x
+        } else {
+            width as i32 + x + 1 - self.width as i32
+        };
+        let y = if y >= 0 {
+            y
+        } else {
+            height as i32 + y + 1 - self.height as i32
+        };
+
+        (x, y)
+    }
+
+    /// Blends the watermark onto the frame.
+    ///
+    /// `output_size` is the size of the final video. If it differs from the frame size, the
+    /// watermark is scaled so that it ends up at the right place after the frame is scaled.
+    ///
+    /// Returns `false` if the frame pixel format is not supported.
+    pub fn draw(&self, frame: &mut VideoFrame, output_size: (u32, u32)) -> bool {
+        let bytes_per_pixel = match frame.format() {
+            format::Pixel::RGB24 => 3,
+            format::Pixel::RGBA => 4,
+            _ => return false,
+        };
+
+        let (frame_width, frame_height) = (frame.width(), frame.height());
+        let scale_x = f64::from(output_size.0) / f64::from(frame_width);
+        let scale_y = f64::from(output_size.1) / f64::from(frame_height);
+        let (origin_x, origin_y) = self.origin(output_size);
+
+        // The watermark rectangle in the frame coordinates.
+        let left = (f64::from(origin_x) / scale_x).floor().max(0f64) as u32;
+        let top = (f64::from(origin_y) / scale_y).floor().max(0f64) as u32;
+        let right = ((f64::from(origin_x) + f64::from(self.width)) / scale_x).ceil();
+        let right = right.max(0f64).min(f64::from(frame_width)) as u32;
+        let bottom = ((f64::from(origin_y) + f64::from(self.height)) / scale_y).ceil();
+        let bottom = bottom.max(0f64).min(f64::from(frame_height)) as u32;
+
+        let stride = frame.stride(0);
+        let data = frame.data_mut(0);
+
+        for y in top..bottom {
+            // Nearest-neighbor sampling of the watermark.
+            let wy = ((f64::from(y) + 0.5) * scale_y).floor() as i64 - i64::from(origin_y);
+            if wy < 0 || wy >= i64::from(self.height) {
+                continue;
+            }
+
+            for x in left..right {
+                let wx = ((f64::from(x) + 0.5) * scale_x).floor() as i64 - i64::from(origin_x);
+                if wx < 0 || wx >= i64::from(self.width) {
+                    continue;
+                }
+
+                let src = ((wy as u32 * self.width + wx as u32) * 4) as usize;
+                let dst = y as usize * stride + x as usize * bytes_per_pixel;
+                let alpha = u32::from(self.data[src + 3]);
+
+                for i in 0..3 {
+                    data[dst + i] = ((u32::from(data[dst + i]) * (255 - alpha)
+                                      + u32::from(self.data[src + i]) * alpha
+                                      + 127)
+                                     / 255) as u8;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Loads the watermark image set with `cap_watermark`.
+///
+/// Returns `None` if the watermark is disabled.
+pub fn load() -> Result<Option<Arc<Watermark>>> {
+    let settings = match *SETTINGS.lock().unwrap() {
+        Some(ref settings) => settings.clone(),
+        None => return Ok(None),
+    };
+
+    let image = decode_image(&settings.filename).with_context(|_| {
+                                                     format!("could not load the watermark \
+                                                              image {}",
+                                                             settings.filename)
+                                                 })?;
+
+    let width = ((image.width() as f32 * settings.scale).round() as u32).max(1);
+    let height = ((image.height() as f32 * settings.scale).round() as u32).max(1);
+
+    let mut context = scaling::Context::get(image.format(),
+                                            image.width(),
+                                            image.height(),
+                                            format::Pixel::RGBA,
+                                            width,
+                                            height,
+                                            scaling::flag::BICUBIC)
+        .context("could not initialize the watermark scaling context")?;
+
+    let mut rgba = VideoFrame::new(format::Pixel::RGBA, width, height);
+    context.run(&image, &mut rgba)
+           .context("could not convert the watermark image")?;
+
+    let stride = rgba.stride(0);
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height as usize {
+        data.extend_from_slice(&rgba.data(0)[y * stride..y * stride + width as usize * 4]);
+    }
+
+    for pixel in data.chunks_mut(4) {
+        pixel[3] = (f32::from(pixel[3]) * settings.opacity).round() as u8;
+    }
+
+    Ok(Some(Arc::new(Watermark { data,
+                                 width,
+                                 height,
+                                 position: settings.position })))
+}
+
+/// Decodes the first frame of the image file.
+fn decode_image(filename: &str) -> Result<VideoFrame> {
+    let mut input = format::input(&filename).context("could not open the file")?;
+
+    let stream_index = input.streams()
+                            .best(media::Type::Video)
+                            .ok_or_else(|| format_err!("the file does not contain an image"))?
+                            .index();
+
+    let mut decoder = input.stream(stream_index)
+                           .unwrap()
+                           .codec()
+                           .decoder()
+                           .video()
+                           .context("could not open the image decoder")?;
+
+    let mut frame = VideoFrame::empty();
+
+    for (stream, packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+
+        if decoder.decode(&packet, &mut frame)
+                  .context("could not decode the image")?
+        {
+            return Ok(frame);
+        }
+    }
+
+    // Some decoders need to be flushed to output the frame.
+    if decoder.decode(&Packet::empty(), &mut frame)
+              .context("could not decode the image")?
+    {
+        return Ok(frame);
+    }
+
+    bail!("the file does not contain an image");
+}
+
+/// Parses the `cap_watermark` arguments following the file name.
+fn parse_settings(filename: String, args: &[String]) -> Result<WatermarkSettings> {
+    ensure!(args.is_empty() || args.len() == 2 || args.len() == 3 || args.len() == 4,
+            "expected <png> [x y [opacity [scale]]]");
+
+    let mut settings = WatermarkSettings { filename,
+                                           position: (-16, -16),
+                                           opacity: 1f32,
+                                           scale: 1f32 };
+
+    if args.len() >= 2 {
+        settings.position = (args[0].parse().context("invalid x")?,
+                             args[1].parse().context("invalid y")?);
+    }
+
+    if let Some(opacity) = args.get(2) {
+        settings.opacity = opacity.parse().context("invalid opacity")?;
+        ensure!(settings.opacity >= 0f32 && settings.opacity <= 1f32,
+                "the opacity must be between 0 and 1");
+    }
+
+    if let Some(scale) = args.get(3) {
+        settings.scale = scale.parse().context("invalid scale")?;
+        ensure!(settings.scale > 0f32, "the scale must be positive");
+    }
+
+    Ok(settings)
+}
+
+command!(cap_watermark, |engine| {
+    let args = engine.args().skip(1).collect::<Vec<_>>();
+
+    if args.is_empty() {
+        let mut buf = String::new();
+
+        match *SETTINGS.lock().unwrap() {
+            Some(ref s) => buf.push_str(&format!("Current watermark: {} at {} {}, opacity {}, \
+                                                  scale {}.\n",
+                                                 s.filename,
+                                                 s.position.0,
+                                                 s.position.1,
+                                                 s.opacity,
+                                                 s.scale)),
+            None => buf.push_str("The watermark is disabled.\n"),
+        }
+
+        buf.push_str("\nUsage:\n");
+        buf.push_str("    cap_watermark <png> [x y [opacity [scale]]]\n");
+        buf.push_str("     - Blend the image onto the video. Negative x and y are counted from \
+                      the right and the bottom edges. The default is -16 -16 1 1.\n");
+        buf.push_str("    cap_watermark \"\"\n");
+        buf.push_str("     - Disable the watermark.\n");
+
+        engine.con_print(&buf);
+        return;
+    }
+
+    if args[0].is_empty() {
+        *SETTINGS.lock().unwrap() = None;
+        return;
+    }
+
+    match parse_settings(args[0].clone(), &args[1..]) {
+        Ok(settings) => *SETTINGS.lock().unwrap() = Some(settings),
+        Err(e) => engine.con_print(&format_error(&e.context("invalid cap_watermark arguments")
+                                                   .into())),
+    }
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_settings_test() {
+        assert_eq!(parse_settings("logo.png".to_string(), &[]).unwrap(),
+                   WatermarkSettings { filename: "logo.png".to_string(),
+                                       position: (-16, -16),
+                                       opacity: 1f32,
+                                       scale: 1f32 });
+        assert_eq!(parse_settings("logo.png".to_string(),
+                                  &args(&["10", "-20", "0.5", "2"])).unwrap(),
+                   WatermarkSettings { filename: "logo.png".to_string(),
+                                       position: (10, -20),
+                                       opacity: 0.5,
+                                       scale: 2f32 });
+    }
+
+    #[test]
+    fn parse_settings_invalid_test() {
+        assert!(parse_settings("logo.png".to_string(), &args(&["10"])).is_err());
+        assert!(parse_settings("logo.png".to_string(), &args(&["a", "0"])).is_err());
+        assert!(parse_settings("logo.png".to_string(), &args(&["0", "0", "1.5"])).is_err());
+        assert!(parse_settings("logo.png".to_string(), &args(&["0", "0", "1", "0"])).is_err());
+        assert!(parse_settings("logo.png".to_string(), &args(&["0", "0", "1", "1", "1"])).is_err());
+    }
+
+    #[test]
+    fn origin_test() {
+        let watermark = Watermark { data: Vec::new(),
+                                    width: 100,
+                                    height: 50,
+                                    position: (16, -16) };
+
+        assert_eq!(watermark.origin((1920, 1080)), (16, 1080 - 16 + 1 - 50));
+    }
+}